@@ -0,0 +1,537 @@
+use crate::utils::mask_low_quality;
+use crate::InputOptions;
+use anyhow::{Context, Result};
+use bio::alphabets::dna::revcomp;
+use noodles_bam as bam;
+use noodles_cram as cram;
+use noodles_fasta as fasta;
+use noodles_sam as sam;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+
+/// The read is aligned to the reverse strand.
+const FLAG_REVERSE: u16 = 0x10;
+/// The alignment is secondary.
+const FLAG_SECONDARY: u16 = 0x100;
+/// The alignment is supplementary.
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// A record of a SAM, BAM or CRAM file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AlignedRead {
+    name: String,
+    flag: u16,
+    /// The reference the read is aligned to, if it is.
+    reference: Option<String>,
+    /// The start of the alignment (0-based).
+    start: usize,
+    /// The number of reference bases the alignment covers.
+    reference_length: usize,
+    seq: Vec<u8>,
+    /// Phred qualities (without an offset), if there are any.
+    qual: Option<Vec<u8>>,
+}
+
+impl AlignedRead {
+    /// Should this record be used, given the options?
+    fn is_wanted(&self, options: &InputOptions) -> bool {
+        if self.flag & FLAG_SECONDARY != 0
+            || (self.flag & FLAG_SUPPLEMENTARY != 0 && !options.supplementary)
+            || self.seq.is_empty()
+        {
+            return false;
+        }
+        if options.regions.is_empty() {
+            return true;
+        }
+        match &self.reference {
+            Some(reference) => options.regions.iter().any(|e| {
+                e.overlaps(
+                    reference,
+                    self.start,
+                    self.start + self.reference_length.max(1),
+                )
+            }),
+            None => false,
+        }
+    }
+
+    /// The read as it was sequenced (undoing the reverse complement
+    /// of reads aligned to the reverse strand), with low quality
+    /// bases masked, as a FASTA record.
+    fn to_fasta(&self, min_quality: Option<u8>) -> Vec<u8> {
+        let mut seq = self.seq.clone();
+        let mut qual = self.qual.clone();
+        if self.flag & FLAG_REVERSE != 0 {
+            seq = revcomp(&seq);
+            if let Some(q) = qual.as_mut() {
+                q.reverse();
+            }
+        }
+        if let (Some(min_quality), Some(qual)) = (min_quality, qual) {
+            // mask_low_quality expects Sanger FASTQ encoding
+            let qual: Vec<u8> = qual.iter().map(|e| e.saturating_add(33)).collect();
+            mask_low_quality(&mut seq, &qual, min_quality);
+        }
+
+        let mut record = Vec::with_capacity(self.name.len() + seq.len() + 3);
+        record.push(b'>');
+        record.extend_from_slice(self.name.as_bytes());
+        record.push(b'\n');
+        record.extend_from_slice(&seq);
+        record.push(b'\n');
+        record
+    }
+}
+
+/// Reads the records of a CRAM file, a container at a time.
+struct CramReader<R: Read> {
+    reader: cram::io::Reader<R>,
+    header: sam::Header,
+    /// The reference sequences the reads were compressed against.
+    repository: fasta::Repository,
+    container: cram::io::reader::Container,
+    /// The reads of the current container which are still to be used.
+    reads: std::vec::IntoIter<AlignedRead>,
+}
+
+impl<R: Read> CramReader<R> {
+    /// Read the header of a CRAM file.
+    fn new(reader: R, repository: fasta::Repository) -> Result<Self> {
+        let mut reader = cram::io::Reader::new(reader);
+        let header = reader
+            .read_header()
+            .context("Could not read the header of the CRAM file.")?;
+        Ok(Self {
+            reader,
+            header,
+            repository,
+            container: Default::default(),
+            reads: Vec::new().into_iter(),
+        })
+    }
+
+    fn next_read(&mut self) -> Result<Option<AlignedRead>> {
+        loop {
+            if let Some(read) = self.reads.next() {
+                return Ok(Some(read));
+            }
+            if self.reader.read_container(&mut self.container)? == 0 {
+                return Ok(None);
+            }
+            self.reads = self.decode_container()?.into_iter();
+        }
+    }
+
+    /// Decode the reads of the current container.
+    fn decode_container(&self) -> io::Result<Vec<AlignedRead>> {
+        let compression_header = self.container.compression_header()?;
+        let mut reads = Vec::new();
+        for slice in self.container.slices() {
+            let slice = slice?;
+            let (core_data, external_data) = slice.decode_blocks()?;
+            let records = slice.records(
+                self.repository.clone(),
+                &self.header,
+                &compression_header,
+                &core_data,
+                &external_data,
+            )?;
+            for record in records {
+                let record =
+                    sam::alignment::RecordBuf::try_from_alignment_record(&self.header, &record)?;
+                reads.push(aligned_read(&self.header, &record));
+            }
+        }
+        Ok(reads)
+    }
+}
+
+/// A record of a SAM, BAM or CRAM file, as decoded by noodles.
+fn aligned_read(header: &sam::Header, record: &sam::alignment::RecordBuf) -> AlignedRead {
+    let qual = record.quality_scores().as_ref();
+    AlignedRead {
+        name: record
+            .name()
+            .map_or_else(|| "*".to_string(), |e| e.to_string()),
+        flag: record.flags().bits(),
+        reference: record
+            .reference_sequence_id()
+            .and_then(|e| header.reference_sequences().get_index(e))
+            .map(|(name, _)| name.to_string()),
+        start: record.alignment_start().map_or(0, |e| usize::from(e) - 1),
+        reference_length: record.alignment_span().unwrap_or_default(),
+        seq: record.sequence().as_ref().to_ascii_uppercase(),
+        // qualities are all 0xff when missing
+        qual: (!qual.is_empty() && qual[0] != 0xff).then(|| qual.to_vec()),
+    }
+}
+
+/// The reference sequences to decode a CRAM file against, which gives
+/// an error for a sequence it doesn't have, rather than nothing (which
+/// would panic while decoding).
+struct ReferenceSequences(Box<dyn fasta::repository::Adapter>);
+
+impl fasta::repository::Adapter for ReferenceSequences {
+    fn get(&mut self, name: &[u8]) -> Option<io::Result<fasta::Record>> {
+        Some(self.0.get(name).unwrap_or_else(|| {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Reference sequence {} was not found.",
+                    String::from_utf8_lossy(name)
+                ),
+            ))
+        }))
+    }
+}
+
+/// The reference sequences of a FASTA, read through its `.fai` index if
+/// it has one, and otherwise all read into memory.
+fn reference_repository(reference: Option<&Path>) -> Result<fasta::Repository> {
+    let Some(reference) = reference else {
+        let sequences: Vec<fasta::Record> = Vec::new();
+        return Ok(fasta::Repository::new(ReferenceSequences(Box::new(
+            sequences,
+        ))));
+    };
+    let mut index = reference.as_os_str().to_owned();
+    index.push(".fai");
+    if Path::new(&index).exists() {
+        let reader = fasta::io::indexed_reader::Builder::default()
+            .build_from_path(reference)
+            .with_context(|| format!("Could not open the reference {}", reference.display()))?;
+        return Ok(fasta::Repository::new(ReferenceSequences(Box::new(
+            fasta::repository::adapters::IndexedReader::new(reader),
+        ))));
+    }
+
+    eprintln!(
+        "[-]\t\tReference {} has no .fai index, so it is read into memory.",
+        reference.display()
+    );
+    let sequences = fasta::io::reader::Builder
+        .build_from_path(reference)
+        .and_then(|mut reader| reader.records().collect::<io::Result<Vec<_>>>())
+        .with_context(|| format!("Could not read the reference {}", reference.display()))?;
+    Ok(fasta::Repository::new(ReferenceSequences(Box::new(
+        sequences,
+    ))))
+}
+
+enum Records<R: BufRead> {
+    Sam(sam::io::Reader<R>, sam::Header),
+    Bam(bam::io::Reader<R>, sam::Header),
+    Cram(Box<CramReader<R>>),
+}
+
+/// Presents the reads of a SAM, BAM or CRAM file as a FASTA file, so
+/// they can be read like any other sequences. Only primary records (and,
+/// optionally, supplementary records) are used.
+pub struct AlignmentFasta<R: BufRead> {
+    records: Records<R>,
+    options: InputOptions,
+    /// The current SAM or BAM record.
+    record: sam::alignment::RecordBuf,
+    /// The current read, as a FASTA record.
+    buffer: Vec<u8>,
+    /// How much of the buffer has been read.
+    position: usize,
+}
+
+impl<R: BufRead> AlignmentFasta<R> {
+    /// Read a SAM file.
+    pub fn sam(reader: R, options: InputOptions) -> Result<Self> {
+        let mut reader = sam::io::Reader::new(reader);
+        let header = reader
+            .read_header()
+            .context("Could not read the header of the SAM file.")?;
+        Ok(Self::new(Records::Sam(reader, header), options))
+    }
+
+    /// Read a (decompressed) BAM file.
+    pub fn bam(reader: R, options: InputOptions) -> Result<Self> {
+        let mut reader = bam::io::Reader::from(reader);
+        let header = reader
+            .read_header()
+            .context("Could not read the header of the BAM file.")?;
+        Ok(Self::new(Records::Bam(reader, header), options))
+    }
+
+    /// Read a CRAM file, decoding it against the reference sequences
+    /// in `options.reference`.
+    pub fn cram(reader: R, options: InputOptions) -> Result<Self> {
+        let repository = reference_repository(options.reference.as_deref())?;
+        Ok(Self::new(
+            Records::Cram(Box::new(CramReader::new(reader, repository)?)),
+            options,
+        ))
+    }
+
+    fn new(records: Records<R>, options: InputOptions) -> Self {
+        Self {
+            records,
+            options,
+            record: Default::default(),
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// The next read to use, as a FASTA record.
+    fn next_record(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let read = match &mut self.records {
+                Records::Sam(reader, header) => {
+                    match reader
+                        .read_record_buf(header, &mut self.record)
+                        .context("Could not read a SAM record.")?
+                    {
+                        0 => None,
+                        _ => Some(aligned_read(header, &self.record)),
+                    }
+                }
+                Records::Bam(reader, header) => {
+                    match reader
+                        .read_record_buf(header, &mut self.record)
+                        .context("Could not read a BAM record.")?
+                    {
+                        0 => None,
+                        _ => Some(aligned_read(header, &self.record)),
+                    }
+                }
+                Records::Cram(reader) => reader.next_read().with_context(|| {
+                    match &self.options.reference {
+                        Some(reference) => format!(
+                            "Could not decode the CRAM records (were the reads aligned to {}?)",
+                            reference.display()
+                        ),
+                        None => "Could not decode the CRAM records (give the FASTA the reads were aligned to with --reference)".to_string(),
+                    }
+                })?,
+            };
+            match read {
+                Some(read) if read.is_wanted(&self.options) => {
+                    return Ok(Some(read.to_fasta(self.options.min_quality)))
+                }
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for AlignmentFasta<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for AlignmentFasta<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.buffer.len() {
+            if let Some(record) = self
+                .next_record()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{e:#}")))?
+            {
+                self.buffer = record;
+                self.position = 0;
+            }
+        }
+        Ok(&self.buffer[self.position..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position = (self.position + amt).min(self.buffer.len());
+    }
+}
+
+/// Does this look like the start of a SAM file? Either a header line
+/// (e.g. `@HD`, followed by a tab), or an alignment line of at least
+/// 11 tab separated fields.
+pub fn is_sam(start: &[u8]) -> bool {
+    let line = start.split(|e| *e == b'\n').next().unwrap_or_default();
+    match line {
+        [b'@', a, b, b'\t', ..] => a.is_ascii_alphabetic() && b.is_ascii_alphabetic(),
+        [b'>' | b'@', ..] => false,
+        _ => line.iter().filter(|e| **e == b'\t').count() >= 10,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAM: &str = "@HD\tVN:1.6\n\
+@SQ\tSN:chr1\tLN:1000\n\
+read1\t0\tchr1\t100\t60\t5M\t*\t0\t0\tACGTT\tIIII!\n\
+read2\t16\tchr1\t500\t60\t2M1D3M\t*\t0\t0\tAACCC\t*\n\
+read3\t256\tchr1\t100\t60\t5M\t*\t0\t0\tACGTT\tIIIII\n\
+read4\t2048\tchr1\t900\t60\t5M\t*\t0\t0\tGGGGG\tIIIII\n\
+read5\t4\t*\t0\t0\t*\t*\t0\t0\tTTTTT\tIIIII\n";
+
+    fn read_all<R: BufRead>(mut reader: R) -> String {
+        let mut fasta = String::new();
+        reader.read_to_string(&mut fasta).unwrap();
+        fasta
+    }
+
+    #[test]
+    fn test_sam() {
+        assert!(is_sam(SAM.as_bytes()));
+        assert!(!is_sam(b"@read1\nACGT\n+\nIIII\n"));
+        assert!(!is_sam(b">read1\nACGT\n"));
+
+        // primary records only, in their sequenced orientation
+        let fasta = read_all(AlignmentFasta::sam(SAM.as_bytes(), InputOptions::default()).unwrap());
+        assert_eq!(fasta, ">read1\nACGTT\n>read2\nGGGTT\n>read5\nTTTTT\n");
+
+        let options = InputOptions {
+            supplementary: true,
+            regions: vec!["chr1:500-900".parse().unwrap()],
+            min_quality: Some(10),
+            ends: None,
+            reference: None,
+        };
+        let fasta = read_all(AlignmentFasta::sam(SAM.as_bytes(), options).unwrap());
+        assert_eq!(fasta, ">read2\nGGGTT\n>read4\nGGGGG\n");
+
+        let options = InputOptions {
+            min_quality: Some(10),
+            ..Default::default()
+        };
+        let fasta = read_all(AlignmentFasta::sam(SAM.as_bytes(), options).unwrap());
+        assert!(fasta.starts_with(">read1\nACGTN\n"));
+    }
+
+    #[test]
+    fn test_bam() {
+        // a header with one reference, and one record
+        let mut bam = b"BAM\x01".to_vec();
+        bam.extend(0u32.to_le_bytes());
+        bam.extend(1u32.to_le_bytes());
+        bam.extend(5u32.to_le_bytes());
+        bam.extend(b"chr1\0");
+        bam.extend(1000u32.to_le_bytes());
+
+        let mut record = Vec::new();
+        record.extend(0i32.to_le_bytes());
+        record.extend(99i32.to_le_bytes());
+        // l_read_name, mapq, bin
+        record.extend([6, 60, 0, 0]);
+        // n_cigar_op, flag
+        record.extend(1u16.to_le_bytes());
+        record.extend(16u16.to_le_bytes());
+        record.extend(5i32.to_le_bytes());
+        record.extend((-1i32).to_le_bytes());
+        record.extend((-1i32).to_le_bytes());
+        record.extend(0i32.to_le_bytes());
+        record.extend(b"read1\0");
+        // 5M
+        record.extend((5u32 << 4).to_le_bytes());
+        // AACCC
+        record.extend([0x11, 0x22, 0x20]);
+        record.extend([40, 40, 40, 0, 40]);
+        bam.extend((record.len() as u32).to_le_bytes());
+        bam.extend(record);
+
+        let options = InputOptions {
+            regions: vec!["chr1:101-101".parse().unwrap()],
+            min_quality: Some(10),
+            ..Default::default()
+        };
+        let fasta = read_all(AlignmentFasta::bam(bam.as_slice(), options).unwrap());
+        assert_eq!(fasta, ">read1\nGNGTT\n");
+
+        let options = InputOptions {
+            regions: vec!["chr1:105-200".parse().unwrap()],
+            ..Default::default()
+        };
+        let fasta = read_all(AlignmentFasta::bam(bam.as_slice(), options).unwrap());
+        assert_eq!(fasta, "");
+
+        // a record cut short is an error, not a panic
+        let mut reads =
+            AlignmentFasta::bam(&bam[..bam.len() - 3], InputOptions::default()).unwrap();
+        assert!(reads.read_to_string(&mut String::new()).is_err());
+    }
+
+    #[test]
+    fn test_cram() {
+        use sam::alignment::io::Write;
+        use sam::alignment::record::cigar::{op::Kind, Op};
+        use sam::alignment::record::Flags;
+        use sam::alignment::RecordBuf;
+        use sam::header::record::value::{map::ReferenceSequence, Map};
+
+        let header = sam::Header::builder()
+            .add_reference_sequence(
+                "chr1",
+                Map::<ReferenceSequence>::new(std::num::NonZero::new(20).unwrap()),
+            )
+            .build();
+        let repository = fasta::Repository::new(vec![fasta::Record::new(
+            fasta::record::Definition::new("chr1", None),
+            fasta::record::Sequence::from(b"TTAGGGTTAGGGACGTACGT".to_vec()),
+        )]);
+
+        // a read aligned to the reverse strand (with one mismatch), and
+        // an unmapped read
+        let mut writer = cram::io::writer::Builder::default()
+            .set_reference_sequence_repository(repository)
+            .build_from_writer(Vec::new());
+        writer.write_header(&header).unwrap();
+        let record = RecordBuf::builder()
+            .set_name("read1")
+            .set_flags(Flags::REVERSE_COMPLEMENTED)
+            .set_reference_sequence_id(0)
+            .set_alignment_start(13.try_into().unwrap())
+            .set_cigar([Op::new(Kind::Match, 5)].into_iter().collect())
+            .set_sequence(b"ACGTT".to_vec().into())
+            .set_quality_scores([40, 40, 40, 0, 40].into_iter().collect())
+            .build();
+        writer.write_alignment_record(&header, &record).unwrap();
+        let record = RecordBuf::builder()
+            .set_name("read2")
+            .set_flags(Flags::UNMAPPED)
+            .set_sequence(b"TTAGG".to_vec().into())
+            .set_quality_scores([40; 5].into_iter().collect())
+            .build();
+        writer.write_alignment_record(&header, &record).unwrap();
+        writer.try_finish(&header).unwrap();
+        let cram = writer.into_inner();
+
+        // the reference, first without and then with an index
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let reference = dir.join("reference.fa");
+        std::fs::write(&reference, ">chr1\nTTAGGGTTAGGGACGTACGT\n").unwrap();
+        let index = dir.join("reference.fa.fai");
+
+        let options = InputOptions {
+            reference: Some(reference.clone()),
+            ..Default::default()
+        };
+        let fasta = read_all(AlignmentFasta::cram(cram.as_slice(), options).unwrap());
+        assert_eq!(fasta, ">read1\nAACGT\n>read2\nTTAGG\n");
+
+        std::fs::write(&index, "chr1\t20\t6\t20\t21\n").unwrap();
+        let options = InputOptions {
+            reference: Some(reference),
+            regions: vec!["chr1:13-13".parse().unwrap()],
+            min_quality: Some(10),
+            ..Default::default()
+        };
+        let fasta = read_all(AlignmentFasta::cram(cram.as_slice(), options).unwrap());
+        assert_eq!(fasta, ">read1\nANCGT\n");
+
+        // the mapped read can't be decoded without the reference
+        let mut reads = AlignmentFasta::cram(cram.as_slice(), InputOptions::default()).unwrap();
+        let error = reads.read_to_string(&mut String::new()).unwrap_err();
+        assert!(error.to_string().contains("--reference"));
+    }
+}
@@ -0,0 +1,372 @@
+use crate::provenance::Provenance;
+use crate::{open_sequence_reader, search, utils, InputOptions, SubCommand};
+use anyhow::Result;
+use serde_json::json;
+use std::fmt::{self, Display};
+use std::fs::{create_dir_all, File};
+use std::io::LineWriter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str;
+
+/// The strand a telomeric array is on, relative to the
+/// telomeric repeat that was queried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strand {
+    /// The telomeric repeat itself.
+    Forward,
+    /// The reverse complement of the telomeric repeat.
+    Reverse,
+}
+
+impl Display for Strand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strand::Forward => write!(f, "+"),
+            Strand::Reverse => write!(f, "-"),
+        }
+    }
+}
+
+/// A contiguous run of telomeric repeat units on one strand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelomericArray {
+    /// The sequence the array is on.
+    pub id: String,
+    /// Start of the array (0-based).
+    pub start: usize,
+    /// End of the array (exclusive).
+    pub end: usize,
+    /// Which strand the repeat was found on.
+    pub strand: Strand,
+    /// The telomeric repeat making up the array.
+    pub telomeric_repeat: String,
+    /// The number of repeat units in the array.
+    pub units: usize,
+    /// The proportion of the array covered by repeat units.
+    pub purity: f64,
+}
+
+impl TelomericArray {
+    /// The length of the array in bp.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Whether the array has no length.
+    pub fn is_empty(&self) -> bool {
+        self.end == self.start
+    }
+
+    /// Whether the array is made of the G-rich strand of the
+    /// telomeric repeat, which is expected at the 3' end of a
+    /// correctly oriented sequence. The C-rich strand is expected
+    /// at the 5' end.
+    pub fn is_g_rich(&self) -> bool {
+        utils::is_g_rich(&self.telomeric_repeat) == (self.strand == Strand::Forward)
+    }
+
+    /// The number of bases of the array, scaled by its purity, which
+    /// fall within `start..end`.
+    pub fn repeat_bases_within(&self, start: usize, end: usize) -> f64 {
+        let overlap = self.end.min(end).saturating_sub(self.start.max(start));
+        overlap as f64 * self.purity
+    }
+}
+
+/// Options for calling telomeric arrays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArrayOptions {
+    /// The largest gap (bp) between two repeat units of the same array.
+    pub max_gap: usize,
+    /// The shortest array (bp) to report.
+    pub min_length: usize,
+    /// Use approximate occurrences, up to this distance.
+    pub distance: Option<utils::Distance>,
+}
+
+impl ArrayOptions {
+    /// Arrays of exact repeat units, joined across gaps of up to
+    /// `max_gap`, and at least `min_length` long.
+    pub fn new(max_gap: usize, min_length: usize) -> Self {
+        Self {
+            max_gap,
+            min_length,
+            distance: None,
+        }
+    }
+
+    /// Parse the array options from the command line.
+    pub fn from_matches(matches: &clap::ArgMatches) -> Self {
+        Self {
+            max_gap: *matches.get_one::<usize>("gap").expect("defaulted by clap"),
+            min_length: *matches
+                .get_one::<usize>("min_length")
+                .expect("defaulted by clap"),
+            distance: utils::Distance::from_matches(matches),
+        }
+    }
+}
+
+/// The entry point for `tidk arrays`.
+pub fn arrays(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
+    let mut provenance = Provenance::new("arrays", matches);
+    let input_fasta = matches
+        .get_one::<PathBuf>("fasta")
+        .expect("errored by clap");
+    let reader = open_sequence_reader(input_fasta, &InputOptions::default())?;
+
+    let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+    provenance.set_motifs(&telomeric_repeats);
+    eprintln!(
+        "[+]\tCalling telomeric arrays of: {}",
+        telomeric_repeats.join(", ")
+    );
+
+    let options = ArrayOptions::from_matches(matches);
+    if let Some(d) = options.distance {
+        d.check(&telomeric_repeats)?;
+        eprintln!("[+]\tUsing approximate matches with {d}");
+    }
+
+    let extension = matches
+        .get_one::<String>("extension")
+        .expect("defaulted by clap");
+    let outdir = matches.get_one::<PathBuf>("dir").expect("errored by clap");
+    let output = matches
+        .get_one::<PathBuf>("output")
+        .expect("errored by clap");
+
+    // create directory for output
+    create_dir_all(outdir)?;
+
+    // create file
+    let file_name = format!(
+        "{}/{}{}{}",
+        outdir.display(),
+        output.display(),
+        "_telomeric_arrays.",
+        extension
+    );
+    let arrays_file = File::create(file_name)?;
+    let mut arrays_file = LineWriter::new(arrays_file);
+
+    match extension.as_str() {
+        "gff3" => writeln!(arrays_file, "##gff-version 3")?,
+        "tsv" => writeln!(
+            arrays_file,
+            "id\tstart\tend\tstrand\ttelomeric_repeat\tunits\tlength\tpurity"
+        )?,
+        _ => (),
+    }
+
+    let mut array_number = 0;
+    // iterate over the fasta records
+    for result in reader.records() {
+        let record = result?;
+        let id = record.id().to_owned();
+        let sequence = str::from_utf8(record.seq())?.to_uppercase();
+
+        let arrays = call_arrays(&id, &sequence, &telomeric_repeats, &options);
+
+        if extension == "gff3" {
+            writeln!(
+                arrays_file,
+                "##sequence-region {} 1 {}",
+                id,
+                record.seq().len()
+            )?;
+        }
+        for array in &arrays {
+            array_number += 1;
+            write_array(&mut arrays_file, array, extension, array_number)?;
+        }
+
+        provenance.add_record(json!({
+            "id": id,
+            "length": record.seq().len(),
+            "arrays": arrays.len(),
+        }));
+
+        eprintln!(
+            "[+]\tChromosome {id} processed: {} telomeric array(s)",
+            arrays.len()
+        );
+    }
+    eprintln!("[+]\tFinished calling {array_number} telomeric array(s).");
+
+    // optional log file
+    sc.log(matches)?;
+    provenance.write()?;
+
+    Ok(())
+}
+
+/// Call the telomeric arrays in an (uppercase) sequence, for each
+/// telomeric repeat and on both strands. Arrays are sorted by start.
+pub fn call_arrays(
+    id: &str,
+    sequence: &str,
+    telomeric_repeats: &[String],
+    options: &ArrayOptions,
+) -> Vec<TelomericArray> {
+    let mut arrays = Vec::new();
+
+    for telomeric_repeat in telomeric_repeats {
+        let reverse_telomeric_repeat = utils::reverse_complement(telomeric_repeat);
+
+        for (motif, strand) in [
+            (telomeric_repeat.as_str(), Strand::Forward),
+            (reverse_telomeric_repeat.as_str(), Strand::Reverse),
+        ] {
+            let hits = match options.distance {
+                Some(d) => utils::find_approximate_motifs(motif, sequence, d).indexes,
+                None => utils::find_motifs(motif, sequence).indexes,
+            };
+
+            for (start, end, units) in merge_hits(&hits, motif.len(), options.max_gap) {
+                if end - start < options.min_length {
+                    continue;
+                }
+                arrays.push(TelomericArray {
+                    id: id.to_string(),
+                    start,
+                    end,
+                    strand,
+                    telomeric_repeat: telomeric_repeat.clone(),
+                    units,
+                    purity: (units * motif.len()) as f64 / (end - start) as f64,
+                });
+            }
+        }
+    }
+
+    arrays.sort_by_key(|a| (a.start, a.end));
+    arrays
+}
+
+/// Merge the (sorted) start positions of motif hits into runs,
+/// returning the start, end and number of units of each run.
+/// Hits overlapping the previous unit are skipped, and a run is
+/// broken when the gap to the next unit exceeds `max_gap`.
+fn merge_hits(hits: &[usize], motif_length: usize, max_gap: usize) -> Vec<(usize, usize, usize)> {
+    let mut runs: Vec<(usize, usize, usize)> = Vec::new();
+
+    for &hit in hits {
+        match runs.last_mut() {
+            Some((_, end, _)) if hit < *end => continue,
+            Some((_, end, units)) if hit - *end <= max_gap => {
+                *end = hit + motif_length;
+                *units += 1;
+            }
+            _ => runs.push((hit, hit + motif_length, 1)),
+        }
+    }
+    runs
+}
+
+/// Write a single telomeric array as BED6, GFF3 or TSV.
+fn write_array<T: std::io::Write>(
+    file: &mut LineWriter<T>,
+    array: &TelomericArray,
+    extension: &str,
+    array_number: usize,
+) -> Result<()> {
+    match extension {
+        "gff3" => writeln!(
+            file,
+            "{}\ttidk\ttelomeric_repeat\t{}\t{}\t{:.3}\t{}\t.\tID=telomeric_array_{};rpt_unit_seq={};units={};length={};purity={:.3}",
+            array.id,
+            // GFF3 is 1-based and inclusive
+            array.start + 1,
+            array.end,
+            array.purity,
+            array.strand,
+            array_number,
+            array.telomeric_repeat,
+            array.units,
+            array.len(),
+            array.purity
+        )?,
+        "tsv" => writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.3}",
+            array.id,
+            array.start,
+            array.end,
+            array.strand,
+            array.telomeric_repeat,
+            array.units,
+            array.len(),
+            array.purity
+        )?,
+        // BED6, with the purity scaled to a score out of 1000
+        _ => writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            array.id,
+            array.start,
+            array.end,
+            array.telomeric_repeat,
+            (array.purity * 1000.0).round() as usize,
+            array.strand
+        )?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a C-rich array at the start, a broken G-rich array at the end
+    const SEQUENCE: &str = "CCTAACCTAACCTAACCTAAGCATGCATGCATGCATTTAGGTTAGGTTAGGCATTTAGGTTAGGTTAGG";
+
+    #[test]
+    fn test_call_arrays() {
+        let arrays = call_arrays(
+            "test1",
+            SEQUENCE,
+            &["TTAGG".into()],
+            &ArrayOptions::new(5, 10),
+        );
+
+        assert_eq!(arrays.len(), 2);
+        // the C-rich array is on the reverse strand
+        assert_eq!(
+            (
+                arrays[0].start,
+                arrays[0].end,
+                arrays[0].strand,
+                arrays[0].units
+            ),
+            (0, 20, Strand::Reverse, 4)
+        );
+        // the gap of three bases is bridged
+        assert_eq!(
+            (
+                arrays[1].start,
+                arrays[1].end,
+                arrays[1].strand,
+                arrays[1].units
+            ),
+            (36, 69, Strand::Forward, 6)
+        );
+        assert!((arrays[1].purity - 30.0 / 33.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_call_arrays_no_gap() {
+        let arrays = call_arrays(
+            "test1",
+            SEQUENCE,
+            &["TTAGG".into()],
+            &ArrayOptions::new(0, 10),
+        );
+
+        // the G-rich array is split in two
+        assert_eq!(arrays.len(), 3);
+        assert_eq!((arrays[1].start, arrays[1].end), (36, 51));
+        assert_eq!((arrays[2].start, arrays[2].end), (54, 69));
+    }
+}
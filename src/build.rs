@@ -1,3 +1,4 @@
+use crate::provenance::Provenance;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::fs;
@@ -6,6 +7,8 @@ use std::path::PathBuf;
 use base64::prelude::*;
 
 const TIDK_DATABASE: &str = "tidk_database.csv";
+/// Holds the SHA of the upstream file the database was fetched from.
+const TIDK_DATABASE_SHA: &str = "tidk_database.sha";
 
 // Define the structure of the tidk database
 #[derive(Serialize, Deserialize, Debug)]
@@ -39,9 +42,27 @@ pub fn get_database_path() -> Result<PathBuf> {
     Ok(app_dir.join(TIDK_DATABASE)) // Path to the dataset file
 }
 
-// Fetch the data from the github repository
-// return the sha and the data
-pub fn fetch_and_save_data() -> Result<()> {
+// The SHA of the upstream file the database was built from, if it was
+// built by a version of tidk that kept it
+pub fn get_database_sha() -> Option<String> {
+    let path = get_database_path().ok()?.with_file_name(TIDK_DATABASE_SHA);
+    fs::read_to_string(path)
+        .ok()
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty())
+}
+
+/// The entry point for `tidk build`.
+pub fn build(matches: &clap::ArgMatches) -> Result<()> {
+    let mut provenance = Provenance::new("build", matches);
+    let sha = fetch_and_save_data()?;
+    provenance.set_database(get_database_path()?, Some(sha));
+    provenance.write()
+}
+
+// Fetch the data from the github repository, saving it along with
+// its sha, which is returned
+pub fn fetch_and_save_data() -> Result<String> {
     eprintln!("tidk build: fetching and saving data from the remote repository.");
     // Define the URL for the dataset
     // here we take from the actual repo
@@ -58,10 +79,11 @@ pub fn fetch_and_save_data() -> Result<()> {
     let response = client.get(url).headers(headers).send()?;
     let response_json = response.json::<serde_json::Value>()?;
 
-    // FIXME: use this sha later on to do better version control?
-    let _sha = response_json["sha"]
+    // kept with the database, to record which version was used
+    let sha = response_json["sha"]
         .as_str()
-        .context("No sha found in response")?;
+        .context("No sha found in response")?
+        .to_string();
     let mut content = response_json["content"]
         .as_str()
         .context("No content found in response")?
@@ -74,11 +96,12 @@ pub fn fetch_and_save_data() -> Result<()> {
     let data_u8 = BASE64_STANDARD.decode(content)?;
     let data = String::from_utf8(data_u8)?;
 
-    // write the data to file - the name of which is the SHA commit
+    // write the data to file, and the SHA alongside it
     let database_path = get_database_path()?;
     fs::write(&database_path, data)?;
+    fs::write(database_path.with_file_name(TIDK_DATABASE_SHA), &sha)?;
 
     eprintln!("Data fetched and saved to: {}", database_path.display());
 
-    Ok(())
+    Ok(sha)
 }
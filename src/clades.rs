@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use std::fmt::{self, Display};
 use tabled::{
     settings::{
@@ -9,6 +9,7 @@ use tabled::{
 };
 
 use crate::build::{get_database_path, TelomereRepeatRow};
+use crate::utils::parse_telomeric_repeat;
 
 /// A telomeric repeat sequence, or sequences.
 #[derive(Debug, Clone)]
@@ -129,7 +130,10 @@ pub fn return_telomere_sequence(clade: String) -> Result<TelomereSeq> {
     for result in rdr.deserialize() {
         let record: TelomereRepeatRow = result?;
         if record.order == clade {
-            telomere_seq.push(record.telomeric_repeat);
+            // IUPAC ambiguity codes are allowed, anything else is an error
+            let telomeric_repeat = parse_telomeric_repeat(&record.telomeric_repeat)
+                .with_context(|| format!("Invalid telomeric repeat in the database for {clade}"))?;
+            telomere_seq.push(telomeric_repeat);
         }
     }
 
@@ -1,10 +1,13 @@
-use crate::{open_fasta_reader, utils, SubCommand};
-use anyhow::bail;
+use crate::provenance::Provenance;
+use crate::{open_sequence_reader, utils, InputOptions, SubCommand};
 use anyhow::Result;
+use anyhow::{bail, Context};
 use itertools::Itertools;
 use rayon::prelude::*;
+use serde_json::json;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::str;
 use std::sync::mpsc::channel;
@@ -18,10 +21,14 @@ static REPEAT_PERIOD_THRESHOLD: usize = 3;
 /// The function called from `tidk explore`. It takes the [`clap::Argmatches`]
 /// from the user and also a [`SubCommand`].
 pub fn explore(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
+    let mut provenance = Provenance::new("explore", matches);
+    // records are scanned on the global pool
+    provenance.set_threads(rayon::current_num_threads());
     // parse arguments from main
     let input_fasta = matches
         .get_one::<PathBuf>("fasta")
         .expect("errored by clap");
+    let input_options = InputOptions::from_matches(matches)?;
     let length = *matches.get_one::<usize>("length").expect("errored by clap");
 
     // if length is not set, these are the lengths (and length itself is set to zero)
@@ -43,87 +50,36 @@ pub fn explore(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
     }
 
     let verbose = matches.get_flag("verbose");
+    // bases masked for their low quality can't be part of a repeat
+    let skip_masked = input_options.min_quality.is_some();
+    let method = Method::from_matches(matches);
+    if method == Method::Period {
+        eprintln!("[+]\tFinding tandem repeats at any offset, tolerating small indels");
+    }
 
-    // to report the telomeres...
-    let mut output_vec: Vec<RepeatPositions> = Vec::new();
     // i.e. if you chose a length, as opposed to a minmum/maximum
-    if length > 0 {
+    let lengths = if length > 0 {
         eprintln!("[+]\tExploring genome for potential telomeric repeats of length: {length}");
-        let reader = open_fasta_reader(input_fasta)?;
-
-        // try parallelising
-        let (sender, receiver) = channel();
-
-        reader
-            .records()
-            .par_bridge()
-            .for_each_with(sender, |s, record| {
-                let record = record.expect("[-]\tError during fasta record parsing.");
-                let id = record.id().to_owned();
-                let seq_len = record.seq().len();
-
-                let sequences = split_seq_by_distance(record, dist_from_chromosome_end, seq_len);
-
-                for sequence in sequences {
-                    let indexes = chunk_fasta(sequence, length, verbose, id.clone());
-
-                    if let Some(r) =
-                        calculate_indexes(indexes, length, verbose, id.clone(), threshold as usize)
-                    {
-                        s.send(r).expect("Did not send!");
-                    }
-                }
-            });
-
-        // this bit is a little chaotic
-        // collect output into a vector
-        let mut output = receiver.into_iter().collect();
-
-        output_vec.append(&mut output);
+        length..=length
     } else {
         // if a range was chosen.
         eprintln!(
             "[+]\tExploring genome for potential telomeric repeats between lengths {minimum} and {maximum}."
         );
-        for length in minimum..maximum + 1 {
-            eprintln!("[+]\t\tFinding telomeric repeat length: {length}");
-
-            // have to call reader in the loop, as otherwise `reader` doesn't live long enough.
-            // I expect it's not an expensive call anyway.
-            let reader = open_fasta_reader(input_fasta)?;
-
-            // try parallelising
-            let (sender, receiver) = channel();
-            reader
-                .records()
-                .par_bridge()
-                .for_each_with(sender, |s, record| {
-                    let record = record.expect("[-]\tError during fasta record parsing.");
-                    let id = record.id().to_owned();
-                    let seq_len = record.seq().len();
-
-                    let sequences =
-                        split_seq_by_distance(record, dist_from_chromosome_end, seq_len);
-
-                    for sequence in sequences {
-                        let indexes = chunk_fasta(sequence, length, verbose, id.clone());
-
-                        if let Some(r) = calculate_indexes(
-                            indexes,
-                            length,
-                            verbose,
-                            id.clone(),
-                            threshold as usize,
-                        ) {
-                            s.send(r).expect("Did not send!");
-                        }
-                    }
-                });
-            let mut output = receiver.iter().collect();
+        minimum..=maximum
+    };
+    let reader = open_sequence_reader(input_fasta, &input_options)?;
+    // to report the telomeres...
+    let output_vec = explore_records(
+        reader.records(),
+        lengths,
+        dist_from_chromosome_end,
+        method,
+        verbose,
+        threshold as usize,
+        skip_masked,
+    )?;
 
-            output_vec.append(&mut output);
-        }
-    }
     eprintln!("[+]\tFinished searching genome");
     eprintln!("[+]\tGenerating output");
 
@@ -138,27 +94,228 @@ pub fn explore(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
 
     // this is not technically a count - it's a count of runs > threshold
     println!("canonical_repeat_unit\tcount_repeat_runs_gt_{threshold}");
-    for (cru, count) in est {
+    for (cru, count) in &est {
         println!("{cru}\t{count}");
     }
+    provenance.set_summary(json!({
+        "repeats": est
+            .iter()
+            .map(|(cru, count)| json!({ "canonical_repeat_unit": cru, "count_repeat_runs": count }))
+            .collect::<Vec<_>>(),
+    }));
 
     // optional log file
     sc.log(matches)?;
+    provenance.write()?;
 
     Ok(())
 }
 
+/// Find the repeats of each length in the ends of each record. The
+/// records are read just once, each scanned for every length, so they
+/// can be streamed (e.g. from stdin). A record which can't be read is an
+/// error, rather than a panic in a worker thread.
+fn explore_records<I>(
+    records: I,
+    lengths: RangeInclusive<usize>,
+    dist_from_chromosome_end: f64,
+    method: Method,
+    verbose: bool,
+    threshold: usize,
+    skip_masked: bool,
+) -> Result<Vec<RepeatPositions>>
+where
+    I: Iterator<Item = std::io::Result<bio::io::fasta::Record>> + Send,
+{
+    // try parallelising
+    let (sender, receiver) = channel();
+
+    records
+        .par_bridge()
+        .try_for_each_with(sender, |s, record| {
+            let record = record.context("Could not read a record.")?;
+            let id = record.id().to_owned();
+            let seq_len = record.seq().len();
+
+            let sequences = split_seq_by_distance(&record, dist_from_chromosome_end, seq_len);
+
+            for length in lengths.clone() {
+                for sequence in sequences {
+                    if let Some(r) = find_repeats(
+                        sequence,
+                        length,
+                        method,
+                        verbose,
+                        id.clone(),
+                        threshold,
+                        skip_masked,
+                    ) {
+                        s.send(r).expect("Did not send!");
+                    }
+                }
+            }
+            Ok::<(), anyhow::Error>(())
+        })?;
+
+    // collect output into a vector
+    Ok(receiver.into_iter().collect())
+}
+
+/// The two ends of a sequence, each a proportion of its length long.
+/// These borrow from the record rather than copying it.
 pub fn split_seq_by_distance(
-    sequence: bio::io::fasta::Record,
+    sequence: &bio::io::fasta::Record,
     dist_from_chromosome_end: f64,
     seq_len: usize,
-) -> [Vec<u8>; 2] {
+) -> [&[u8]; 2] {
     let dist = (seq_len as f64 * dist_from_chromosome_end).ceil() as usize;
-    let filtered_sequence1 = sequence.seq()[0..dist].to_vec();
-    let filtered_sequence2 = sequence.seq()[(seq_len - dist)..].to_vec();
+    let filtered_sequence1 = &sequence.seq()[0..dist];
+    let filtered_sequence2 = &sequence.seq()[(seq_len - dist)..];
     [filtered_sequence1, filtered_sequence2]
 }
 
+/// How tandem repeats of a given length are found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Compare adjacent, non-overlapping chunks of the sequence. Only
+    /// arrays in phase with the chunks are found, and an indel splits
+    /// an array.
+    Chunk,
+    /// Compare each base with the base one repeat length along, so that
+    /// arrays are found at any offset, and across small indels.
+    Period,
+}
+
+impl Method {
+    /// Parse the method from the command line.
+    pub fn from_matches(matches: &clap::ArgMatches) -> Self {
+        match matches.get_one::<String>("method").map(|e| e.as_str()) {
+            Some("period") => Method::Period,
+            _ => Method::Chunk,
+        }
+    }
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Method::Chunk => write!(f, "chunk"),
+            Method::Period => write!(f, "period"),
+        }
+    }
+}
+
+/// Find the tandem repeats of length `length` in a sequence, by
+/// `method`, keeping those with more than `frequency` copies. If
+/// `skip_masked`, bases masked by `--min-quality` are not counted as
+/// part of a repeat.
+fn find_repeats(
+    sequence: &[u8],
+    length: usize,
+    method: Method,
+    verbose: bool,
+    id: String,
+    frequency: usize,
+    skip_masked: bool,
+) -> Option<RepeatPositions> {
+    match method {
+        Method::Chunk => {
+            let indexes = chunk_fasta(sequence, length, verbose, id.clone(), skip_masked);
+            calculate_indexes(indexes, length, verbose, id, frequency)
+        }
+        Method::Period => {
+            let collection = find_periodic_runs(sequence, length, &id, skip_masked);
+            if collection.is_empty() {
+                if verbose {
+                    eprintln!(
+                        "[-]\t\tChromosome {id}: No tandem repeats of length {length} were identified."
+                    );
+                }
+                None
+            } else {
+                Some(RepeatPositions(collection).filter_by_frequency(frequency))
+            }
+        }
+    }
+}
+
+/// Find tandem arrays with a period of `period` bases at any offset in
+/// a sequence, by comparing each base with the base `period` further
+/// along (an autocorrelation at that lag). Inside an array they match.
+/// A stretch of at least `period` matches in a row (two whole copies)
+/// starts an array, and stretches separated by no more than twice the
+/// period are joined, as an indel or substitution only breaks the
+/// matches for about a period. The repeat unit of each array is its most
+/// common `period`-mer, at any offset. If `skip_masked`, bases masked to
+/// `N` (by `--min-quality`) can't be part of an array.
+fn find_periodic_runs(
+    sequence: &[u8],
+    period: usize,
+    id: &str,
+    skip_masked: bool,
+) -> Vec<RepeatPosition> {
+    if period == 0 || sequence.len() <= period {
+        return Vec::new();
+    }
+
+    // the stretches of consecutive matches, at least a period long
+    let mut stretches: Vec<(usize, usize)> = Vec::new();
+    let mut stretch_start = None;
+    for i in 0..=sequence.len() - period {
+        let is_match = i < sequence.len() - period
+            && sequence[i].eq_ignore_ascii_case(&sequence[i + period])
+            && !(skip_masked && matches!(sequence[i], b'N' | b'n'));
+        match (is_match, stretch_start) {
+            (true, None) => stretch_start = Some(i),
+            (false, Some(start)) => {
+                if i - start >= period {
+                    stretches.push((start, i));
+                }
+                stretch_start = None;
+            }
+            _ => (),
+        }
+    }
+
+    // join stretches broken by an indel or substitution
+    let mut arrays: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in stretches {
+        match arrays.last_mut() {
+            Some(last) if start - last.1 <= 2 * period => last.1 = end,
+            _ => arrays.push((start, end)),
+        }
+    }
+
+    arrays
+        .into_iter()
+        .map(|(start, end)| {
+            // the matches at the end of the array look a period along
+            let end = end + period;
+            RepeatPosition {
+                id: id.to_string(),
+                start,
+                end,
+                sequence: most_common_kmer(&sequence[start..end].to_ascii_uppercase(), period),
+            }
+        })
+        .collect()
+}
+
+/// The most common k-mer of a sequence, the lexicographically smallest
+/// of any ties.
+fn most_common_kmer(sequence: &[u8], k: usize) -> String {
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+    for kmer in sequence.windows(k) {
+        *counts.entry(kmer).or_insert(0) += 1;
+    }
+    let kmer = counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0)))
+        .map(|e| e.0)
+        .unwrap_or_default();
+    String::from_utf8_lossy(kmer).into_owned()
+}
+
 /// A chunked fasta segment with a position and a sequence.
 /// We split the fasta into chunks of size k, where k is the
 /// potential telomeric repeat length. Consecutive iterations
@@ -174,12 +331,14 @@ pub struct ChunkedFasta {
 
 /// Chunk a fasta into a [`Vec<ChunkedFasta>`], i.e. split a fasta into chunks
 /// and compare adjacent chunks for equality. Store the positions and sequences
-/// if they are equivalent.
+/// if they are equivalent. If `skip_masked`, chunks with bases masked to `N`
+/// (by `--min-quality`) are not counted as repeats.
 fn chunk_fasta(
-    sequence: Vec<u8>,
+    sequence: &[u8],
     chunk_length: usize,
     verbose: bool,
     id: String,
+    skip_masked: bool,
 ) -> Vec<ChunkedFasta> {
     let sequence_len = sequence.len();
     let chunks = sequence.chunks(chunk_length);
@@ -205,7 +364,8 @@ fn chunk_fasta(
     // this is the heavy lifting.
     // can use the enumerate to check whether the position is < dist from start or > dist from end.
     for (a, b) in chunks.zip(chunks_plus_one) {
-        if a == b {
+        // masked (low quality) bases can't be part of a repeat
+        if a == b && !(skip_masked && a.iter().any(|e| matches!(e, b'N' | b'n'))) {
             if is_first_consecutive {
                 indexes.push(ChunkedFasta {
                     position: pos,
@@ -224,7 +384,7 @@ fn chunk_fasta(
                     sequence: str::from_utf8(a).unwrap().to_uppercase(),
                 });
             }
-        } else if a != b {
+        } else {
             pos += chunk_length;
             is_first_consecutive = true;
         }
@@ -424,7 +584,7 @@ fn get_telomeric_repeat_estimates(
     }
 
     let mut count_vec: Vec<_> = map.into_iter().collect();
-    count_vec.sort_by(|a, b| b.1.cmp(&a.1));
+    count_vec.sort_by_key(|b| std::cmp::Reverse(b.1));
     filter_count_vec(&mut count_vec)?;
 
     Ok(count_vec)
@@ -501,7 +661,7 @@ mod tests {
 
     fn split_by_dist(genome: &str) -> [Vec<u8>; 2] {
         let record = bio::io::fasta::Record::with_attrs("id1", None, genome.as_bytes());
-        split_seq_by_distance(record, DIST_FROM_CHROM_END, genome.len())
+        split_seq_by_distance(&record, DIST_FROM_CHROM_END, genome.len()).map(|e| e.to_vec())
     }
 
     // GENOME/GENOME_2 are just two meta-repeats, so this should just be in half
@@ -521,7 +681,7 @@ mod tests {
 
     fn generate_chunks_left(genome: &str) -> Vec<ChunkedFasta> {
         let left = &split_by_dist(genome)[0];
-        chunk_fasta(left.clone(), CHUNK_LENGTH, false, "".into())
+        chunk_fasta(left, CHUNK_LENGTH, false, "".into(), false)
     }
 
     #[test]
@@ -553,7 +713,7 @@ mod tests {
 
     fn generate_chunks_right() -> Vec<ChunkedFasta> {
         let left = &split_by_dist(GENOME)[1];
-        chunk_fasta(left.clone(), CHUNK_LENGTH, false, "".into())
+        chunk_fasta(left, CHUNK_LENGTH, false, "".into(), false)
     }
 
     #[test]
@@ -618,6 +778,141 @@ mod tests {
         assert_eq!(map_len, 3);
     }
 
+    // an array of 30 copies, with a sequence either side
+    fn make_array(copies: usize, offset: usize) -> Vec<u8> {
+        let flank = b"GCGTACGATCGATTGCACGTACGCATGCAGTCGATGCGTAGCTAGCTGACTG";
+        [&flank[..offset], "AACCT".repeat(copies).as_bytes(), flank].concat()
+    }
+
+    #[test]
+    fn test_periodic_runs_any_offset() {
+        // the chunks are only in phase with the array for one offset
+        for offset in 0..5 {
+            let sequence = make_array(30, offset);
+            let runs = find_periodic_runs(&sequence, CHUNK_LENGTH, "test", false);
+            assert_eq!(runs.len(), 1);
+            assert_eq!(runs[0].get_count(), 30);
+            assert_eq!(runs[0].sequence, "AACCT");
+
+            let chunks = chunk_fasta(&sequence, CHUNK_LENGTH, false, "test".into(), false);
+            assert_eq!(chunks.len() >= 30, offset == 0);
+        }
+    }
+
+    #[test]
+    fn test_periodic_runs_indels() {
+        let mut sequence = make_array(30, 0);
+        // a deletion, an insertion and a substitution
+        sequence.remove(30);
+        sequence.insert(80, b'G');
+        sequence[120] = b'G';
+
+        let runs = find_periodic_runs(&sequence, CHUNK_LENGTH, "test", false);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].start, 0);
+        assert_eq!(runs[0].end, 150);
+        // the indels shift which rotation is most common
+        assert!(utils::string_rotation(&runs[0].sequence, "AACCT"));
+
+        // whereas each splits the chunked arrays
+        let chunks = chunk_fasta(&sequence, CHUNK_LENGTH, false, "test".into(), false);
+        let indexes = calculate_indexes(chunks, CHUNK_LENGTH, false, "test".into(), 0).unwrap();
+        assert!(indexes.0.len() > 1);
+    }
+
+    #[test]
+    fn test_chunks_masked() {
+        // an array, part of it masked
+        let sequence = [b"AACCT".repeat(4), b"NNNNN".repeat(4)].concat();
+        let chunks = chunk_fasta(&sequence, CHUNK_LENGTH, false, "test".into(), false);
+        assert_eq!(chunks.len(), 8);
+        let chunks = chunk_fasta(&sequence, CHUNK_LENGTH, false, "test".into(), true);
+        assert_eq!(chunks.len(), 4);
+    }
+
+    #[test]
+    fn test_explore_records_once() {
+        // the records can only be read once, as from stdin
+        let records = vec![
+            bio::io::fasta::Record::with_attrs("five", None, &make_array(30, 0)),
+            bio::io::fasta::Record::with_attrs("six", None, "AACCTT".repeat(30).as_bytes()),
+        ]
+        .into_iter()
+        .map(Ok);
+        let output = explore_records(records, 4..=6, 0.5, Method::Period, false, 0, false).unwrap();
+
+        let mut found: Vec<(String, usize)> = output
+            .iter()
+            .flat_map(|e| e.0.iter())
+            .map(|e| (e.id.clone(), e.sequence.len()))
+            .collect();
+        found.sort();
+        found.dedup();
+        assert!(found.contains(&("five".into(), 5)));
+        assert!(found.contains(&("six".into(), 6)));
+    }
+
+    #[test]
+    fn test_explore_records_error() {
+        // a record which can't be read
+        let records = vec![
+            Ok(bio::io::fasta::Record::with_attrs(
+                "five",
+                None,
+                &make_array(30, 0),
+            )),
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad")),
+        ]
+        .into_iter();
+        let output = explore_records(records, 4..=6, 0.5, Method::Chunk, false, 0, false);
+        assert!(output.is_err());
+    }
+
+    #[test]
+    fn test_periodic_runs_none() {
+        // no run of two whole copies
+        let runs = find_periodic_runs(b"GCGTACGATCGATTGCACGTACGCAT", CHUNK_LENGTH, "test", false);
+        assert!(runs.is_empty());
+    }
+
+    #[test]
+    fn test_methods_masked() {
+        // N is a base like any other unless it is masked, in an array or
+        // a run of its own (which is a simple repeat, so not reported)
+        let flank = b"GCGTACGATCGATTGCACGTACGCATGCAGTCGATGCGTAGCTAGCTGAC";
+        let sequence = [
+            &[b'N'; 50],
+            &flank[..],
+            "AACCN".repeat(10).as_bytes(),
+            flank,
+        ]
+        .concat();
+        for skip_masked in [false, true] {
+            let counts: Vec<Vec<(String, usize)>> = [Method::Chunk, Method::Period]
+                .into_iter()
+                .map(|method| {
+                    find_repeats(
+                        &sequence,
+                        CHUNK_LENGTH,
+                        method,
+                        false,
+                        "test".into(),
+                        0,
+                        skip_masked,
+                    )
+                    .map(|e| {
+                        e.0.iter()
+                            .map(|e| (e.sequence.clone(), e.get_count()))
+                            .collect()
+                    })
+                    .unwrap_or_default()
+                })
+                .collect();
+            assert_eq!(counts[0], counts[1]);
+            assert_eq!(counts[0].is_empty(), skip_masked);
+        }
+    }
+
     #[test]
     fn test_get_telomeric_repeat_estimates() {
         let mut indices = generate_indexes_left(GENOME_2);
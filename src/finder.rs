@@ -1,6 +1,8 @@
-use crate::{clades, open_fasta_reader, utils, SubCommand};
-use anyhow::{Context, Result};
-use std::fs::{create_dir_all, File};
+use crate::output::{self, Compression};
+use crate::provenance::{Provenance, WindowCounts, WindowTotals};
+use crate::stream::{Window, WindowStream};
+use crate::{build, clades, open_sequence_stream, utils, InputOptions, SubCommand};
+use anyhow::{bail, Context, Result};
 use std::io::LineWriter;
 use std::io::Write;
 use std::path::PathBuf;
@@ -16,12 +18,19 @@ pub fn finder(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
         clades::print_table()?;
         process::exit(1);
     }
+    let mut provenance = Provenance::new("find", matches);
 
     let input_fasta: PathBuf = matches
         .get_one::<PathBuf>("fasta")
         .expect("errored by clap")
         .clone();
-    let reader = open_fasta_reader(input_fasta)?;
+    let mut input_options = InputOptions::from_matches(matches)?;
+    // only scan the ends of each sequence, if asked
+    input_options.ends = utils::Ends::from_matches(matches)?;
+    if let Some(ends) = input_options.ends {
+        eprintln!("[+]\tOnly scanning {ends} at each end of each sequence");
+    }
+    let mut fasta_stream = open_sequence_stream(input_fasta, &input_options)?;
 
     let clade = matches
         .get_one::<String>("clade")
@@ -29,6 +38,7 @@ pub fn finder(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
         .clone();
 
     let clade_info = clades::return_telomere_sequence(clade)?;
+    provenance.set_database(build::get_database_path()?, build::get_database_sha());
 
     if clade_info.length == 1 {
         eprintln!(
@@ -53,128 +63,189 @@ pub fn finder(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
         }
     }
 
-    let window_size: usize = *matches.get_one::<usize>("window").expect("errored by clap");
+    let options = utils::WindowOptions::from_matches(matches)?;
+    if let Some(d) = options.distance {
+        d.check(clade_info.seq.get_inner())?;
+        eprintln!("[+]\tAlso counting approximate matches with {d}");
+    }
+
     let outdir = matches.get_one::<PathBuf>("dir").expect("errored by clap");
     let output = matches
         .get_one::<PathBuf>("output")
         .expect("errored by clap");
 
-    // create directory for output
-    create_dir_all(outdir)?;
-
-    // create file
-    let file_name = format!(
-        "{}/{}{}",
-        outdir.display(),
-        output.display(),
-        "_telomeric_repeat_windows.tsv"
-    );
-    let finder_file = File::create(file_name)?;
+    let threads = *matches
+        .get_one::<usize>("threads")
+        .expect("defaulted by clap");
+    if threads == 0 {
+        bail!("The number of threads must be greater than zero.");
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+
+    // fail now rather than after the search if the log has no name
+    if matches.get_flag("log") {
+        crate::log_file_name(matches)?;
+    }
+
+    // create file (or write to stdout)
+    let compression = Compression::from_matches(matches, "tsv");
+    let finder_file =
+        output::create_output(outdir, output, "_telomeric_repeat_windows.tsv", compression)?;
+    if compression.is_compressed() {
+        eprintln!("[+]\tCompressing the output with {compression}, sorted by coordinate");
+    }
     let mut finder_file = LineWriter::new(finder_file);
     // add headers
     writeln!(
         finder_file,
-        "id\twindow\tforward_repeat_number\treverse_repeat_number\ttelomeric_repeat"
+        "{}",
+        options.tsv_header(compression.is_compressed())
     )?;
 
     // extract the string from TelomereSeq struct
     // dereference here because of Box<T>
     let telomeric_repeat = clade_info.seq.get_inner();
-
-    // iterate over the fasta records
-    for result in reader.records() {
-        let record = result?;
-        let id = record.id().to_owned();
+    let motifs: Vec<String> = telomeric_repeat
+        .iter()
+        .take(clade_info.length)
+        .cloned()
+        .collect();
+    provenance.set_motifs(&motifs);
+
+    // iterate over the fasta records, a window at a time
+    let context = options.context(telomeric_repeat);
+    while let Some(id) = fasta_stream.next_record()? {
+        let windows = WindowStream::new(&mut fasta_stream, options, context);
 
         // fn window counter
-        write_window_counts(
-            record,
+        let totals = write_window_counts(
+            windows,
             &mut finder_file,
             clade_info.clone(),
             telomeric_repeat,
-            window_size,
-            id.clone(),
+            &id,
+            options,
+            compression.is_compressed(),
+            &pool,
         )?;
+        provenance.add_record(totals.to_record(&id, &motifs));
 
         eprintln!("[+]\tChromosome {id} processed");
     }
+    // complete the compressed stream
+    finder_file
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .finish()?;
     eprintln!("[+]\tFinished searching genome.");
 
     // optional log file
     sc.log(matches)?;
+    provenance.write()?;
 
     Ok(())
 }
 
-/// Creates the window iterator and iterates over each iteration of the
-/// fasta file, writing on the fly.
-fn write_window_counts<T: std::io::Write>(
-    sequence: bio::io::fasta::Record,
+/// Iterates over the windows of a sequence, which are read a batch
+/// at a time and processed in parallel on `pool`. All the telomeric
+/// repeats of the clade are found in a single pass over each window,
+/// and the rows are written out in a block per telomeric repeat, or
+/// if `sorted`, in coordinate order so the output can be indexed with
+/// `tabix`. Approximate counts are appended if a `distance` is given.
+/// The exact counts are summed over the windows, and returned.
+#[allow(clippy::too_many_arguments)]
+fn write_window_counts<T: std::io::Write, R: std::io::BufRead>(
+    windows: WindowStream<R>,
     file: &mut LineWriter<T>,
     clade_info: clades::TelomereSeq,
     telomeric_repeat: &[String],
-    window_size: usize,
-    id: String,
-) -> Result<()> {
+    id: &str,
+    options: utils::WindowOptions,
+    sorted: bool,
+    pool: &rayon::ThreadPool,
+) -> Result<WindowTotals> {
     // needed as in some clades there is more than one telomeric repeat sequence
-    let mut telomeric_repeat_index = 0;
-    loop {
-        // break this loop if we reach the end of &[&str] of telomeric repeats
-        if clade_info.length == telomeric_repeat_index {
-            break;
-        }
-
-        // get forward and reverse sequences, and length
-        // to remove overlapping matches.
-        let forward_telomeric_seq =
-            telomeric_repeat
-                .get(telomeric_repeat_index)
-                .context(format!(
-                    "Could not get the telomeric repeat with index: {telomeric_repeat_index}."
-                ))?;
-        let reverse_telomeric_seq = utils::reverse_complement(forward_telomeric_seq);
-        let current_telomeric_length = forward_telomeric_seq.len();
-
-        // create the iterator in each loop iteration isnt costly is it?
-        let windows = sequence.seq().chunks(window_size);
-        // keep track of the window size
-
-        let mut end = window_size;
-        // iterate over windows
-        for (i, window) in windows.enumerate() {
-            // make window uppercase
-            let windows_upper = str::from_utf8(window)?.to_uppercase();
+    let telomeric_repeat = telomeric_repeat.get(..clade_info.length).context(format!(
+        "Could not get the {} telomeric repeats.",
+        clade_info.length
+    ))?;
+    let matcher = utils::RepeatMatcher::new(telomeric_repeat, options.distance)?;
+
+    // the rows for each telomeric repeat. With just the one
+    // repeat, or if sorted by coordinate, they can be written
+    // straight away
+    let mut rows = vec![String::new(); telomeric_repeat.len()];
+    let mut totals = WindowTotals::new(telomeric_repeat.len());
+
+    windows.process_in_order(
+        pool,
+        pool.current_num_threads() * 16,
+        |window: &Window| -> Result<(Vec<String>, WindowCounts)> {
+            let Window { start, end, .. } = *window;
+            // make window uppercase, dropping masked (or unmasked) bases if asked
+            let windows_upper = options.masking.apply(&window.seq);
+            let composition = utils::BaseComposition::new(window.bases());
             // for each window, find the motifs in this
-            let forward_motif = utils::find_motifs(forward_telomeric_seq, &windows_upper);
-            let reverse_motif = utils::find_motifs(&reverse_telomeric_seq, &windows_upper);
-
-            // remove overlapping matches
-            // not sure this is necessary, but thought it might be...
-            let forward_motif_noverlap =
-                utils::remove_overlapping_indexes(forward_motif, current_telomeric_length);
-            let reverse_motif_noverlap =
-                utils::remove_overlapping_indexes(reverse_motif, current_telomeric_length);
-
-            // the number of matches for forward/reverse
-            let forward_repeat_number = forward_motif_noverlap.len();
-            let reverse_repeat_number = reverse_motif_noverlap.len();
-
-            if i != 0 {
-                end += window_size;
+            let hits = matcher.find(&windows_upper, window.context_start);
+
+            let mut window_rows = Vec::new();
+            let mut window_counts = Vec::new();
+            for (forward_telomeric_seq, hits) in telomeric_repeat.iter().zip(hits.iter()) {
+                let current_telomeric_length = forward_telomeric_seq.len();
+                let count =
+                    |hits: &[usize]| options.count_hits(hits, current_telomeric_length, start, end);
+
+                // the number of matches for forward/reverse
+                let forward_repeat_number = count(&hits.forward);
+                let reverse_repeat_number = count(&hits.reverse);
+                window_counts.push((forward_repeat_number, reverse_repeat_number));
+                // and the fraction of the window they cover
+                let density = utils::RepeatDensity::new(
+                    &hits.forward,
+                    &hits.reverse,
+                    current_telomeric_length,
+                    (start, end),
+                );
+
+                let mut row = format!(
+                    "{id}\t{start}\t{end}\t{}\t{}\t{forward_telomeric_seq}\t{density}\t{composition}",
+                    options.format_count(forward_repeat_number),
+                    options.format_count(reverse_repeat_number)
+                );
+                if let Some((forward_approx, reverse_approx)) = &hits.approx {
+                    row.push_str(&format!(
+                        "\t{}\t{}",
+                        options.format_count(count(forward_approx)),
+                        options.format_count(count(reverse_approx))
+                    ));
+                }
+                row.push('\n');
+                window_rows.push(row);
             }
-            if end > sequence.seq().len() {
-                end = sequence.seq().len();
+            Ok((window_rows, window_counts))
+        },
+        |(window_rows, window_counts)| {
+            totals.add(&window_counts);
+            if rows.len() == 1 || sorted {
+                for window_row in window_rows {
+                    file.write_all(window_row.as_bytes())?;
+                }
+            } else {
+                for (row, window_row) in rows.iter_mut().zip(window_rows) {
+                    row.push_str(&window_row);
+                }
             }
-            // write to file
-            writeln!(
-                file,
-                "{id}\t{end}\t{forward_repeat_number}\t{reverse_repeat_number}\t{forward_telomeric_seq}"
-            )?;
-        }
-        // go to the next telomeric repeat (if there is one)
-        telomeric_repeat_index += 1;
+            Ok(())
+        },
+    )?;
+
+    // write to file
+    for row in rows {
+        file.write_all(row.as_bytes())?;
     }
-    Ok(())
+    Ok(totals)
 }
 
 #[cfg(test)]
@@ -182,6 +253,8 @@ mod tests {
     use std::io::{LineWriter, Read};
 
     use crate::clades::{Seq, TelomereSeq};
+    use crate::stream::{SequenceStream, WindowStream};
+    use crate::utils::WindowOptions;
 
     use super::write_window_counts;
 
@@ -197,7 +270,26 @@ mod tests {
         let id = rec.id().to_owned();
 
         let telomeric_repeat = ts.seq.get_inner().clone();
-        write_window_counts(rec, &mut lw, ts, &telomeric_repeat, ws, id).unwrap();
+        let options = WindowOptions::new(ws, ws);
+        let fasta = [b">", id.as_bytes(), b"\n", rec.seq(), b"\n"].concat();
+        let mut stream = SequenceStream::new(fasta.as_slice());
+        stream.next_record().unwrap();
+        let windows = WindowStream::new(&mut stream, options, 0);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        write_window_counts(
+            windows,
+            &mut lw,
+            ts,
+            &telomeric_repeat,
+            &id,
+            options,
+            false,
+            &pool,
+        )
+        .unwrap();
 
         // read file contents to new vec
         let mut out = Vec::new();
@@ -227,10 +319,19 @@ mod tests {
         let rows: Vec<&str> = windows_calculation.lines().collect();
 
         // three in first window
-        assert_eq!(rows[0], "test1\t20\t2\t0\tAAACCCT");
+        assert_eq!(
+            rows[0],
+            "test1\t0\t20\t2\t0\tAAACCCT\t20\t0.7000\t0.0000\t0.7000\t0.0000\t0.0000\t0.4500"
+        );
         // none in second
-        assert_eq!(rows[1], "test1\t40\t0\t0\tAAACCCT");
+        assert_eq!(
+            rows[1],
+            "test1\t20\t40\t0\t0\tAAACCCT\t20\t0.0000\t0.0000\t0.0000\t0.0000\t0.0000\t0.6500"
+        );
         // two in third
-        assert_eq!(rows[2], "test1\t57\t1\t0\tAAACCCT");
+        assert_eq!(
+            rows[2],
+            "test1\t40\t57\t1\t0\tAAACCCT\t17\t0.4118\t0.0000\t0.4118\t0.0000\t0.0000\t0.5294"
+        );
     }
 }
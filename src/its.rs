@@ -0,0 +1,296 @@
+use crate::arrays::{call_arrays, ArrayOptions, TelomericArray};
+use crate::provenance::Provenance;
+use crate::{open_sequence_reader, search, InputOptions, SubCommand};
+use anyhow::Result;
+use serde_json::json;
+use std::fmt::{self, Display};
+use std::fs::{create_dir_all, File};
+use std::io::LineWriter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str;
+
+/// The kind of interstitial telomeric sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItsClass {
+    /// A lone array shorter than the long ITS length.
+    Short,
+    /// A lone array at least as long as the long ITS length.
+    Long,
+    /// A G-rich array followed by a C-rich array, i.e. the repeats
+    /// point towards each other, as expected from an end-to-end fusion.
+    HeadToHead,
+    /// A C-rich array followed by a G-rich array, i.e. the repeats
+    /// point away from each other.
+    TailToTail,
+}
+
+impl Display for ItsClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ItsClass::Short => write!(f, "short_its"),
+            ItsClass::Long => write!(f, "long_its"),
+            ItsClass::HeadToHead => write!(f, "head_to_head"),
+            ItsClass::TailToTail => write!(f, "tail_to_tail"),
+        }
+    }
+}
+
+/// An interstitial telomeric sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Its {
+    /// The telomeric array itself.
+    pub array: TelomericArray,
+    /// The distance to the nearest end of the sequence.
+    pub distance_to_end: usize,
+    /// What kind of ITS this is.
+    pub class: ItsClass,
+}
+
+/// Options for cataloguing interstitial telomeric sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ItsOptions {
+    /// Arrays closer than this (bp) to either end are telomeres,
+    /// not ITSs.
+    pub end_distance: usize,
+    /// The length (bp) from which an ITS is long.
+    pub long_length: usize,
+    /// The largest gap (bp) between two arrays of opposite orientation
+    /// for them to be called a pair.
+    pub pair_distance: usize,
+}
+
+impl ItsOptions {
+    /// Parse the options from the command line.
+    pub fn from_matches(matches: &clap::ArgMatches) -> Self {
+        Self {
+            end_distance: *matches
+                .get_one::<usize>("end_distance")
+                .expect("defaulted by clap"),
+            long_length: *matches
+                .get_one::<usize>("long_length")
+                .expect("defaulted by clap"),
+            pair_distance: *matches
+                .get_one::<usize>("pair_distance")
+                .expect("defaulted by clap"),
+        }
+    }
+}
+
+/// The entry point for `tidk its`.
+pub fn its(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
+    let mut provenance = Provenance::new("its", matches);
+    let input_fasta = matches
+        .get_one::<PathBuf>("fasta")
+        .expect("errored by clap");
+    let reader = open_sequence_reader(input_fasta, &InputOptions::default())?;
+
+    let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+    provenance.set_motifs(&telomeric_repeats);
+    eprintln!(
+        "[+]\tCataloguing interstitial telomeric sequences of: {}",
+        telomeric_repeats.join(", ")
+    );
+
+    let array_options = ArrayOptions::from_matches(matches);
+    if let Some(d) = array_options.distance {
+        d.check(&telomeric_repeats)?;
+    }
+    let options = ItsOptions::from_matches(matches);
+
+    let extension = matches
+        .get_one::<String>("extension")
+        .expect("defaulted by clap");
+    let outdir = matches.get_one::<PathBuf>("dir").expect("errored by clap");
+    let output = matches
+        .get_one::<PathBuf>("output")
+        .expect("errored by clap");
+
+    // create directory for output
+    create_dir_all(outdir)?;
+
+    // create file
+    let file_name = format!(
+        "{}/{}{}{}",
+        outdir.display(),
+        output.display(),
+        "_its.",
+        extension
+    );
+    let its_file = File::create(file_name)?;
+    let mut its_file = LineWriter::new(its_file);
+
+    if extension == "tsv" {
+        writeln!(
+            its_file,
+            "id\tstart\tend\tstrand\torientation\ttelomeric_repeat\tlength\tpurity\tdistance_to_end\tclass"
+        )?;
+    }
+
+    let mut its_number = 0;
+    // iterate over the fasta records
+    for result in reader.records() {
+        let record = result?;
+        let id = record.id().to_owned();
+        let sequence = str::from_utf8(record.seq())?.to_uppercase();
+
+        let arrays = call_arrays(&id, &sequence, &telomeric_repeats, &array_options);
+        let catalogue = catalogue_its(sequence.len(), arrays, &options);
+
+        for its in &catalogue {
+            let array = &its.array;
+            if extension == "tsv" {
+                writeln!(
+                    its_file,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.3}\t{}\t{}",
+                    array.id,
+                    array.start,
+                    array.end,
+                    array.strand,
+                    if array.is_g_rich() {
+                        "G-rich"
+                    } else {
+                        "C-rich"
+                    },
+                    array.telomeric_repeat,
+                    array.len(),
+                    array.purity,
+                    its.distance_to_end,
+                    its.class
+                )?;
+            } else {
+                // BED6, with the purity scaled to a score out of 1000
+                writeln!(
+                    its_file,
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    array.id,
+                    array.start,
+                    array.end,
+                    its.class,
+                    (array.purity * 1000.0).round() as usize,
+                    array.strand
+                )?;
+            }
+        }
+        its_number += catalogue.len();
+        provenance.add_record(json!({
+            "id": id,
+            "length": sequence.len(),
+            "its": catalogue.len(),
+        }));
+
+        eprintln!(
+            "[+]\tChromosome {id} processed: {} interstitial telomeric sequence(s)",
+            catalogue.len()
+        );
+    }
+    eprintln!("[+]\tFinished cataloguing {its_number} interstitial telomeric sequence(s).");
+
+    // optional log file
+    sc.log(matches)?;
+    provenance.write()?;
+
+    Ok(())
+}
+
+/// Keep the telomeric arrays (sorted by start) on a sequence of length
+/// `seq_len` which are further than the end distance from both ends,
+/// and classify them. Neighbouring arrays of opposite orientation are
+/// classified as a pair, otherwise arrays are short or long.
+pub fn catalogue_its(
+    seq_len: usize,
+    arrays: Vec<TelomericArray>,
+    options: &ItsOptions,
+) -> Vec<Its> {
+    let internal: Vec<TelomericArray> = arrays
+        .into_iter()
+        .filter(|a| a.start.min(seq_len - a.end) > options.end_distance)
+        .collect();
+
+    let mut classes: Vec<Option<ItsClass>> = vec![None; internal.len()];
+    for i in 1..internal.len() {
+        let (previous, current) = (&internal[i - 1], &internal[i]);
+        if classes[i - 1].is_some()
+            || previous.is_g_rich() == current.is_g_rich()
+            || current.start.saturating_sub(previous.end) > options.pair_distance
+        {
+            continue;
+        }
+        let class = if previous.is_g_rich() {
+            ItsClass::HeadToHead
+        } else {
+            ItsClass::TailToTail
+        };
+        classes[i - 1] = Some(class);
+        classes[i] = Some(class);
+    }
+
+    internal
+        .into_iter()
+        .zip(classes)
+        .map(|(array, class)| {
+            let class = class.unwrap_or(if array.len() >= options.long_length {
+                ItsClass::Long
+            } else {
+                ItsClass::Short
+            });
+            Its {
+                distance_to_end: array.start.min(seq_len - array.end),
+                array,
+                class,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalogue(sequence: &str) -> Vec<Its> {
+        let array_options = ArrayOptions::new(10, 30);
+        let options = ItsOptions {
+            end_distance: 100,
+            long_length: 100,
+            pair_distance: 20,
+        };
+        let arrays = call_arrays("test", sequence, &["TTAGGG".into()], &array_options);
+        catalogue_its(sequence.len(), arrays, &options)
+    }
+
+    #[test]
+    fn test_its_classes() {
+        let spacer = "GCAT".repeat(50);
+        let sequence = [
+            // a telomere, not an ITS
+            "CCCTAA".repeat(10),
+            spacer.clone(),
+            // short
+            "TTAGGG".repeat(6),
+            spacer.clone(),
+            // long
+            "CCCTAA".repeat(20),
+            spacer.clone(),
+            // a fusion
+            "TTAGGG".repeat(6),
+            "CCCTAA".repeat(6),
+            spacer.clone(),
+        ]
+        .concat();
+
+        let catalogue = catalogue(&sequence);
+        let classes: Vec<ItsClass> = catalogue.iter().map(|e| e.class).collect();
+
+        assert_eq!(
+            classes,
+            vec![
+                ItsClass::Short,
+                ItsClass::Long,
+                ItsClass::HeadToHead,
+                ItsClass::HeadToHead
+            ]
+        );
+        // the closest end is the 3' end
+        assert_eq!(catalogue[3].distance_to_end, 200);
+    }
+}
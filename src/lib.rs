@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bio::io::fasta;
 use chrono::Local;
 use clap::crate_version;
@@ -9,6 +9,10 @@ use std::{
     path::{Path, PathBuf},
 };
 
+/// Reading reads from SAM, BAM and CRAM files.
+pub mod alignment;
+/// The entry point for the `tidk arrays` subcommand.
+pub mod arrays;
 /// For building the database
 pub mod build;
 /// A module where the clades are defined, and their
@@ -18,226 +22,704 @@ pub mod clades;
 pub mod explore;
 /// The entry point for the `tidk find` subcommand.
 pub mod finder;
+/// The entry point for the `tidk its` subcommand.
+pub mod its;
+/// Writing output to files or stdout, optionally compressed
+/// with gzip or BGZF.
+pub mod output;
 /// Functions to plot output from `tidk search` and
 /// `tidk find`.
 pub mod plot;
+/// A machine-readable record of each run, for pipelines.
+pub mod provenance;
+/// Reading regions of a FASTA file, using its index if it has one.
+pub mod regions;
 /// The entry point for the `tidk search` subcommand.
 pub mod search;
+/// Reading sequences a block at a time, so that memory
+/// scales with the window size rather than the sequence.
+pub mod stream;
+/// The entry point for the `tidk t2t` subcommand.
+pub mod t2t;
+/// The entry point for the `tidk telomere-content` subcommand.
+pub mod telomere_content;
+/// The entry point for the `tidk telomere-length` subcommand.
+pub mod telomere_length;
 /// Module for utilities.
 pub mod utils;
 
-/// Open a FASTA file that may be plain or gzipped.
-/// Returns a `bio::io::fasta::Reader` whose underlying reader is `Send`,
-/// so you can still use `.records().par_bridge()`.
-pub fn open_fasta_reader<P: AsRef<Path>>(
+/// The formats sequences can be read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceFormat {
+    Fasta,
+    Fastq,
+    /// SAM, BAM or CRAM, read as FASTA.
+    Alignment,
+    /// Regions of a FASTA file, read as FASTA with where each region
+    /// starts in its header.
+    Regions,
+}
+
+/// Options for reading the input sequences, shared by `tidk search`,
+/// `tidk find` and `tidk explore`.
+#[derive(Debug, Clone, Default)]
+pub struct InputOptions {
+    /// Bases of reads below this quality are masked.
+    pub min_quality: Option<u8>,
+    /// Use supplementary as well as primary records of SAM/BAM/CRAM input.
+    pub supplementary: bool,
+    /// Only use these regions of FASTA input, or the reads of SAM/BAM/CRAM
+    /// input aligned to them (everything if empty).
+    pub regions: Vec<utils::Region>,
+    /// Only use the ends of each sequence of FASTA input.
+    pub ends: Option<utils::Ends>,
+    /// The FASTA the reads of CRAM input were aligned to.
+    pub reference: Option<PathBuf>,
+}
+
+impl InputOptions {
+    /// Get the input options from the command line.
+    pub fn from_matches(matches: &clap::ArgMatches) -> Result<Self> {
+        let mut regions = matches
+            .get_many::<String>("region")
+            .unwrap_or_default()
+            .map(|e| e.parse())
+            .collect::<Result<Vec<utils::Region>>>()?;
+        if let Some(bed) = matches.get_one::<PathBuf>("regions") {
+            regions.append(&mut utils::read_bed_regions(bed)?);
+        }
+        // overlapping regions would give the same windows twice
+        let regions = utils::merge_regions(regions);
+
+        Ok(Self {
+            min_quality: matches.get_one::<u8>("min_quality").copied(),
+            supplementary: matches.get_flag("supplementary"),
+            regions,
+            ends: None,
+            reference: matches.get_one::<PathBuf>("reference").cloned(),
+        })
+    }
+}
+
+/// Open a file (or stdin, for `-`) that may be plain or gzipped (or
+/// BGZF), and work out whether it is FASTA, FASTQ, SAM, BAM or CRAM.
+/// Both are decided from the content of the file rather than its
+/// extension. The reads of SAM, BAM and CRAM files are presented as
+/// FASTA, as are just the regions asked for of a FASTA file.
+fn open_input<P: AsRef<Path>>(
     path: P,
-) -> Result<fasta::Reader<Box<dyn BufRead + Send>>> {
+    options: &InputOptions,
+) -> Result<(Box<dyn BufRead + Send>, SequenceFormat)> {
     let path = path.as_ref();
-    let file = File::open(path)?;
-
-    // Decide whether to decompress based on extension.
-    // You could also sniff the magic bytes 0x1f, 0x8b if you want.
-    let boxed_reader: Box<dyn BufRead + Send> = match path.extension().and_then(|e| e.to_str()) {
-        Some("gz") => {
-            let gz = MultiGzDecoder::new(file);
-            Box::new(BufReader::new(gz))
+    // `-` is stdin
+    let file: Box<dyn std::io::Read + Send> = if path == Path::new("-") {
+        Box::new(std::io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+
+    // gzip files start with the magic bytes 0x1f, 0x8b
+    let mut reader = BufReader::new(file);
+    let compressed = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    let mut boxed_reader: Box<dyn BufRead + Send> = if compressed {
+        Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+    } else {
+        Box::new(reader)
+    };
+
+    let start = boxed_reader.fill_buf()?;
+    let is_alignment =
+        start.starts_with(b"BAM\x01") || start.starts_with(b"CRAM") || alignment::is_sam(start);
+    if options.ends.is_some() && is_alignment {
+        bail!("Only the ends of FASTA input can be scanned.");
+    }
+    if start.starts_with(b"BAM\x01") {
+        let reads = alignment::AlignmentFasta::bam(boxed_reader, options.clone())?;
+        return Ok((Box::new(reads), SequenceFormat::Alignment));
+    }
+    if start.starts_with(b"CRAM") {
+        let reads = alignment::AlignmentFasta::cram(boxed_reader, options.clone())?;
+        return Ok((Box::new(reads), SequenceFormat::Alignment));
+    }
+    if alignment::is_sam(start) {
+        let reads = alignment::AlignmentFasta::sam(boxed_reader, options.clone())?;
+        return Ok((Box::new(reads), SequenceFormat::Alignment));
+    }
+
+    // the first character which isn't whitespace
+    let format = match start.iter().find(|e| !e.is_ascii_whitespace()) {
+        Some(b'@') => SequenceFormat::Fastq,
+        Some(b'>') | None => SequenceFormat::Fasta,
+        Some(_) => bail!(
+            "Could not recognise {} as FASTA, FASTQ, SAM, BAM or CRAM.",
+            path.display()
+        ),
+    };
+
+    if !options.regions.is_empty() || options.ends.is_some() {
+        if format == SequenceFormat::Fastq && options.ends.is_some() {
+            bail!("Only the ends of FASTA input can be scanned.");
+        }
+        if format == SequenceFormat::Fastq {
+            bail!("Regions can only be used with FASTA, SAM, BAM or CRAM input.");
         }
-        _ => Box::new(BufReader::new(file)),
+        let regions = regions::RegionFasta::new(
+            path,
+            boxed_reader,
+            compressed,
+            options.regions.clone(),
+            options.ends,
+        )?;
+        return Ok((Box::new(regions), SequenceFormat::Regions));
+    }
+
+    Ok((boxed_reader, format))
+}
+
+/// A reader of FASTA, FASTQ, SAM, BAM or CRAM records.
+pub struct SequenceReader {
+    inner: SequenceReaderInner,
+}
+
+enum SequenceReaderInner {
+    Fasta(fasta::Reader<Box<dyn BufRead + Send>>),
+    /// FASTQ is read as a stream, which handles wrapped records and
+    /// masks low quality bases.
+    Fastq(stream::SequenceStream<Box<dyn BufRead + Send>>),
+}
+
+impl SequenceReader {
+    /// The records, all as FASTA records. Any FASTQ bases below the
+    /// minimum quality are masked to `N`. The iterator is `Send`, so
+    /// you can still use `.records().par_bridge()`.
+    pub fn records(self) -> Box<dyn Iterator<Item = std::io::Result<fasta::Record>> + Send> {
+        match self.inner {
+            SequenceReaderInner::Fasta(reader) => Box::new(reader.records()),
+            SequenceReaderInner::Fastq(mut stream) => {
+                let mut done = false;
+                Box::new(std::iter::from_fn(move || {
+                    if done {
+                        return None;
+                    }
+                    let record = (|| -> Result<Option<fasta::Record>> {
+                        let Some(id) = stream.next_record()? else {
+                            return Ok(None);
+                        };
+                        let mut seq = Vec::new();
+                        stream.read(&mut seq, usize::MAX)?;
+                        Ok(Some(fasta::Record::with_attrs(&id, None, &seq)))
+                    })();
+                    match record {
+                        Ok(Some(record)) => Some(Ok(record)),
+                        Ok(None) => {
+                            done = true;
+                            None
+                        }
+                        Err(e) => {
+                            done = true;
+                            Some(Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                e.to_string(),
+                            )))
+                        }
+                    }
+                }))
+            }
+        }
+    }
+}
+
+/// Open a FASTA, FASTQ, SAM, BAM or CRAM file that may be plain or gzipped.
+pub fn open_sequence_reader<P: AsRef<Path>>(
+    path: P,
+    options: &InputOptions,
+) -> Result<SequenceReader> {
+    let (reader, format) = open_input(path, options)?;
+    let inner = match format {
+        SequenceFormat::Fasta | SequenceFormat::Alignment | SequenceFormat::Regions => {
+            SequenceReaderInner::Fasta(fasta::Reader::from_bufread(reader))
+        }
+        SequenceFormat::Fastq => SequenceReaderInner::Fastq(
+            stream::SequenceStream::new(reader).with_min_quality(options.min_quality),
+        ),
     };
+    Ok(SequenceReader { inner })
+}
 
-    Ok(fasta::Reader::from_bufread(boxed_reader))
+/// Open a FASTA, FASTQ, SAM, BAM or CRAM file that may be plain or gzipped,
+/// to be read a block of sequence at a time. Regions of a FASTA file
+/// keep their coordinates along the whole sequence.
+pub fn open_sequence_stream<P: AsRef<Path>>(
+    path: P,
+    options: &InputOptions,
+) -> Result<stream::SequenceStream<Box<dyn BufRead + Send>>> {
+    let (reader, format) = open_input(path, options)?;
+    Ok(stream::SequenceStream::new(reader)
+        .with_min_quality(options.min_quality)
+        .with_regions(format == SequenceFormat::Regions))
 }
 
-/// Three possible subcommands.
+/// The subcommands which can write a log.
 pub enum SubCommand {
     Find,
     Explore,
     Search,
+    Arrays,
+    T2T,
+    Its,
+    TelomereLength,
+    TelomereContent,
 }
 
 /// A date format.
 const DATE_FORMAT_STR: &str = "%Y-%m-%d: %H:%M:%S";
 
-// this is not the optimal way to do this... but oh well.
-// add optional log file directory
+/// Format the optional approximate matching distance for the logs.
+fn format_distance(distance: Option<utils::Distance>) -> String {
+    match distance {
+        Some(d) => d.to_string(),
+        None => "None".into(),
+    }
+}
+
+/// Format the minimum base quality for FASTQ input for a log.
+fn format_min_quality(matches: &clap::ArgMatches) -> String {
+    match matches.get_one::<u8>("min_quality") {
+        Some(q) => q.to_string(),
+        None => "None".into(),
+    }
+}
+
+/// Format the reference for CRAM input for a log.
+fn format_reference(matches: &clap::ArgMatches) -> String {
+    match matches.get_one::<PathBuf>("reference") {
+        Some(reference) => reference.display().to_string(),
+        None => "None".into(),
+    }
+}
+
+/// Format the regions the input was restricted to for a log.
+fn format_regions(matches: &clap::ArgMatches) -> String {
+    let mut regions: Vec<String> = matches
+        .get_many::<String>("region")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    if let Some(bed) = matches.get_one::<PathBuf>("regions") {
+        regions.push(bed.display().to_string());
+    }
+    if regions.is_empty() {
+        "None".into()
+    } else {
+        regions.join(", ")
+    }
+}
+
+/// Format how much of each end of the sequences was scanned for a log.
+fn format_ends(matches: &clap::ArgMatches) -> String {
+    match utils::Ends::from_matches(matches) {
+        Ok(Some(ends)) => format!("{ends} at each end"),
+        _ => "None".into(),
+    }
+}
+
+/// The name given to the log with `--log-file`.
+fn log_file_arg(matches: &clap::ArgMatches) -> Option<&PathBuf> {
+    matches.get_one::<PathBuf>("log_file")
+}
+
+/// The file the log of a subcommand is written to: `--log-file` if it
+/// was given, otherwise `<dir>/<output>.log`. Output written to stdout
+/// has no name for the log to follow, and `tidk explore`, which only
+/// prints, logs to `tidk-explore.log`.
+pub fn log_file_name(matches: &clap::ArgMatches) -> Result<String> {
+    if let Some(log_file) = log_file_arg(matches) {
+        return Ok(log_file.display().to_string());
+    }
+    let Ok(Some(output)) = matches.try_get_one::<PathBuf>("output") else {
+        return Ok("tidk-explore.log".into());
+    };
+    if output::is_stdout(output) {
+        bail!("The output is written to stdout, so give the log a name with --log-file.");
+    }
+    let outdir = matches
+        .get_one::<PathBuf>("dir")
+        .expect("defaulted by clap");
+    Ok(format!(
+        "{}/{}{}",
+        outdir.display(),
+        output.display(),
+        ".log"
+    ))
+}
+
+/// The name of an output file for a log: `<dir>/<output><suffix>`, or
+/// `STDOUT`.
+fn format_output_file(matches: &clap::ArgMatches, suffix: &str) -> String {
+    let output = matches
+        .get_one::<PathBuf>("output")
+        .expect("errored by clap");
+    if output::is_stdout(output) {
+        return "STDOUT".into();
+    }
+    let outdir = matches
+        .get_one::<PathBuf>("dir")
+        .expect("defaulted by clap");
+    format!("{}/{}{}", outdir.display(), output.display(), suffix)
+}
+
+/// Write the log of a run of `tidk <subcommand>`, with the output file it
+/// describes and the parameters it was run with, to the file given by
+/// [`log_file_name`].
+fn write_log(
+    matches: &clap::ArgMatches,
+    subcommand: &str,
+    output_file: &str,
+    parameters: &[(&str, String)],
+) -> Result<()> {
+    let mut log_string = format!(
+        "tidk version: {}\nLog information for output file: {}\nDate: {}\n`tidk {}` was run with the following parameters:",
+        crate_version!(),
+        output_file,
+        Local::now().format(DATE_FORMAT_STR),
+        subcommand
+    );
+    for (name, value) in parameters {
+        log_string += &format!("\n    {name}: {value}");
+    }
+
+    // create file
+    let log_file_name = log_file_name(matches)?;
+    let log_file = std::fs::File::create(&log_file_name)?;
+    let mut log_file = std::io::LineWriter::new(log_file);
+
+    writeln!(log_file, "{log_string}")?;
+
+    eprintln!("[+]\tLog file written to: {log_file_name}");
+
+    Ok(())
+}
+
 impl SubCommand {
     /// Make a log dependent on the subcommand that was run.
     pub fn log(&self, matches: &clap::ArgMatches) -> Result<()> {
-        // only if log CLI arg is present
-        if matches.get_flag("log") {
-            match self {
-                SubCommand::Find => {
-                    let output = matches
-                        .get_one::<PathBuf>("output")
-                        .expect("errored by clap");
-                    let outdir = matches.get_one::<PathBuf>("dir").expect("errored by clap");
-                    let input_fasta = matches
-                        .get_one::<PathBuf>("fasta")
-                        .expect("errored by clap");
-                    let clade = matches.get_one::<String>("clade").expect("errored by clap");
-                    let clade_info = clades::return_telomere_sequence(clade.clone());
-                    let window_size = *matches.get_one::<usize>("window").expect("errored by clap");
-
-                    let file_name = format!(
-                        "{}/{}{}",
-                        outdir.display(),
-                        output.display(),
-                        "_telomeric_repeat_windows.csv"
-                    );
-
-                    let log_string = format!(
-                        r#"tidk version: {}
-Log information for output file: {}
-Date: {}
-`tidk find` was run with the following parameters:
-    Input fasta: {}
-    Window size: {}
-    Clade chosen: {}
-    Telomeric repeats queried: {}"#,
-                        crate_version!(),
-                        file_name,
-                        Local::now().format(DATE_FORMAT_STR),
-                        input_fasta.display(),
-                        window_size,
-                        clade,
-                        clade_info?.seq.get_inner().join(", ")
-                    );
-
-                    // create file
-                    let log_file_name =
-                        format!("{}/{}{}", outdir.display(), output.display(), ".log");
-                    let log_file = std::fs::File::create(&log_file_name)?;
-                    let mut log_file = std::io::LineWriter::new(log_file);
-
-                    writeln!(log_file, "{log_string}")?;
-
-                    eprintln!("[+]\tLog file written to: {log_file_name}");
-
-                    Ok(())
-                }
-                SubCommand::Explore => {
-                    let input_fasta = matches
-                        .get_one::<PathBuf>("fasta")
-                        .expect("errored by clap");
-                    let length = matches.get_one::<usize>("length");
-
-                    let minimum = matches.get_one::<usize>("minimum");
-                    let maximum = matches.get_one::<usize>("maximum");
-
-                    let threshold = matches.get_one::<i32>("threshold");
-
-                    let dist_from_chromosome_end = matches.get_one::<f64>("distance");
-
-                    let log_string = format!(
-                        r#"tidk version: {}
-Log information for output files: printed to STDOUT
-Date: {}
-`tidk explore` was run with the following parameters:
-    Input fasta: {}
-    Explored telomeric repeat units of length: {}
-    Or from length: {}
-    To length: {}
-    Threshold: {}
-    Searching at {}% distance from chromosome end"#,
-                        crate_version!(),
-                        Local::now().format(DATE_FORMAT_STR),
-                        input_fasta.display(),
-                        {
-                            if let Some(l) = length {
-                                l.to_string()
-                            } else {
-                                "None".into()
-                            }
-                        },
-                        {
-                            if let Some(min) = minimum {
-                                min.to_string()
-                            } else {
-                                "None".into()
-                            }
-                        },
-                        {
-                            if let Some(max) = maximum {
-                                max.to_string()
-                            } else {
-                                "None".into()
-                            }
+        // only if log CLI arg (or a log file name) is present
+        if !matches.get_flag("log") && log_file_arg(matches).is_none() {
+            return Ok(());
+        }
+        let threads = || {
+            matches
+                .get_one::<usize>("threads")
+                .expect("defaulted by clap")
+                .to_string()
+        };
+        let input_fasta = || {
+            matches
+                .get_one::<PathBuf>("fasta")
+                .expect("errored by clap")
+                .display()
+                .to_string()
+        };
+        let extension = || {
+            matches
+                .get_one::<String>("extension")
+                .expect("errored by clap")
+                .clone()
+        };
+        // the options of the subcommands which read alignments
+        let input_parameters = || {
+            [
+                ("Minimum base quality", format_min_quality(matches)),
+                (
+                    "Supplementary records",
+                    matches.get_flag("supplementary").to_string(),
+                ),
+                ("Reference", format_reference(matches)),
+                ("Regions", format_regions(matches)),
+            ]
+        };
+        // the options of the subcommands which call arrays
+        let array_parameters = || {
+            let options = arrays::ArrayOptions::from_matches(matches);
+            [
+                ("Maximum gap within an array", options.max_gap.to_string()),
+                ("Minimum array length", options.min_length.to_string()),
+                ("Approximate matching", format_distance(options.distance)),
+            ]
+        };
+
+        match self {
+            SubCommand::Find => {
+                let clade = matches.get_one::<String>("clade").expect("errored by clap");
+                let clade_info = clades::return_telomere_sequence(clade.clone())?;
+                let options = utils::WindowOptions::from_matches(matches)?;
+
+                let mut parameters = vec![
+                    ("Input fasta", input_fasta()),
+                    ("Window size", options.window_size.to_string()),
+                    ("Window step", options.step.to_string()),
+                    ("Assignment to windows", options.assignment.to_string()),
+                    ("Soft-masking", options.masking.to_string()),
+                    ("Threads", threads()),
+                    ("Clade chosen", clade.clone()),
+                    (
+                        "Telomeric repeats queried",
+                        clade_info.seq.get_inner().join(", "),
+                    ),
+                    ("Approximate matching", format_distance(options.distance)),
+                ];
+                parameters.extend(input_parameters());
+                parameters.push(("Ends", format_ends(matches)));
+
+                write_log(
+                    matches,
+                    "find",
+                    &format_output_file(matches, "_telomeric_repeat_windows.csv"),
+                    &parameters,
+                )
+            }
+            SubCommand::Explore => {
+                let format_length = |id: &str| {
+                    matches
+                        .get_one::<usize>(id)
+                        .map_or("None".into(), |e| e.to_string())
+                };
+
+                let mut parameters = vec![
+                    ("Input fasta", input_fasta()),
+                    (
+                        "Explored telomeric repeat units of length",
+                        format_length("length"),
+                    ),
+                    ("Or from length", format_length("minimum")),
+                    ("To length", format_length("maximum")),
+                    (
+                        "Threshold",
+                        matches
+                            .get_one::<i32>("threshold")
+                            .expect("defaulted by clap")
+                            .to_string(),
+                    ),
+                    (
+                        "Percentage distance from chromosome end",
+                        (matches
+                            .get_one::<f64>("distance")
+                            .expect("defaulted by clap")
+                            * 100.0)
+                            .to_string(),
+                    ),
+                    ("Method", explore::Method::from_matches(matches).to_string()),
+                ];
+                parameters.extend(input_parameters());
+
+                write_log(matches, "explore", "STDOUT", &parameters)
+            }
+            SubCommand::Search => {
+                let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+                let options = utils::WindowOptions::from_matches(matches)?;
+
+                let mut parameters = vec![
+                    ("Input fasta", input_fasta()),
+                    (
+                        "Telomeric repeat search string(s)",
+                        telomeric_repeats.join(", "),
+                    ),
+                    ("Window size", options.window_size.to_string()),
+                    ("Window step", options.step.to_string()),
+                    ("Assignment to windows", options.assignment.to_string()),
+                    ("Soft-masking", options.masking.to_string()),
+                    ("Threads", threads()),
+                    ("Approximate matching", format_distance(options.distance)),
+                ];
+                parameters.extend(input_parameters());
+                parameters.push(("Ends", format_ends(matches)));
+
+                write_log(
+                    matches,
+                    "search",
+                    &format_output_file(
+                        matches,
+                        &format!("_telomeric_repeat_windows.{}", extension()),
+                    ),
+                    &parameters,
+                )
+            }
+            SubCommand::Arrays => {
+                let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+
+                let mut parameters = vec![
+                    ("Input fasta", input_fasta()),
+                    (
+                        "Telomeric repeat search string(s)",
+                        telomeric_repeats.join(", "),
+                    ),
+                ];
+                parameters.extend(array_parameters());
+
+                write_log(
+                    matches,
+                    "arrays",
+                    &format_output_file(matches, &format!("_telomeric_arrays.{}", extension())),
+                    &parameters,
+                )
+            }
+            SubCommand::T2T => {
+                let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+                let options = t2t::T2TOptions::from_matches(matches)?;
+
+                let mut parameters = vec![
+                    ("Input fasta", input_fasta()),
+                    (
+                        "Telomeric repeat search string(s)",
+                        telomeric_repeats.join(", "),
+                    ),
+                ];
+                parameters.extend(array_parameters());
+                parameters.extend([
+                    ("End length", options.end_length.to_string()),
+                    (
+                        "Minimum telomeric repeat density",
+                        options.min_density.to_string(),
+                    ),
+                    (
+                        "Minimum assembly gap length",
+                        options.min_gap_length.to_string(),
+                    ),
+                ]);
+
+                write_log(
+                    matches,
+                    "t2t",
+                    &format_output_file(matches, "_t2t.tsv"),
+                    &parameters,
+                )
+            }
+            SubCommand::Its => {
+                let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+                let options = its::ItsOptions::from_matches(matches);
+
+                let mut parameters = vec![
+                    ("Input fasta", input_fasta()),
+                    (
+                        "Telomeric repeat search string(s)",
+                        telomeric_repeats.join(", "),
+                    ),
+                ];
+                parameters.extend(array_parameters());
+                parameters.extend([
+                    (
+                        "Minimum distance from the ends",
+                        options.end_distance.to_string(),
+                    ),
+                    ("Long ITS length", options.long_length.to_string()),
+                    (
+                        "Maximum distance within a pair",
+                        options.pair_distance.to_string(),
+                    ),
+                ]);
+
+                write_log(
+                    matches,
+                    "its",
+                    &format_output_file(matches, &format!("_its.{}", extension())),
+                    &parameters,
+                )
+            }
+            SubCommand::TelomereLength => {
+                let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+                let options = telomere_length::TelomereLengthOptions::from_matches(matches)?;
+
+                let mut parameters = vec![
+                    ("Input reads", input_fasta()),
+                    (
+                        "Telomeric repeat search string(s)",
+                        telomeric_repeats.join(", "),
+                    ),
+                ];
+                parameters.extend(array_parameters());
+                parameters.extend([
+                    (
+                        "Maximum distance from a read end",
+                        options.end_distance.to_string(),
+                    ),
+                    ("Histogram bin size", options.bin_size.to_string()),
+                    ("Threads", threads()),
+                ]);
+                parameters.extend(input_parameters());
+
+                write_log(
+                    matches,
+                    "telomere-length",
+                    &format_output_file(matches, "_telomere_lengths.tsv"),
+                    &parameters,
+                )
+            }
+            SubCommand::TelomereContent => {
+                let samples = telomere_content::Sample::from_matches(matches)?;
+                let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+                let gc_range = telomere_content::gc_range(&telomeric_repeats[0]);
+
+                let parameters = [
+                    (
+                        "Samples",
+                        samples
+                            .iter()
+                            .map(|e| {
+                                format!(
+                                    "{} ({})",
+                                    e.name,
+                                    e.reads
+                                        .iter()
+                                        .map(|r| r.display().to_string())
+                                        .collect::<Vec<_>>()
+                                        .join(", ")
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    ),
+                    ("Telomeric repeat(s)", telomeric_repeats.join(", ")),
+                    (
+                        "Minimum tandem copies",
+                        matches
+                            .get_one::<usize>("copies")
+                            .expect("defaulted by clap")
+                            .to_string(),
+                    ),
+                    (
+                        "GC-matched background",
+                        format!("{:.2}-{:.2}", gc_range.0, gc_range.1),
+                    ),
+                    (
+                        "GC-matched genome size",
+                        match matches.get_one::<u64>("gc_genome_size") {
+                            Some(s) => s.to_string(),
+                            None => format!(
+                                "from {}",
+                                matches
+                                    .get_one::<PathBuf>("gc_reference")
+                                    .expect("required by clap unless the size is given")
+                                    .display()
+                            ),
                         },
-                        // safely unwrap
-                        threshold.unwrap(),
-                        // safely unwrap
-                        *dist_from_chromosome_end.unwrap() * 100.0,
-                    );
-
-                    // create file
-                    let log_file_name = "tidk-explore.log".to_string();
-                    let log_file = std::fs::File::create(&log_file_name)?;
-                    let mut log_file = std::io::LineWriter::new(log_file);
-
-                    writeln!(log_file, "{log_string}")?;
-
-                    eprintln!("[+]\tLog file written to: {log_file_name}");
-
-                    Ok(())
-                }
-                SubCommand::Search => {
-                    let input_fasta = matches
-                        .get_one::<PathBuf>("fasta")
-                        .expect("errored by clap");
-                    let telomeric_repeat = matches
-                        .get_one::<String>("string")
-                        .expect("errored by clap");
-                    let extension = matches
-                        .get_one::<String>("extension")
-                        .expect("errored by clap");
-
-                    let window_size = matches.get_one::<usize>("window").expect("errored by clap");
-                    let outdir = matches.get_one::<PathBuf>("dir").expect("errored by clap");
-                    let output = matches
-                        .get_one::<PathBuf>("output")
-                        .expect("errored by clap");
-
-                    // create file
-                    let file_name = format!(
-                        "{}/{}{}{}",
-                        outdir.display(),
-                        output.display(),
-                        "_telomeric_repeat_windows.",
-                        extension
-                    );
-
-                    let log_string = format!(
-                        r#"tidk version: {}
-Log information for output file: {}
-Date: {}
-`tidk search` was run with the following parameters:
-    Input fasta: {}
-    Telomeric repeat search string: {}
-    Window size: {}
-                    "#,
-                        crate_version!(),
-                        file_name,
-                        Local::now().format(DATE_FORMAT_STR),
-                        input_fasta.display(),
-                        telomeric_repeat,
-                        window_size
-                    );
-
-                    // create file
-                    let log_file_name =
-                        format!("{}/{}{}", outdir.display(), output.display(), ".log");
-                    let log_file = std::fs::File::create(&log_file_name)?;
-                    let mut log_file = std::io::LineWriter::new(log_file);
-
-                    writeln!(log_file, "{log_string}")?;
-
-                    eprintln!("[+]\tLog file written to: {log_file_name}");
-
-                    Ok(())
-                }
+                    ),
+                    (
+                        "Chromosome ends",
+                        matches
+                            .get_one::<u64>("chromosome_ends")
+                            .expect("errored by clap")
+                            .to_string(),
+                    ),
+                    ("Threads", threads()),
+                ];
+
+                write_log(
+                    matches,
+                    "telomere-content",
+                    &format_output_file(matches, "_telomere_content.tsv"),
+                    &parameters,
+                )
             }
-        } else {
-            Ok(())
         }
     }
 }
@@ -1,9 +1,18 @@
 use anyhow::Result;
 use clap::{arg, builder::ArgPredicate, crate_version, value_parser, Arg, Command};
 use std::path::PathBuf;
-use tidk::{build, clades::get_clades, explore, finder, plot, search, SubCommand};
+use tidk::{
+    arrays, build, clades::get_clades, explore, finder, its, plot, search, t2t, telomere_content,
+    telomere_length, SubCommand,
+};
 
 fn main() -> Result<()> {
+    // the clades in the database, for `find` and `telomere-content`
+    let clades = get_clades().unwrap_or_else(|_| -> Vec<_> {
+        eprintln!("Warning! No clades found in the database. Run 'tidk build' to fetch the latest data.\n");
+        vec![]
+    });
+
     // command line options
     let matches = Command::new("tidk")
         .version(crate_version!())
@@ -14,6 +23,10 @@ fn main() -> Result<()> {
         .subcommand(
             Command::new("build")
                 .about("Build the reference database of telomeric repeat sequences. This is required for the 'find' subcommand.")
+                .arg(
+                    arg!(--provenance <PROVENANCE> "Write a JSON record of the run (parameters, inputs with checksums, motifs and counts) to this file")
+                        .value_parser(value_parser!(PathBuf))
+                )
         )
         .subcommand(
             Command::new("find")
@@ -22,7 +35,7 @@ fn main() -> Result<()> {
                     Arg::new("fasta")
                         .value_name("FASTA")
                         .value_parser(value_parser!(PathBuf))
-                        .help("The input fasta file")
+                        .help("The input FASTA, FASTQ, SAM, BAM or CRAM file, which may be gzipped, or - for stdin")
                         .required_unless_present("print")
                 )
                 .arg(
@@ -31,23 +44,83 @@ fn main() -> Result<()> {
                         .value_parser(value_parser!(usize))
                         .default_value("10000")
                 )
+                .arg(
+                    arg!(--step [STEP] "Distance between the starts of consecutive windows. Defaults to the window size (non-overlapping windows)")
+                        .value_parser(value_parser!(usize))
+                )
+                .arg(
+                    arg!(-t --threads [THREADS] "Number of threads to scan sequences with. The output is in the same order whatever the number of threads")
+                        .value_parser(value_parser!(usize))
+                        .default_value("1")
+                )
+                .arg(
+                    arg!(--assign [ASSIGN] "How repeats are assigned to windows. `window` scans each window on its own, missing repeats spanning window boundaries. `start` and `proportional` scan each sequence once, and assign repeats to windows by their start, or in proportion to their overlap")
+                        .value_parser(["window", "start", "proportional"])
+                        .default_value("window")
+                )
+                .arg(
+                    arg!(--mask [MASK] "Which bases to count repeats in, given soft-masking (lowercase bases): all of them, only the unmasked bases, or only the masked bases")
+                        .value_parser(["both", "unmasked", "masked"])
+                        .default_value("both")
+                )
+                .arg(
+                    arg!(--mismatches [MISMATCHES] "Also count occurrences with up to this many mismatches (Hamming distance)")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with("edits")
+                )
+                .arg(
+                    arg!(--edits [EDITS] "Also count occurrences with up to this many edits (Levenshtein distance)")
+                        .value_parser(value_parser!(usize))
+                )
+                .arg(
+                    arg!(--"min-quality" [MIN_QUALITY] "For FASTQ, SAM, BAM or CRAM input, mask bases with a Phred quality below this before counting")
+                        .id("min_quality")
+                        .value_parser(value_parser!(u8))
+                )
+                .arg(
+                    arg!(--supplementary "For SAM, BAM or CRAM input, use supplementary as well as primary records")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    arg!(--reference [REFERENCE] "For CRAM input, the FASTA the reads were aligned to, which is needed to decode them")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--region [REGION] "Only scan this region of FASTA input, or for SAM, BAM or CRAM input, only use reads aligned to it (chr, chr:start or chr:start-end). Can be given more than once")
+                        .action(clap::ArgAction::Append)
+                )
+                .arg(
+                    arg!(--regions [REGIONS] "As --region, for each region in a BED file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--ends [ENDS] "Only scan this many bp at each end of each FASTA sequence")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with_all(["distance", "region", "regions"])
+                )
+                .arg(
+                    arg!(--distance [DISTANCE] "Only scan this proportion of each FASTA sequence at either end. Must range from 0-0.5")
+                        .value_parser(value_parser!(f64))
+                        .conflicts_with_all(["region", "regions"])
+                )
                 .arg(
                     arg!(-c --clade <CLADE> "The clade of organism to identify telomeres in")
                         .required_unless_present("print")
-                        .value_parser(get_clades().unwrap_or_else(|_| -> Vec<_> {
-                            eprintln!("Warning! No clades found in the database. Run 'tidk build' to fetch the latest data.\n");
-                            vec![]
-                        }))
+                        .value_parser(clades.clone())
                 )
                 .arg(
-                    arg!(-o --output <OUTPUT> "Output filename for the TSVs (without extension)")
+                    arg!(-o --output <OUTPUT> "Output filename for the TSVs (without extension), or - for stdout")
                         .value_parser(value_parser!(PathBuf))
                         .required_unless_present("print")
                 )
                 .arg(
-                    arg!(-d --dir <DIR> "Output directory to write files to")
-                        .required_unless_present("print")
+                    arg!(-d --dir [DIR] "Output directory to write files to")
                         .value_parser(value_parser!(PathBuf))
+                        .default_value(".")
+                )
+                .arg(
+                    arg!(--compress [COMPRESS] "Compress the output, sorted by coordinate, with gzip or with BGZF (which can be indexed with tabix)")
+                        .value_parser(["gzip", "bgzf"])
                 )
                 .arg(
                     arg!(-p --print "Print a table of clades, along with their telomeric sequences")
@@ -57,6 +130,15 @@ fn main() -> Result<()> {
                     arg!(--log "Output a log file")
                         .action(clap::ArgAction::SetTrue)
                 )
+                .arg(
+                    arg!(--"log-file" <LOG_FILE> "Write a log to this file, e.g. when the output goes to stdout")
+                        .id("log_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--provenance <PROVENANCE> "Write a JSON record of the run (parameters, inputs with checksums, motifs and counts) to this file")
+                        .value_parser(value_parser!(PathBuf))
+                )
         )
         .subcommand(
             Command::new("explore")
@@ -66,7 +148,7 @@ fn main() -> Result<()> {
                         .value_name("FASTA")
                         .value_parser(value_parser!(PathBuf))
                         .required(true)
-                        .help("The input fasta file")
+                        .help("The input FASTA, FASTQ, SAM, BAM or CRAM file, which may be gzipped, or - for stdin")
                 )
                 .arg(
                     arg!(-l --length [LENGTH] "Length of substring")
@@ -96,6 +178,32 @@ fn main() -> Result<()> {
                         .value_parser(value_parser!(f64))
                         .default_value("0.01")
                 )
+                .arg(
+                    arg!(--method [METHOD] "How tandem repeats are found. `chunk` compares adjacent chunks of each length, so only finds arrays in phase with the chunks. `period` compares each base with the base one length along, so finds arrays at any offset, and across small indels")
+                        .default_value("chunk")
+                        .value_parser(["chunk", "period"])
+                )
+                .arg(
+                    arg!(--"min-quality" [MIN_QUALITY] "For FASTQ, SAM, BAM or CRAM input, mask bases with a Phred quality below this before exploring")
+                        .id("min_quality")
+                        .value_parser(value_parser!(u8))
+                )
+                .arg(
+                    arg!(--supplementary "For SAM, BAM or CRAM input, use supplementary as well as primary records")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    arg!(--reference [REFERENCE] "For CRAM input, the FASTA the reads were aligned to, which is needed to decode them")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--region [REGION] "Only scan this region of FASTA input, or for SAM, BAM or CRAM input, only use reads aligned to it (chr, chr:start or chr:start-end). Can be given more than once")
+                        .action(clap::ArgAction::Append)
+                )
+                .arg(
+                    arg!(--regions [REGIONS] "As --region, for each region in a BED file")
+                        .value_parser(value_parser!(PathBuf))
+                )
                 .arg(
                     arg!(-v --verbose "Print verbose output.")
                         .action(clap::ArgAction::SetTrue)
@@ -104,10 +212,136 @@ fn main() -> Result<()> {
                     arg!(--log "Output a log file.")
                         .action(clap::ArgAction::SetTrue)
                 )
+                .arg(
+                    arg!(--"log-file" <LOG_FILE> "Write the log to this file, rather than tidk-explore.log")
+                        .id("log_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--provenance <PROVENANCE> "Write a JSON record of the run (parameters, inputs with checksums, motifs and counts) to this file")
+                        .value_parser(value_parser!(PathBuf))
+                )
         )
         .subcommand(
             Command::new("search")
                 .about("Search the input genome with a specific telomeric repeat search string.")
+                .arg(
+                    Arg::new("fasta")
+                        .value_name("FASTA")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                        .help("The input FASTA, FASTQ, SAM, BAM or CRAM file, which may be gzipped, or - for stdin")
+                )
+                .arg(
+                    arg!(-s --string <STRING> "The DNA string to query the genome with. IUPAC ambiguity codes are allowed. Can be given more than once")
+                        .action(clap::ArgAction::Append)
+                        .required_unless_present("motif_file")
+                )
+                .arg(
+                    arg!(--"motif-file" <MOTIF_FILE> "A file of DNA strings to query the genome with, one per line")
+                        .id("motif_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(-w --window [WINDOW] "Window size to calculate telomeric repeat counts in")
+                        .value_parser(value_parser!(usize))
+                        .default_value("10000")
+                )
+                .arg(
+                    arg!(--step [STEP] "Distance between the starts of consecutive windows. Defaults to the window size (non-overlapping windows)")
+                        .value_parser(value_parser!(usize))
+                )
+                .arg(
+                    arg!(-t --threads [THREADS] "Number of threads to scan sequences with. The output is in the same order whatever the number of threads")
+                        .value_parser(value_parser!(usize))
+                        .default_value("1")
+                )
+                .arg(
+                    arg!(--assign [ASSIGN] "How repeats are assigned to windows. `window` scans each window on its own, missing repeats spanning window boundaries. `start` and `proportional` scan each sequence once, and assign repeats to windows by their start, or in proportion to their overlap")
+                        .value_parser(["window", "start", "proportional"])
+                        .default_value("window")
+                )
+                .arg(
+                    arg!(--mask [MASK] "Which bases to count repeats in, given soft-masking (lowercase bases): all of them, only the unmasked bases, or only the masked bases")
+                        .value_parser(["both", "unmasked", "masked"])
+                        .default_value("both")
+                )
+                .arg(
+                    arg!(--mismatches [MISMATCHES] "Also count occurrences with up to this many mismatches (Hamming distance)")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with("edits")
+                )
+                .arg(
+                    arg!(--edits [EDITS] "Also count occurrences with up to this many edits (Levenshtein distance)")
+                        .value_parser(value_parser!(usize))
+                )
+                .arg(
+                    arg!(--"min-quality" [MIN_QUALITY] "For FASTQ, SAM, BAM or CRAM input, mask bases with a Phred quality below this before counting")
+                        .id("min_quality")
+                        .value_parser(value_parser!(u8))
+                )
+                .arg(
+                    arg!(--supplementary "For SAM, BAM or CRAM input, use supplementary as well as primary records")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    arg!(--reference [REFERENCE] "For CRAM input, the FASTA the reads were aligned to, which is needed to decode them")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--region [REGION] "Only scan this region of FASTA input, or for SAM, BAM or CRAM input, only use reads aligned to it (chr, chr:start or chr:start-end). Can be given more than once")
+                        .action(clap::ArgAction::Append)
+                )
+                .arg(
+                    arg!(--regions [REGIONS] "As --region, for each region in a BED file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--ends [ENDS] "Only scan this many bp at each end of each FASTA sequence")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with_all(["distance", "region", "regions"])
+                )
+                .arg(
+                    arg!(--distance [DISTANCE] "Only scan this proportion of each FASTA sequence at either end. Must range from 0-0.5")
+                        .value_parser(value_parser!(f64))
+                        .conflicts_with_all(["region", "regions"])
+                )
+                .arg(
+                    arg!(-o --output <OUTPUT> "Output filename for the TSVs (without extension), or - for stdout")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                )
+                .arg(
+                    arg!(-d --dir [DIR] "Output directory to write files to")
+                        .value_parser(value_parser!(PathBuf))
+                        .default_value(".")
+                )
+                .arg(
+                    arg!(-e --extension [EXTENSION] "The extension, defining the output type of the file. A .gz extension compresses it with BGZF")
+                        .default_value("tsv")
+                        .value_parser(["tsv", "bedgraph", "tsv.gz", "bedgraph.gz"])
+                )
+                .arg(
+                    arg!(--compress [COMPRESS] "Compress the output, sorted by coordinate, with gzip or with BGZF (which can be indexed with tabix)")
+                        .value_parser(["gzip", "bgzf"])
+                )
+                .arg(
+                    arg!(--log "Output a log file")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    arg!(--"log-file" <LOG_FILE> "Write a log to this file, e.g. when the output goes to stdout")
+                        .id("log_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--provenance <PROVENANCE> "Write a JSON record of the run (parameters, inputs with checksums, motifs and counts) to this file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+        )
+        .subcommand(
+            Command::new("arrays")
+                .about("Call contiguous arrays of telomeric repeats, and report them as intervals.")
                 .arg(
                     Arg::new("fasta")
                         .value_name("FASTA")
@@ -116,14 +350,122 @@ fn main() -> Result<()> {
                         .help("The input fasta file")
                 )
                 .arg(
-                    arg!(-s --string <STRING> "The DNA string to query the genome with")
+                    arg!(-s --string <STRING> "The DNA string to query the genome with. IUPAC ambiguity codes are allowed. Can be given more than once")
+                        .action(clap::ArgAction::Append)
+                        .required_unless_present("motif_file")
+                )
+                .arg(
+                    arg!(--"motif-file" <MOTIF_FILE> "A file of DNA strings to query the genome with, one per line")
+                        .id("motif_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--gap [GAP] "The largest gap (bp) between two repeat units of the same array")
+                        .value_parser(value_parser!(usize))
+                        .default_value("50")
+                )
+                .arg(
+                    arg!(--"min-length" [MIN_LENGTH] "The shortest array (bp) to report")
+                        .id("min_length")
+                        .value_parser(value_parser!(usize))
+                        .default_value("200")
+                )
+                .arg(
+                    arg!(--mismatches [MISMATCHES] "Also use occurrences with up to this many mismatches (Hamming distance)")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with("edits")
+                )
+                .arg(
+                    arg!(--edits [EDITS] "Also use occurrences with up to this many edits (Levenshtein distance)")
+                        .value_parser(value_parser!(usize))
+                )
+                .arg(
+                    arg!(-o --output <OUTPUT> "Output filename for the arrays (without extension)")
+                        .value_parser(value_parser!(PathBuf))
                         .required(true)
                 )
                 .arg(
-                    arg!(-w --window [WINDOW] "Window size to calculate telomeric repeat counts in")
+                    arg!(-d --dir <DIR> "Output directory to write files to")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(-e --extension [EXTENSION] "The extension, defining the output type of the file")
+                        .default_value("bed")
+                        .value_parser(["bed", "gff3", "tsv"])
+                )
+                .arg(
+                    arg!(--log "Output a log file")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    arg!(--"log-file" <LOG_FILE> "Write the log to this file, rather than <DIR>/<OUTPUT>.log")
+                        .id("log_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--provenance <PROVENANCE> "Write a JSON record of the run (parameters, inputs with checksums, motifs and counts) to this file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+        )
+        .subcommand(
+            Command::new("t2t")
+                .about("Call telomeres at both ends of each sequence, and report which sequences are telomere-to-telomere.")
+                .arg(
+                    Arg::new("fasta")
+                        .value_name("FASTA")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                        .help("The input fasta file")
+                )
+                .arg(
+                    arg!(-s --string <STRING> "The DNA string to query the genome with. IUPAC ambiguity codes are allowed. Can be given more than once")
+                        .action(clap::ArgAction::Append)
+                        .required_unless_present("motif_file")
+                )
+                .arg(
+                    arg!(--"motif-file" <MOTIF_FILE> "A file of DNA strings to query the genome with, one per line")
+                        .id("motif_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--"end-length" [END_LENGTH] "How far in from each end of a sequence (bp) to look for a telomere")
+                        .id("end_length")
                         .value_parser(value_parser!(usize))
                         .default_value("10000")
                 )
+                .arg(
+                    arg!(--"min-density" [MIN_DENSITY] "The proportion of the end which must be telomeric repeat, in the expected orientation, to call a telomere")
+                        .id("min_density")
+                        .value_parser(value_parser!(f64))
+                        .default_value("0.05")
+                )
+                .arg(
+                    arg!(--"min-gap" [MIN_GAP] "The shortest run of Ns (bp) to report as an assembly gap")
+                        .id("min_gap")
+                        .value_parser(value_parser!(usize))
+                        .default_value("10")
+                )
+                .arg(
+                    arg!(--gap [GAP] "The largest gap (bp) between two repeat units of the same array")
+                        .value_parser(value_parser!(usize))
+                        .default_value("50")
+                )
+                .arg(
+                    arg!(--"min-length" [MIN_LENGTH] "The shortest array (bp) to count towards a telomere")
+                        .id("min_length")
+                        .value_parser(value_parser!(usize))
+                        .default_value("60")
+                )
+                .arg(
+                    arg!(--mismatches [MISMATCHES] "Also use occurrences with up to this many mismatches (Hamming distance)")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with("edits")
+                )
+                .arg(
+                    arg!(--edits [EDITS] "Also use occurrences with up to this many edits (Levenshtein distance)")
+                        .value_parser(value_parser!(usize))
+                )
                 .arg(
                     arg!(-o --output <OUTPUT> "Output filename for the TSVs (without extension)")
                         .value_parser(value_parser!(PathBuf))
@@ -134,22 +476,301 @@ fn main() -> Result<()> {
                         .required(true)
                         .value_parser(value_parser!(PathBuf))
                 )
+                .arg(
+                    arg!(--log "Output a log file")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    arg!(--"log-file" <LOG_FILE> "Write the log to this file, rather than <DIR>/<OUTPUT>.log")
+                        .id("log_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--provenance <PROVENANCE> "Write a JSON record of the run (parameters, inputs with checksums, motifs and counts) to this file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+        )
+        .subcommand(
+            Command::new("its")
+                .about("Catalogue interstitial telomeric sequences, i.e. telomeric arrays away from the ends of sequences.")
+                .arg(
+                    Arg::new("fasta")
+                        .value_name("FASTA")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                        .help("The input fasta file")
+                )
+                .arg(
+                    arg!(-s --string <STRING> "The DNA string to query the genome with. IUPAC ambiguity codes are allowed. Can be given more than once")
+                        .action(clap::ArgAction::Append)
+                        .required_unless_present("motif_file")
+                )
+                .arg(
+                    arg!(--"motif-file" <MOTIF_FILE> "A file of DNA strings to query the genome with, one per line")
+                        .id("motif_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--"end-distance" [END_DISTANCE] "Arrays closer than this (bp) to either end of a sequence are not reported")
+                        .id("end_distance")
+                        .value_parser(value_parser!(usize))
+                        .default_value("10000")
+                )
+                .arg(
+                    arg!(--"long-length" [LONG_LENGTH] "The length (bp) from which an interstitial telomeric sequence is long")
+                        .id("long_length")
+                        .value_parser(value_parser!(usize))
+                        .default_value("500")
+                )
+                .arg(
+                    arg!(--"pair-distance" [PAIR_DISTANCE] "The largest gap (bp) between two arrays of opposite orientation for them to be called a head-to-head or tail-to-tail pair")
+                        .id("pair_distance")
+                        .value_parser(value_parser!(usize))
+                        .default_value("1000")
+                )
+                .arg(
+                    arg!(--gap [GAP] "The largest gap (bp) between two repeat units of the same array")
+                        .value_parser(value_parser!(usize))
+                        .default_value("50")
+                )
+                .arg(
+                    arg!(--"min-length" [MIN_LENGTH] "The shortest array (bp) to report")
+                        .id("min_length")
+                        .value_parser(value_parser!(usize))
+                        .default_value("30")
+                )
+                .arg(
+                    arg!(--mismatches [MISMATCHES] "Also use occurrences with up to this many mismatches (Hamming distance)")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with("edits")
+                )
+                .arg(
+                    arg!(--edits [EDITS] "Also use occurrences with up to this many edits (Levenshtein distance)")
+                        .value_parser(value_parser!(usize))
+                )
+                .arg(
+                    arg!(-o --output <OUTPUT> "Output filename for the catalogue (without extension)")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                )
+                .arg(
+                    arg!(-d --dir <DIR> "Output directory to write files to")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                )
                 .arg(
                     arg!(-e --extension [EXTENSION] "The extension, defining the output type of the file")
                         .default_value("tsv")
-                        .value_parser(["tsv", "bedgraph"])
+                        .value_parser(["tsv", "bed"])
+                )
+                .arg(
+                    arg!(--log "Output a log file")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    arg!(--"log-file" <LOG_FILE> "Write the log to this file, rather than <DIR>/<OUTPUT>.log")
+                        .id("log_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--provenance <PROVENANCE> "Write a JSON record of the run (parameters, inputs with checksums, motifs and counts) to this file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+        )
+        .subcommand(
+            Command::new("telomere-length")
+                .about("Estimate telomere length from long reads, from the telomeric array at the end of each read.")
+                .arg(
+                    Arg::new("fasta")
+                        .value_name("READS")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                        .help("The input reads, as FASTA, FASTQ, SAM, BAM or CRAM, which may be gzipped")
+                )
+                .arg(
+                    arg!(-s --string <STRING> "The DNA string to query the reads with. IUPAC ambiguity codes are allowed. Can be given more than once")
+                        .action(clap::ArgAction::Append)
+                        .required_unless_present("motif_file")
+                )
+                .arg(
+                    arg!(--"motif-file" <MOTIF_FILE> "A file of DNA strings to query the reads with, one per line")
+                        .id("motif_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--"end-distance" [END_DISTANCE] "How close (bp) to a read end an array must come to be a telomere")
+                        .id("end_distance")
+                        .value_parser(value_parser!(usize))
+                        .default_value("100")
+                )
+                .arg(
+                    arg!(--"bin-size" [BIN_SIZE] "The width (bp) of the bins of the telomere length histogram")
+                        .id("bin_size")
+                        .value_parser(value_parser!(usize))
+                        .default_value("500")
+                )
+                .arg(
+                    arg!(--gap [GAP] "The largest gap (bp) between two repeat units of the same array")
+                        .value_parser(value_parser!(usize))
+                        .default_value("50")
+                )
+                .arg(
+                    arg!(--"min-length" [MIN_LENGTH] "The shortest array (bp) to count as a telomere")
+                        .id("min_length")
+                        .value_parser(value_parser!(usize))
+                        .default_value("100")
+                )
+                .arg(
+                    arg!(-t --threads [THREADS] "Number of threads to scan reads with. The output is in the same order whatever the number of threads")
+                        .value_parser(value_parser!(usize))
+                        .default_value("1")
+                )
+                .arg(
+                    arg!(--mismatches [MISMATCHES] "Also use occurrences with up to this many mismatches (Hamming distance)")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with("edits")
+                )
+                .arg(
+                    arg!(--edits [EDITS] "Also use occurrences with up to this many edits (Levenshtein distance)")
+                        .value_parser(value_parser!(usize))
+                )
+                .arg(
+                    arg!(--"min-quality" [MIN_QUALITY] "For FASTQ, SAM, BAM or CRAM input, mask bases with a Phred quality below this before calling arrays")
+                        .id("min_quality")
+                        .value_parser(value_parser!(u8))
+                )
+                .arg(
+                    arg!(--supplementary "For SAM, BAM or CRAM input, use supplementary as well as primary records")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    arg!(--reference [REFERENCE] "For CRAM input, the FASTA the reads were aligned to, which is needed to decode them")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--region [REGION] "For aligned SAM, BAM or CRAM input, only use reads aligned to this region (chr, chr:start or chr:start-end). Can be given more than once")
+                        .action(clap::ArgAction::Append)
+                )
+                .arg(
+                    arg!(--regions [REGIONS] "As --region, for each region in a BED file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(-o --output <OUTPUT> "Output filename for the TSVs (without extension)")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                )
+                .arg(
+                    arg!(-d --dir <DIR> "Output directory to write files to")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--log "Output a log file")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    arg!(--"log-file" <LOG_FILE> "Write the log to this file, rather than <DIR>/<OUTPUT>.log")
+                        .id("log_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--provenance <PROVENANCE> "Write a JSON record of the run (parameters, inputs with checksums, motifs and counts) to this file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+        )
+        .subcommand(
+            Command::new("telomere-content")
+                .about("Estimate telomere content (and average length) from short reads, by counting reads made of telomeric repeat, as in TelSeq.")
+                .arg(
+                    Arg::new("reads")
+                        .value_name("READS")
+                        .value_parser(value_parser!(PathBuf))
+                        .num_args(1..=2)
+                        .required_unless_present("samples")
+                        .help("The reads of a sample, as FASTA, FASTQ, SAM or BAM, which may be gzipped. Give two files for paired reads")
+                )
+                .arg(
+                    arg!(--sample [SAMPLE] "The name of the sample. Defaults to the name of the first read file")
+                )
+                .arg(
+                    arg!(--samples [SAMPLES] "A TSV of samples to compare: the name of each sample, followed by one or two read files")
+                        .value_parser(value_parser!(PathBuf))
+                        .conflicts_with_all(["reads", "sample"])
+                )
+                .arg(
+                    arg!(-c --clade [CLADE] "The clade of organism, whose telomeric repeat(s) to count")
+                        .value_parser(clades)
+                        .required_unless_present_any(["string", "motif_file"])
+                        .conflicts_with_all(["string", "motif_file"])
+                )
+                .arg(
+                    arg!(-s --string [STRING] "The DNA string to query the reads with. IUPAC ambiguity codes are allowed. Can be given more than once")
+                        .action(clap::ArgAction::Append)
+                )
+                .arg(
+                    arg!(--"motif-file" [MOTIF_FILE] "A file of DNA strings to query the reads with, one per line")
+                        .id("motif_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(-k --copies [COPIES] "A read is telomeric if it has at least this many tandem copies of the telomeric repeat")
+                        .value_parser(value_parser!(usize))
+                        .default_value("7")
+                )
+                .arg(
+                    arg!(--"chromosome-ends" <CHROMOSOME_ENDS> "The number of chromosome ends (i.e. twice the number of chromosomes)")
+                        .id("chromosome_ends")
+                        .value_parser(value_parser!(u64))
+                        .required(true)
+                )
+                .arg(
+                    arg!(--"gc-genome-size" [GC_GENOME_SIZE] "The size of the genome (bp) with GC content matching the telomeric repeat")
+                        .id("gc_genome_size")
+                        .value_parser(value_parser!(u64))
+                        .required_unless_present("gc_reference")
+                        .conflicts_with("gc_reference")
+                )
+                .arg(
+                    arg!(--"gc-reference" [GC_REFERENCE] "A FASTA of the genome, to find the size of its part with GC content matching the telomeric repeat, in place of --gc-genome-size")
+                        .id("gc_reference")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(-t --threads [THREADS] "Number of threads to scan reads with")
+                        .value_parser(value_parser!(usize))
+                        .default_value("1")
+                )
+                .arg(
+                    arg!(-o --output <OUTPUT> "Output filename for the TSV (without extension)")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                )
+                .arg(
+                    arg!(-d --dir <DIR> "Output directory to write files to")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
                 )
                 .arg(
                     arg!(--log "Output a log file")
                         .action(clap::ArgAction::SetTrue)
                 )
+                .arg(
+                    arg!(--"log-file" <LOG_FILE> "Write the log to this file, rather than <DIR>/<OUTPUT>.log")
+                        .id("log_file")
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    arg!(--provenance <PROVENANCE> "Write a JSON record of the run (parameters, inputs with checksums, motifs and counts) to this file")
+                        .value_parser(value_parser!(PathBuf))
+                )
         )
         .subcommand(
             Command::new("plot")
                 .about("SVG plot of TSV generated from tidk search.")
                 // output file name
                 .arg(
-                    arg!(-t --tsv <TSV> "The input TSV file")
+                    arg!(-t --tsv <TSV> "The input TSV file, which may be gzipped")
                         .value_parser(value_parser!(PathBuf))
                         .required(true)
                 )
@@ -173,11 +794,20 @@ fn main() -> Result<()> {
                         .value_parser(value_parser!(i32))
                         .default_value("12")
                 )
+                .arg(
+                    arg!(--value [VALUE] "What to plot on the y-axis: the repeat counts, or the fraction of each window covered by the repeat")
+                        .value_parser(["count", "density"])
+                        .default_value("count")
+                )
                 .arg(
                     arg!(--strokewidth [STROKE_WIDTH] "The stroke width of the line graph in the plot")
                         .value_parser(value_parser!(i32))
                         .default_value("2")
                 )
+                .arg(
+                    arg!(--provenance <PROVENANCE> "Write a JSON record of the run (parameters, inputs with checksums, motifs and counts) to this file")
+                        .value_parser(value_parser!(PathBuf))
+                )
             )
         .get_matches();
 
@@ -192,11 +822,26 @@ fn main() -> Result<()> {
         Some(("search", matches)) => {
             search::search(matches, SubCommand::Search)?;
         }
+        Some(("arrays", matches)) => {
+            arrays::arrays(matches, SubCommand::Arrays)?;
+        }
+        Some(("t2t", matches)) => {
+            t2t::t2t(matches, SubCommand::T2T)?;
+        }
+        Some(("its", matches)) => {
+            its::its(matches, SubCommand::Its)?;
+        }
+        Some(("telomere-length", matches)) => {
+            telomere_length::telomere_length(matches, SubCommand::TelomereLength)?;
+        }
+        Some(("telomere-content", matches)) => {
+            telomere_content::telomere_content(matches, SubCommand::TelomereContent)?;
+        }
         Some(("plot", matches)) => {
             plot::plot(matches)?;
         }
-        Some(("build", _)) => {
-            build::fetch_and_save_data()?;
+        Some(("build", matches)) => {
+            build::build(matches)?;
         }
         _ => {
             unreachable!()
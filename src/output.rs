@@ -0,0 +1,266 @@
+use anyhow::Result;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Crc;
+use std::fmt::{self, Display};
+use std::fs::{create_dir_all, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The most uncompressed data in a BGZF block, as in htslib.
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// The empty block which marks the end of a BGZF file.
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// How an output is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Plain gzip.
+    Gzip,
+    /// Blocked gzip, as written by `bgzip`, which can be indexed
+    /// with `tabix`.
+    Bgzf,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Bgzf => write!(f, "bgzf"),
+        }
+    }
+}
+
+impl Compression {
+    /// Get the compression from `--compress`, or failing that from the
+    /// extension: BGZF if it ends in `.gz`.
+    pub fn from_matches(matches: &clap::ArgMatches, extension: &str) -> Self {
+        match matches.get_one::<String>("compress").map(|e| e.as_str()) {
+            Some("gzip") => Compression::Gzip,
+            Some("bgzf") => Compression::Bgzf,
+            _ if extension.ends_with(".gz") => Compression::Bgzf,
+            _ => Compression::None,
+        }
+    }
+
+    /// Whether the output is compressed at all.
+    pub fn is_compressed(&self) -> bool {
+        *self != Compression::None
+    }
+}
+
+/// Writes BGZF: gzip in independent blocks of at most 64kb, each with
+/// its compressed size in the header, so that the file can be indexed.
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    /// Data not yet written in a block.
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    /// Wrap a writer.
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buffer: Vec::with_capacity(BGZF_BLOCK_SIZE),
+        }
+    }
+
+    /// Write the buffered data as a block.
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&self.buffer)?;
+        let deflated = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&self.buffer);
+
+        // the header, footer and compressed data, less one
+        let block_size = (deflated.len() + 25) as u16;
+        self.inner.write_all(&[
+            0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00,
+        ])?;
+        self.inner.write_all(&block_size.to_le_bytes())?;
+        self.inner.write_all(&deflated)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner
+            .write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Write any remaining data and the end of file marker, and return
+    /// the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BGZF_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.flush()
+    }
+}
+
+enum Inner {
+    Plain(Box<dyn Write + Send>),
+    Gzip(GzEncoder<Box<dyn Write + Send>>),
+    Bgzf(BgzfWriter<Box<dyn Write + Send>>),
+}
+
+/// A file (or stdout) to write output to, which may be compressed.
+/// Call [`Output::finish`] once done, to complete the compressed
+/// stream.
+pub struct Output {
+    inner: Inner,
+}
+
+impl Output {
+    /// Wrap a writer, compressing what is written to it.
+    pub fn new(writer: Box<dyn Write + Send>, compression: Compression) -> Self {
+        let inner = match compression {
+            Compression::None => Inner::Plain(writer),
+            Compression::Gzip => {
+                Inner::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+            }
+            Compression::Bgzf => Inner::Bgzf(BgzfWriter::new(writer)),
+        };
+        Self { inner }
+    }
+
+    /// Finish the output, writing the end of any compressed stream.
+    pub fn finish(self) -> io::Result<()> {
+        match self.inner {
+            Inner::Plain(mut writer) => writer.flush(),
+            Inner::Gzip(encoder) => encoder.finish()?.flush(),
+            Inner::Bgzf(writer) => writer.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            Inner::Plain(writer) => writer.write(buf),
+            Inner::Gzip(writer) => writer.write(buf),
+            Inner::Bgzf(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Plain(writer) => writer.flush(),
+            Inner::Gzip(writer) => writer.flush(),
+            Inner::Bgzf(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Whether an output name is `-`, meaning stdout.
+pub fn is_stdout(output: &Path) -> bool {
+    output == Path::new("-")
+}
+
+/// Open an output: stdout if `output` is `-`, otherwise the file
+/// `<dir>/<output><suffix>`, creating `dir` if it doesn't exist. A
+/// compressed file gets `.gz` on the end of its name, if `suffix`
+/// doesn't already have it.
+pub fn create_output(
+    dir: &Path,
+    output: &Path,
+    suffix: &str,
+    compression: Compression,
+) -> Result<Output> {
+    if is_stdout(output) {
+        return Ok(Output::new(Box::new(io::stdout()), compression));
+    }
+    create_dir_all(dir)?;
+
+    let mut file_name = format!("{}/{}{}", dir.display(), output.display(), suffix);
+    if compression.is_compressed() && !file_name.ends_with(".gz") {
+        file_name.push_str(".gz");
+    }
+    Ok(Output::new(Box::new(File::create(file_name)?), compression))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_bgzf() {
+        // enough for several blocks
+        let data: Vec<u8> = (0..200_000).map(|e| b"ACGT\n"[e % 5]).collect();
+
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        // each block gives its own size
+        let mut position = 0;
+        let mut blocks = 0;
+        while position < compressed.len() {
+            let block = &compressed[position..];
+            assert_eq!(&block[..4], &[0x1f, 0x8b, 0x08, 0x04]);
+            assert_eq!(&block[12..16], b"BC\x02\x00");
+            position += u16::from_le_bytes([block[16], block[17]]) as usize + 1;
+            blocks += 1;
+        }
+        assert_eq!(position, compressed.len());
+        assert_eq!(blocks, 5);
+        assert!(compressed.ends_with(&BGZF_EOF));
+
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_compression() {
+        let matches = clap::Command::new("test")
+            .arg(clap::arg!(--compress[COMPRESS]))
+            .get_matches_from(["test"]);
+        assert_eq!(
+            Compression::from_matches(&matches, "tsv"),
+            Compression::None
+        );
+        assert_eq!(
+            Compression::from_matches(&matches, "tsv.gz"),
+            Compression::Bgzf
+        );
+
+        let matches = clap::Command::new("test")
+            .arg(clap::arg!(--compress[COMPRESS]))
+            .get_matches_from(["test", "--compress", "gzip"]);
+        assert_eq!(
+            Compression::from_matches(&matches, "tsv"),
+            Compression::Gzip
+        );
+    }
+}
@@ -1,8 +1,12 @@
-use anyhow::Result;
+use crate::provenance::Provenance;
+use anyhow::{bail, Result};
 use csv::ReaderBuilder;
+use flate2::read::MultiGzDecoder;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::BufReader;
 use std::path::PathBuf;
 
 /// The plot margins
@@ -10,6 +14,7 @@ const MARGIN: i32 = 40;
 
 /// The entry point for `tidk plot`.
 pub fn plot(matches: &clap::ArgMatches) -> Result<()> {
+    let provenance = Provenance::new("plot", matches);
     // parse the command line options
     let tsv = matches.get_one::<PathBuf>("tsv").expect("errored by clap");
     // FIXME: a bug here for manual input of chromosome cut-off which I can't figure out right now.
@@ -25,9 +30,20 @@ pub fn plot(matches: &clap::ArgMatches) -> Result<()> {
     let stroke_width = matches
         .get_one::<i32>("strokewidth")
         .expect("defaulted by clap");
+    let value = match matches
+        .get_one::<String>("value")
+        .expect("defaulted by clap")
+        .as_str()
+    {
+        "density" => PlotValue::Density,
+        _ => PlotValue::Count,
+    };
 
     // parse the tsv
     let parsed_tsv = parse_tsv(tsv.to_path_buf())?;
+    if value == PlotValue::Density && parsed_tsv.iter().any(|e| e.repeat_density.is_none()) {
+        bail!("The input TSV has no repeat_density column, it may be from an older version of tidk. Plot the counts instead, or re-run `tidk search`/`tidk find`.");
+    }
 
     // calculate the number of chromosomes to plot with the length cutoff
     let chromosome_number = chromosome_number(&parsed_tsv, chromosome_cutoff);
@@ -36,7 +52,7 @@ pub fn plot(matches: &clap::ArgMatches) -> Result<()> {
     let height: i32 = height_subplot * chromosome_number as i32 + (2 * MARGIN);
 
     // generate the plot data (see struct PlotData)
-    let plot_data = generate_plot_data(parsed_tsv, height, *width, *height_subplot);
+    let plot_data = generate_plot_data(parsed_tsv, value, height, *width, *height_subplot);
 
     // filter the data based on the cutoff
     let plot_data_filtered: Vec<PlotData> = plot_data
@@ -69,22 +85,63 @@ pub fn plot(matches: &clap::ArgMatches) -> Result<()> {
 
     svg_file.write_all(svg.as_bytes())?;
 
+    provenance.write()?;
+
     Ok(())
 }
 
 /// Deserialise the TSV records into a struct.
 #[derive(Debug, Deserialize)]
 pub struct TelomericRepeatRecord {
+    /// The header of compressed output starts with `#`, for tabix.
+    #[serde(alias = "#id")]
     pub id: String,
     pub window: i32,
-    pub forward_repeat_number: i32,
-    pub reverse_repeat_number: i32,
+    /// Fractional with proportional assignment to windows.
+    pub forward_repeat_number: f64,
+    pub reverse_repeat_number: f64,
     pub telomeric_repeat: String,
+    /// Not present in the output of older versions of tidk.
+    #[serde(default)]
+    pub repeat_density: Option<f64>,
+}
+
+/// What to plot on the y-axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlotValue {
+    /// The number of repeats, summed over both strands.
+    Count,
+    /// The fraction of the window covered by the repeat.
+    Density,
 }
 
-/// This deserializes a TSV to a [`Vec<TelomericRepeatRecord>`].
+impl TelomericRepeatRecord {
+    /// The y value of this window.
+    fn y(&self, value: PlotValue) -> f64 {
+        match value {
+            PlotValue::Count => self.forward_repeat_number + self.reverse_repeat_number,
+            PlotValue::Density => self.repeat_density.unwrap_or(0.0),
+        }
+    }
+
+    /// Is this window on the same path (sequence and telomeric repeat)
+    /// as another?
+    fn same_path(&self, other: &Self) -> bool {
+        self.id == other.id && self.telomeric_repeat == other.telomeric_repeat
+    }
+}
+
+/// This deserializes a TSV, which may be gzipped (or BGZF), to a
+/// [`Vec<TelomericRepeatRecord>`].
 fn parse_tsv(path: PathBuf) -> Result<Vec<TelomericRepeatRecord>> {
-    let mut tsv_reader = ReaderBuilder::new().delimiter(b'\t').from_path(path)?;
+    // gzip files start with the magic bytes 0x1f, 0x8b
+    let mut reader = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Box::new(MultiGzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+    let mut tsv_reader = ReaderBuilder::new().delimiter(b'\t').from_reader(reader);
     let mut plot_coords_vec = Vec::new();
 
     for result in tsv_reader.deserialize() {
@@ -92,7 +149,30 @@ fn parse_tsv(path: PathBuf) -> Result<Vec<TelomericRepeatRecord>> {
         plot_coords_vec.push(record);
     }
 
-    Ok(plot_coords_vec)
+    Ok(group_paths(plot_coords_vec))
+}
+
+/// Put the windows of each sequence and telomeric repeat together, in
+/// the order each pair first appears. Compressed output is sorted by
+/// coordinate, so the windows of several telomeric repeats are
+/// interleaved.
+fn group_paths(parsed_tsv: Vec<TelomericRepeatRecord>) -> Vec<TelomericRepeatRecord> {
+    let mut paths: HashMap<(String, String), usize> = HashMap::new();
+    let order: Vec<usize> = parsed_tsv
+        .iter()
+        .map(|e| {
+            let next = paths.len();
+            *paths
+                .entry((e.id.clone(), e.telomeric_repeat.clone()))
+                .or_insert(next)
+        })
+        .collect();
+
+    // a stable sort, so the windows of each path stay in order
+    let mut indexed: Vec<(usize, TelomericRepeatRecord)> =
+        order.into_iter().zip(parsed_tsv).collect();
+    indexed.sort_by_key(|e| e.0);
+    indexed.into_iter().map(|e| e.1).collect()
 }
 
 /// Takes the parsed TSV and the chromosome cutoff,
@@ -112,7 +192,7 @@ fn chromosome_number(parsed_tsv: &[TelomericRepeatRecord], chromosome_cutoff: i3
             break;
         }
 
-        if parsed_tsv[it].id == parsed_tsv[it + 1].id {
+        if parsed_tsv[it].same_path(&parsed_tsv[it + 1]) {
             it += 1;
             continue;
         } else {
@@ -138,14 +218,13 @@ fn scale_y(y: f64, a: f64, b: f64, min: f64, max: f64) -> f64 {
 /// This is also where the paths are scaled to
 /// the plot width and subplot height.
 fn make_path_element(
-    path_vec: Vec<(i32, i32)>,
+    path_vec: Vec<(i32, f64)>,
     x_max: usize,
-    y_max: usize,
+    (y_max, subplot_gap): (f64, f64),
     height: i32,
     width: i32,
     height_per_plot: i32,
 ) -> Option<String> {
-    let subplot_gap = 25.0;
     // need this here...
     if path_vec.is_empty() {
         return None;
@@ -167,11 +246,11 @@ fn make_path_element(
         height as f64
             - MARGIN as f64
             - scale_y(
-                path_vec[0].1 as f64,
+                path_vec[0].1,
                 0.0,
                 height_per_plot as f64,
                 0.0,
-                y_max as f64 + subplot_gap
+                y_max + subplot_gap
             )
     );
 
@@ -184,11 +263,11 @@ fn make_path_element(
             height as f64
                 - MARGIN as f64
                 - scale_y(
-                    element.1 as f64,
+                    element.1,
                     0.0,
                     height_per_plot as f64,
                     0.0,
-                    y_max as f64 + subplot_gap
+                    y_max + subplot_gap
                 )
         );
         bin += x_bin;
@@ -259,6 +338,7 @@ pub struct PlotData {
 /// along with other [`PlotData`] elements.
 fn generate_plot_data(
     parsed_tsv: Vec<TelomericRepeatRecord>,
+    value: PlotValue,
     height: i32,
     width: i32,
     height_per_plot: i32,
@@ -270,22 +350,24 @@ fn generate_plot_data(
     // a mutable vector to calculate svg path attribute
     let mut path_vec = Vec::new();
     let mut plot_data = Vec::new();
-    let mut y_max = 0;
+    let mut y_max = 0.0;
+    // headroom above the highest value
+    let subplot_gap = match value {
+        PlotValue::Count => 25.0,
+        PlotValue::Density => 0.1,
+    };
 
     loop {
         if it == file_length - 1 {
             // there may not be a path element
             // so explicitly make a blank if there is not.
             // Issue #25
-            y_max = parsed_tsv[it].forward_repeat_number + parsed_tsv[it].reverse_repeat_number;
-            path_vec.push((
-                parsed_tsv[it].window,
-                parsed_tsv[it].forward_repeat_number + parsed_tsv[it].reverse_repeat_number,
-            ));
+            y_max = parsed_tsv[it].y(value);
+            path_vec.push((parsed_tsv[it].window, parsed_tsv[it].y(value)));
             let path_element = match make_path_element(
                 path_vec.clone(),
                 path_vec.clone().len(),
-                y_max as usize,
+                (y_max, subplot_gap),
                 height,
                 width,
                 height_per_plot,
@@ -303,38 +385,30 @@ fn generate_plot_data(
             break;
         }
 
-        if parsed_tsv[it].id == parsed_tsv[it + 1].id {
+        if parsed_tsv[it].same_path(&parsed_tsv[it + 1]) {
             // calculate y max
-            if y_max <= parsed_tsv[it].forward_repeat_number + parsed_tsv[it].reverse_repeat_number
-            {
-                y_max = parsed_tsv[it].forward_repeat_number + parsed_tsv[it].reverse_repeat_number;
+            if y_max <= parsed_tsv[it].y(value) {
+                y_max = parsed_tsv[it].y(value);
             }
             // window (i.e x)
             // forward + reverse counts
-            path_vec.push((
-                parsed_tsv[it].window,
-                parsed_tsv[it].forward_repeat_number + parsed_tsv[it].reverse_repeat_number,
-            ));
+            path_vec.push((parsed_tsv[it].window, parsed_tsv[it].y(value)));
             it += 1;
         } else {
             // want to calculate y_max and...
-            if y_max <= parsed_tsv[it].forward_repeat_number + parsed_tsv[it].reverse_repeat_number
-            {
-                y_max = parsed_tsv[it].forward_repeat_number + parsed_tsv[it].reverse_repeat_number;
+            if y_max <= parsed_tsv[it].y(value) {
+                y_max = parsed_tsv[it].y(value);
             }
             // the path vector for the last element (seems important for things which occur at the
             // ends of chromosomes right..? DOH)
-            path_vec.push((
-                parsed_tsv[it].window,
-                parsed_tsv[it].forward_repeat_number + parsed_tsv[it].reverse_repeat_number,
-            ));
+            path_vec.push((parsed_tsv[it].window, parsed_tsv[it].y(value)));
             // calculate the svg path element from path_vec here
             // there may not be a path element
             // so explicitly make a blank if there is not.
             let path_element = match make_path_element(
                 path_vec.clone(),
                 path_vec.clone().len(),
-                y_max as usize,
+                (y_max, subplot_gap),
                 height,
                 width,
                 height_per_plot,
@@ -351,8 +425,59 @@ fn generate_plot_data(
             });
             path_vec.clear();
             it += 1;
-            y_max = 0;
+            y_max = 0.0;
         }
     }
     plot_data
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, window: i32, repeat: &str, count: f64) -> TelomericRepeatRecord {
+        TelomericRepeatRecord {
+            id: id.into(),
+            window,
+            forward_repeat_number: count,
+            reverse_repeat_number: 0.0,
+            telomeric_repeat: repeat.into(),
+            repeat_density: None,
+        }
+    }
+
+    #[test]
+    fn test_interleaved_repeats() {
+        // sorted by coordinate, as compressed output is
+        let parsed_tsv = group_paths(vec![
+            record("chr1", 10, "TTAGGG", 5.0),
+            record("chr1", 10, "TTAGG", 1.0),
+            record("chr1", 20, "TTAGGG", 0.0),
+            record("chr1", 20, "TTAGG", 3.0),
+            record("chr2", 10, "TTAGGG", 2.0),
+            record("chr2", 10, "TTAGG", 0.0),
+        ]);
+        assert_eq!(chromosome_number(&parsed_tsv, 0), 4);
+
+        let plot_data = generate_plot_data(parsed_tsv, PlotValue::Count, 400, 600, 100);
+        let paths: Vec<(&str, &str, usize)> = plot_data
+            .iter()
+            .map(|e| {
+                (
+                    e.id.as_str(),
+                    e.sequence.as_str(),
+                    e.path.matches('L').count(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("chr1", "TTAGGG", 1),
+                ("chr1", "TTAGG", 1),
+                ("chr2", "TTAGGG", 0),
+                ("chr2", "TTAGG", 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use clap::crate_version;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// The arguments, across the subcommands, which name an input file.
+const INPUT_IDS: [&str; 8] = [
+    "fasta",
+    "reference",
+    "motif_file",
+    "regions",
+    "samples",
+    "reads",
+    "gc_reference",
+    "tsv",
+];
+
+/// A machine-readable record of a run, written as JSON with
+/// `--provenance`: the version and parameters, the inputs with their
+/// sizes and checksums, the database used, the motifs, the wall time and
+/// counts for each record (sequence, or sample).
+///
+/// Inputs are only checksummed when the record is written, so building
+/// one up costs nothing if it isn't asked for.
+pub struct Provenance {
+    subcommand: String,
+    /// Where to write the record, if anywhere.
+    file: Option<PathBuf>,
+    date: String,
+    started: Instant,
+    parameters: Map<String, Value>,
+    inputs: Vec<PathBuf>,
+    /// The database path, and the SHA of its upstream file.
+    database: Option<(PathBuf, Option<String>)>,
+    motifs: Vec<String>,
+    /// The number of threads, from `--threads`, or the size of the global
+    /// pool where the subcommand scans with that.
+    threads: Option<usize>,
+    records: Vec<Value>,
+    summary: Option<Value>,
+}
+
+impl Provenance {
+    /// Start the record of a run of `subcommand`, taking the parameters
+    /// and input files from the command line.
+    pub fn new(subcommand: &str, matches: &clap::ArgMatches) -> Self {
+        let mut parameters = Map::new();
+        for id in matches.ids() {
+            let Ok(Some(raw)) = matches.try_get_raw(id.as_str()) else {
+                continue;
+            };
+            let values: Vec<Value> = raw
+                .map(|e| Value::String(e.to_string_lossy().into_owned()))
+                .collect();
+            let value = match <[Value; 1]>::try_from(values) {
+                Ok([value]) => value,
+                Err(values) => Value::Array(values),
+            };
+            parameters.insert(id.to_string(), value);
+        }
+
+        let mut inputs = Vec::new();
+        for id in INPUT_IDS {
+            if let Ok(Some(paths)) = matches.try_get_many::<PathBuf>(id) {
+                inputs.extend(paths.cloned());
+            }
+        }
+
+        let threads = parameters
+            .get("threads")
+            .and_then(|e| e.as_str()?.parse::<usize>().ok());
+
+        Self {
+            subcommand: subcommand.into(),
+            file: matches
+                .try_get_one::<PathBuf>("provenance")
+                .ok()
+                .flatten()
+                .cloned(),
+            date: Local::now().to_rfc3339(),
+            started: Instant::now(),
+            parameters,
+            inputs,
+            database: None,
+            motifs: Vec::new(),
+            threads,
+            records: Vec::new(),
+            summary: None,
+        }
+    }
+
+    /// Add an input file (beyond those named on the command line).
+    pub fn add_input<P: AsRef<Path>>(&mut self, path: P) {
+        self.inputs.push(path.as_ref().to_path_buf());
+    }
+
+    /// Record the database used, and the SHA of the upstream file it was
+    /// built from, if known.
+    pub fn set_database(&mut self, path: PathBuf, sha: Option<String>) {
+        self.database = Some((path, sha));
+    }
+
+    /// Record the motifs searched for, as resolved from the command line,
+    /// a motif file or the database.
+    pub fn set_motifs(&mut self, motifs: &[String]) {
+        self.motifs = motifs.to_vec();
+    }
+
+    /// Record the number of threads, where there is no `--threads`.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = Some(threads);
+    }
+
+    /// Add the counts for a record.
+    pub fn add_record(&mut self, record: Value) {
+        self.records.push(record);
+    }
+
+    /// Record counts over the whole run, where a record for each read
+    /// would be too many.
+    pub fn set_summary(&mut self, summary: Value) {
+        self.summary = Some(summary);
+    }
+
+    /// The record as JSON.
+    pub fn to_json(&self) -> Result<Value> {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|e| describe_input(e))
+            .collect::<Result<Vec<Value>>>()?;
+
+        Ok(json!({
+            "tidk_version": crate_version!(),
+            "subcommand": self.subcommand,
+            "command_line": std::env::args().collect::<Vec<String>>(),
+            "date": self.date,
+            "parameters": self.parameters,
+            "inputs": inputs,
+            "database": self.database.as_ref().map(|(path, sha)| json!({
+                "path": path.display().to_string(),
+                "upstream_sha": sha,
+            })),
+            "motifs": self.motifs,
+            "threads": self.threads,
+            "wall_time_seconds": self.started.elapsed().as_secs_f64(),
+            "records": self.records,
+            "summary": self.summary,
+        }))
+    }
+
+    /// Write the record, if `--provenance` was given.
+    pub fn write(&self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let json = self.to_json()?;
+        let mut writer = BufWriter::new(File::create(file)?);
+        serde_json::to_writer_pretty(&mut writer, &json)?;
+        writeln!(writer)?;
+        writer.flush()?;
+
+        eprintln!("[+]\tProvenance written to: {}", file.display());
+        Ok(())
+    }
+}
+
+/// The forward and reverse counts of each telomeric repeat in a window.
+pub type WindowCounts = Vec<(f64, f64)>;
+
+/// The windows of a sequence, and the occurrences of each telomeric
+/// repeat summed over them, for `tidk find` and `tidk search`.
+#[derive(Debug, Clone)]
+pub struct WindowTotals {
+    pub windows: usize,
+    /// The forward and reverse counts of each telomeric repeat.
+    pub counts: WindowCounts,
+}
+
+impl WindowTotals {
+    /// Zero counts for this many telomeric repeats.
+    pub fn new(repeats: usize) -> Self {
+        Self {
+            windows: 0,
+            counts: vec![(0.0, 0.0); repeats],
+        }
+    }
+
+    /// Add the counts of a window.
+    pub fn add(&mut self, counts: &[(f64, f64)]) {
+        self.windows += 1;
+        for (total, count) in self.counts.iter_mut().zip(counts) {
+            total.0 += count.0;
+            total.1 += count.1;
+        }
+    }
+
+    /// The record of a sequence, with the counts keyed by telomeric repeat.
+    pub fn to_record(&self, id: &str, telomeric_repeats: &[String]) -> Value {
+        let counts: Map<String, Value> = telomeric_repeats
+            .iter()
+            .zip(&self.counts)
+            .map(|(repeat, (forward, reverse))| {
+                (
+                    repeat.clone(),
+                    json!({ "forward": forward, "reverse": reverse }),
+                )
+            })
+            .collect();
+        json!({ "id": id, "windows": self.windows, "counts": counts })
+    }
+}
+
+/// The path, size and SHA-256 of an input file. Stdin has neither a size
+/// nor a checksum.
+fn describe_input(path: &Path) -> Result<Value> {
+    if path == Path::new("-") {
+        return Ok(json!({ "path": "-", "size": null, "sha256": null }));
+    }
+    let mut file =
+        File::open(path).with_context(|| format!("Could not checksum {}", path.display()))?;
+    let size = file.metadata()?.len();
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    let sha256: String = hasher
+        .finalize()
+        .iter()
+        .map(|e| format!("{e:02x}"))
+        .collect();
+
+    Ok(json!({
+        "path": path.display().to_string(),
+        "size": size,
+        "sha256": sha256,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{arg, value_parser, Command};
+
+    #[test]
+    fn test_provenance() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("provenance.fa");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let matches = Command::new("test")
+            .arg(
+                arg!(<FASTA>)
+                    .id("fasta")
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(arg!(-s --string <STRING>).action(clap::ArgAction::Append))
+            .arg(arg!(-t - -threads[THREADS]).default_value("4"))
+            .get_matches_from([
+                "test",
+                path.to_str().unwrap(),
+                "-s",
+                "TTAGG",
+                "-s",
+                "TTAGGG",
+            ]);
+
+        let mut provenance = Provenance::new("search", &matches);
+        provenance.set_motifs(&["TTAGG".into(), "TTAGGG".into()]);
+        provenance.add_record(json!({ "id": "chr1", "windows": 2 }));
+        let json = provenance.to_json().unwrap();
+
+        assert_eq!(json["subcommand"], "search");
+        assert_eq!(json["threads"], 4);
+        assert_eq!(json["parameters"]["string"], json!(["TTAGG", "TTAGGG"]));
+        assert_eq!(json["parameters"]["threads"], "4");
+        assert_eq!(json["motifs"], json!(["TTAGG", "TTAGGG"]));
+        assert_eq!(json["records"][0]["windows"], 2);
+        assert_eq!(json["inputs"][0]["size"], 3);
+        // the SHA-256 of "abc"
+        assert_eq!(
+            json["inputs"][0]["sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(json["database"].is_null());
+
+        provenance.set_threads(2);
+        assert_eq!(provenance.to_json().unwrap()["threads"], 2);
+    }
+}
@@ -0,0 +1,488 @@
+use crate::utils::{Ends, Region};
+use anyhow::{bail, Context, Result};
+use bio::io::fasta;
+use flate2::read::MultiGzDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// The number of bases of a region read from the FASTA at a time, so
+/// that a region as long as a whole chromosome is never held in memory.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// A line of a `.fai` index (as written by `samtools faidx`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FaiRecord {
+    /// The length of the sequence.
+    length: usize,
+    /// Where the sequence starts in the (uncompressed) file.
+    offset: u64,
+    /// The number of bases on each line.
+    line_bases: usize,
+    /// The number of bytes on each line, including the line ending.
+    line_width: usize,
+}
+
+impl FaiRecord {
+    /// Where base `position` (0-based) is in the (uncompressed) file.
+    fn file_offset(&self, position: usize) -> u64 {
+        self.offset
+            + (position / self.line_bases * self.line_width + position % self.line_bases) as u64
+    }
+}
+
+/// Read a `.fai` index.
+fn read_fai(path: &Path) -> Result<(HashMap<String, FaiRecord>, Vec<String>)> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut index = HashMap::new();
+    let mut names = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let parse = |i: usize| -> Result<usize> {
+            fields.get(i).and_then(|e| e.parse().ok()).with_context(|| {
+                format!("Could not parse the line of {}: {}", path.display(), line)
+            })
+        };
+        let record = FaiRecord {
+            length: parse(1)?,
+            offset: parse(2)? as u64,
+            line_bases: parse(3)?,
+            line_width: parse(4)?,
+        };
+        if record.line_bases == 0 && record.length > 0 {
+            bail!(
+                "The index {} has a line of no bases: {}",
+                path.display(),
+                line
+            );
+        }
+        index.insert(fields[0].to_string(), record);
+        names.push(fields[0].to_string());
+    }
+
+    Ok((index, names))
+}
+
+/// Read a `.gzi` index (as written by `bgzip -i` or `samtools faidx`):
+/// the compressed and uncompressed offsets of each BGZF block, bar the
+/// first.
+fn read_gzi(path: &Path) -> Result<Vec<(u64, u64)>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut read_u64 = || -> Result<u64> {
+        let mut bytes = [0; 8];
+        reader
+            .read_exact(&mut bytes)
+            .with_context(|| format!("The index {} is truncated.", path.display()))?;
+        Ok(u64::from_le_bytes(bytes))
+    };
+
+    let blocks = read_u64()?;
+    let mut index = vec![(0, 0)];
+    for _ in 0..blocks {
+        index.push((read_u64()?, read_u64()?));
+    }
+
+    Ok(index)
+}
+
+/// A FASTA file with a `.fai` index, and a `.gzi` index too if it is
+/// compressed with bgzip, so that regions of it can be read without
+/// reading the rest of the file.
+pub struct IndexedFasta {
+    file: File,
+    index: HashMap<String, FaiRecord>,
+    /// The sequence names, in the order of the file.
+    names: Vec<String>,
+    /// The BGZF blocks, for a compressed file.
+    blocks: Option<Vec<(u64, u64)>>,
+}
+
+impl IndexedFasta {
+    /// Open `path`, if it has the index (or indexes) needed for random
+    /// access. `compressed` says whether the file is gzipped.
+    pub fn open(path: &Path, compressed: bool) -> Result<Option<Self>> {
+        let fai = index_path(path, "fai");
+        let gzi = index_path(path, "gzi");
+        if !fai.exists() || (compressed && !gzi.exists()) {
+            return Ok(None);
+        }
+
+        let (index, names) = read_fai(&fai)?;
+        Ok(Some(Self {
+            file: File::open(path)?,
+            index,
+            names,
+            blocks: if compressed {
+                Some(read_gzi(&gzi)?)
+            } else {
+                None
+            },
+        }))
+    }
+
+    /// The length of sequence `name`, if it is in the index.
+    fn length(&self, name: &str) -> Option<usize> {
+        self.index.get(name).map(|e| e.length)
+    }
+
+    /// Read the bases from `start` to `end` (0-based, exclusive) of
+    /// sequence `name`.
+    fn fetch(&mut self, name: &str, start: usize, end: usize) -> Result<Vec<u8>> {
+        let record = self
+            .index
+            .get(name)
+            .with_context(|| format!("Sequence {} is not in the index.", name))?;
+        let end = end.min(record.length);
+        if start >= end {
+            return Ok(Vec::new());
+        }
+
+        let from = record.file_offset(start);
+        let to = record.file_offset(end - 1) + 1;
+        let mut bytes = vec![0; (to - from) as usize];
+
+        match &self.blocks {
+            None => {
+                self.file.seek(SeekFrom::Start(from))?;
+                self.file.read_exact(&mut bytes)?;
+            }
+            Some(blocks) => {
+                // decompress from the start of the block holding `from`
+                let (compressed, uncompressed) = blocks
+                    .iter()
+                    .rev()
+                    .find(|(_, u)| *u <= from)
+                    .copied()
+                    .unwrap_or((0, 0));
+                self.file.seek(SeekFrom::Start(compressed))?;
+                let mut decoder = MultiGzDecoder::new(BufReader::new(&mut self.file));
+                io::copy(
+                    &mut (&mut decoder).take(from - uncompressed),
+                    &mut io::sink(),
+                )?;
+                decoder.read_exact(&mut bytes)?;
+            }
+        }
+
+        bytes.retain(|e| !matches!(e, b'\n' | b'\r'));
+        Ok(bytes)
+    }
+}
+
+/// The path of an index of `path`, e.g. `genome.fa.fai`.
+fn index_path(path: &Path, extension: &str) -> PathBuf {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".");
+    index.push(extension);
+    PathBuf::from(index)
+}
+
+/// The header of the FASTA record of a region: the sequence name,
+/// followed by where along the sequence the region starts.
+fn region_header(name: &str, start: usize) -> Vec<u8> {
+    format!(">{name}\t{start}\n").into_bytes()
+}
+
+/// The sequence name and start of a region, from the header of its
+/// record (without the leading '>').
+pub fn parse_region_header(header: &[u8]) -> (String, usize) {
+    let header = String::from_utf8_lossy(header);
+    let mut fields = header.split_whitespace();
+    let name = fields.next().unwrap_or_default().to_string();
+    let start = fields.next().and_then(|e| e.parse().ok()).unwrap_or(0);
+    (name, start)
+}
+
+enum Source {
+    /// Regions read from an indexed FASTA, in the order given.
+    Indexed {
+        fasta: IndexedFasta,
+        regions: std::vec::IntoIter<Region>,
+        /// The region being read, and how far along it we are.
+        current: Option<(Region, usize)>,
+    },
+    /// Regions cut out of the records of an unindexed FASTA, in the
+    /// order of the file. Each record is read whole, as the ends of a
+    /// sequence can't be found without its length, so this does not
+    /// have the bounded memory of an indexed FASTA.
+    Streamed {
+        records: fasta::Records<Box<dyn BufRead + Send>>,
+        regions: Vec<Region>,
+        ends: Option<Ends>,
+        /// Whether each region has been found.
+        found: Vec<bool>,
+    },
+}
+
+/// Presents regions of a FASTA file as a FASTA file, one record per
+/// region. Each header carries where along the sequence the region
+/// starts, so that coordinates can be reported along the whole sequence
+/// (see [`parse_region_header`]).
+pub struct RegionFasta {
+    source: Source,
+    /// The next part of the output.
+    buffer: Vec<u8>,
+    /// How much of the buffer has been read.
+    position: usize,
+}
+
+impl RegionFasta {
+    /// Read the regions of the FASTA file at `path`, and the `ends` of
+    /// every sequence. With an index they are read directly, otherwise
+    /// the whole of `reader` (the file, decompressed) is read through to
+    /// find them, holding each record in memory in turn.
+    pub fn new(
+        path: &Path,
+        reader: Box<dyn BufRead + Send>,
+        compressed: bool,
+        mut regions: Vec<Region>,
+        ends: Option<Ends>,
+    ) -> Result<Self> {
+        let source = match IndexedFasta::open(path, compressed)? {
+            Some(fasta) => {
+                if let Some(ends) = ends {
+                    for name in &fasta.names {
+                        regions.append(&mut ends.regions(name, fasta.index[name].length));
+                    }
+                }
+                // check the regions up front, rather than part way through
+                for region in &regions {
+                    match fasta.length(&region.name) {
+                        Some(length) if region.start < length => (),
+                        Some(length) => bail!(
+                            "Region {}:{} starts beyond the end of {} ({} bp).",
+                            region.name,
+                            region.start + 1,
+                            region.name,
+                            length
+                        ),
+                        None => bail!(
+                            "Region {} is not in the index of {}.",
+                            region.name,
+                            path.display()
+                        ),
+                    }
+                }
+                // in file order, so the output is sorted by record and coordinate
+                regions.sort_by_key(|e| (fasta.index[&e.name].offset, e.start));
+                Source::Indexed {
+                    fasta,
+                    regions: regions.into_iter(),
+                    current: None,
+                }
+            }
+            None => {
+                eprintln!(
+                    "[-]\t\tNo {} index for {}, so the whole file is read to find the regions, a whole sequence at a time. Index it with `samtools faidx`{} to skip straight to them.",
+                    if compressed { ".fai/.gzi" } else { ".fai" },
+                    path.display(),
+                    if compressed { " (after compressing it with bgzip)" } else { "" }
+                );
+                // records come in file order, the regions of each by start
+                regions.sort_by_key(|e| e.start);
+                Source::Streamed {
+                    records: fasta::Reader::from_bufread(reader).records(),
+                    found: vec![false; regions.len()],
+                    regions,
+                    ends,
+                }
+            }
+        };
+
+        Ok(Self {
+            source,
+            buffer: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// The next part of the output: a header, a chunk of sequence, or
+    /// (unindexed) a whole record. `None` once all the regions are read.
+    fn next_part(&mut self) -> Result<Option<Vec<u8>>> {
+        match &mut self.source {
+            Source::Indexed {
+                fasta,
+                regions,
+                current,
+            } => match current.take() {
+                None => Ok(regions.next().map(|region| {
+                    let header = region_header(&region.name, region.start);
+                    let start = region.start;
+                    *current = Some((region, start));
+                    header
+                })),
+                Some((region, position)) => {
+                    let length = fasta.length(&region.name).unwrap_or(0);
+                    let end = region.end.map_or(length, |e| e.min(length));
+                    let chunk_end = end.min(position + CHUNK_SIZE);
+                    let mut chunk = fasta.fetch(&region.name, position, chunk_end)?;
+                    if chunk_end < end {
+                        *current = Some((region, chunk_end));
+                    }
+                    chunk.push(b'\n');
+                    Ok(Some(chunk))
+                }
+            },
+            Source::Streamed {
+                records,
+                regions,
+                ends,
+                found,
+            } => {
+                for result in records.by_ref() {
+                    let record = result?;
+                    let seq = record.seq();
+                    let mut part = Vec::new();
+                    let mut add = |region: &Region| {
+                        let end = region.end.unwrap_or(usize::MAX).min(seq.len());
+                        part.extend(region_header(&region.name, region.start));
+                        part.extend_from_slice(&seq[region.start..end]);
+                        part.push(b'\n');
+                    };
+                    for (region, found) in regions.iter().zip(found.iter_mut()) {
+                        if region.name == record.id() && region.start < seq.len() {
+                            add(region);
+                            *found = true;
+                        }
+                    }
+                    if let Some(ends) = ends {
+                        ends.regions(record.id(), seq.len())
+                            .iter()
+                            .for_each(&mut add);
+                    }
+                    if !part.is_empty() {
+                        return Ok(Some(part));
+                    }
+                }
+                for (region, _) in regions.iter().zip(found.iter()).filter(|(_, f)| !**f) {
+                    eprintln!("[-]\t\tRegion {} was not found in the input.", region.name);
+                }
+                found.fill(true);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Read for RegionFasta {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for RegionFasta {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.buffer.len() {
+            if let Some(part) = self
+                .next_part()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+            {
+                self.buffer = part;
+                self.position = 0;
+            }
+        }
+        Ok(&self.buffer[self.position..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position = (self.position + amt).min(self.buffer.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const FASTA: &str = ">chr1 description\nACGTACGTAC\nGTACGTACGT\nAA\n>chr2\nTTAGGGTTAG\nGG\n";
+    const FAI: &str = "chr1\t22\t18\t10\t11\nchr2\t12\t49\t10\t11\n";
+
+    fn regions() -> Vec<Region> {
+        vec!["chr1:9-21".parse().unwrap(), "chr2:7".parse().unwrap()]
+    }
+
+    fn read_all(mut reader: RegionFasta) -> String {
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_region_header() {
+        assert_eq!(region_header("chr1", 100), b">chr1\t100\n");
+        assert_eq!(parse_region_header(b"chr1\t100"), ("chr1".into(), 100));
+        assert_eq!(parse_region_header(b"chr1"), ("chr1".into(), 0));
+    }
+
+    #[test]
+    fn test_regions() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let expected = ">chr1\t8\nACGTACGTACGTA\n>chr2\t6\nTTAGGG\n";
+
+        // indexed
+        let path = dir.join("indexed.fa");
+        std::fs::write(&path, FASTA).unwrap();
+        std::fs::write(dir.join("indexed.fa.fai"), FAI).unwrap();
+        let reader =
+            RegionFasta::new(&path, Box::new(FASTA.as_bytes()), false, regions(), None).unwrap();
+        assert_eq!(read_all(reader), expected);
+
+        // bgzipped, as two blocks, and indexed
+        let path = dir.join("indexed.fa.gz");
+        let (first, second) = FASTA.as_bytes().split_at(30);
+        let first_block = gzip(first);
+        std::fs::write(&path, [first_block.clone(), gzip(second)].concat()).unwrap();
+        std::fs::write(dir.join("indexed.fa.gz.fai"), FAI).unwrap();
+        let mut gzi = 1u64.to_le_bytes().to_vec();
+        gzi.extend((first_block.len() as u64).to_le_bytes());
+        gzi.extend(30u64.to_le_bytes());
+        std::fs::write(dir.join("indexed.fa.gz.gzi"), gzi).unwrap();
+        let reader =
+            RegionFasta::new(&path, Box::new(FASTA.as_bytes()), true, regions(), None).unwrap();
+        assert_eq!(read_all(reader), expected);
+
+        // unindexed, so read through
+        let path = dir.join("unindexed.fa");
+        let reader =
+            RegionFasta::new(&path, Box::new(FASTA.as_bytes()), false, regions(), None).unwrap();
+        assert_eq!(read_all(reader), expected);
+
+        // just the ends, with and without the index
+        let ends = ">chr1\t0\nACG\n>chr1\t19\nTAA\n>chr2\t0\nTTA\n>chr2\t9\nGGG\n";
+        for path in [dir.join("indexed.fa"), dir.join("unindexed.fa")] {
+            let reader = RegionFasta::new(
+                &path,
+                Box::new(FASTA.as_bytes()),
+                false,
+                vec![],
+                Some(Ends::Length(3)),
+            )
+            .unwrap();
+            assert_eq!(read_all(reader), ends);
+        }
+
+        // regions must be in the index
+        let path = dir.join("indexed.fa");
+        let missing = vec!["chr3".parse().unwrap()];
+        assert!(RegionFasta::new(&path, Box::new(FASTA.as_bytes()), false, missing, None).is_err());
+    }
+}
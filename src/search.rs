@@ -1,6 +1,9 @@
-use crate::{open_fasta_reader, utils, SubCommand};
-use anyhow::Result;
-use std::fs::{create_dir_all, File};
+use crate::output::{self, Compression};
+use crate::provenance::{Provenance, WindowCounts, WindowTotals};
+use crate::stream::{Window, WindowStream};
+use crate::{clades, open_sequence_stream, utils, InputOptions, SubCommand};
+use anyhow::{bail, Result};
+use std::collections::HashSet;
 use std::io::LineWriter;
 use std::io::Write;
 use std::path::PathBuf;
@@ -8,22 +11,52 @@ use std::str;
 
 /// The entry point for `tidk search`.
 pub fn search(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
+    let mut provenance = Provenance::new("search", matches);
     let input_fasta = matches
         .get_one::<PathBuf>("fasta")
         .expect("errored by clap");
-    let reader = open_fasta_reader(input_fasta)?;
+    let mut input_options = InputOptions::from_matches(matches)?;
+    // only scan the ends of each sequence, if asked
+    input_options.ends = utils::Ends::from_matches(matches)?;
+    if let Some(ends) = input_options.ends {
+        eprintln!("[+]\tOnly scanning {ends} at each end of each sequence");
+    }
+    let mut fasta_stream = open_sequence_stream(input_fasta, &input_options)?;
 
-    let telomeric_repeat = matches
-        .get_one::<String>("string")
-        .expect("errored by clap");
+    let telomeric_repeats = get_telomeric_repeats(matches)?;
+    provenance.set_motifs(&telomeric_repeats);
     let extension = matches
         .get_one::<String>("extension")
         .expect("defaulted by clap");
-    eprintln!("[+]\tSearching genome for telomeric repeat: {telomeric_repeat}");
+    // a .gz extension means the output is BGZF-compressed
+    let compression = Compression::from_matches(matches, extension);
+    let extension = extension.trim_end_matches(".gz");
+    if telomeric_repeats.len() == 1 {
+        eprintln!(
+            "[+]\tSearching genome for telomeric repeat: {}",
+            telomeric_repeats[0]
+        );
+    } else {
+        eprintln!(
+            "[+]\tSearching genome for {} telomeric repeats:",
+            telomeric_repeats.len()
+        );
+        for telomeric_repeat in &telomeric_repeats {
+            eprintln!("[+]\t\t{telomeric_repeat}");
+        }
+    }
+
+    let options = utils::WindowOptions::from_matches(matches)?;
+    if let Some(d) = options.distance {
+        d.check(&telomeric_repeats)?;
+        eprintln!("[+]\tAlso counting approximate matches with {d}");
+        if extension == "bedgraph" {
+            eprintln!(
+                "[+]\tThe bedgraph reports the approximate counts, in place of the exact counts"
+            );
+        }
+    }
 
-    let window_size = *matches
-        .get_one::<usize>("window")
-        .expect("defaulted by clap");
     let outdir = matches
         .get_one::<PathBuf>("dir")
         .expect("defaulted by clap");
@@ -31,123 +64,240 @@ pub fn search(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
         .get_one::<PathBuf>("output")
         .expect("errored by clap");
 
-    // create directory for output
-    create_dir_all(outdir)?;
-
-    // create file
-    let file_name = format!(
-        "{}/{}{}{}",
-        outdir.display(),
-        output.display(),
-        "_telomeric_repeat_windows.",
-        extension
-    );
-    let search_file = File::create(file_name)?;
+    let threads = *matches
+        .get_one::<usize>("threads")
+        .expect("defaulted by clap");
+    if threads == 0 {
+        bail!("The number of threads must be greater than zero.");
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+
+    // fail now rather than after the search if the log has no name
+    if matches.get_flag("log") {
+        crate::log_file_name(matches)?;
+    }
+
+    // create file (or write to stdout)
+    let search_file = output::create_output(
+        outdir,
+        output,
+        &format!("_telomeric_repeat_windows.{extension}"),
+        compression,
+    )?;
+    if compression.is_compressed() {
+        eprintln!("[+]\tCompressing the output with {compression}, sorted by coordinate");
+    }
     let mut search_file = LineWriter::new(search_file);
 
     // add headers if extension/file type is a csv
     if extension == "tsv" {
         writeln!(
             search_file,
-            "id\twindow\tforward_repeat_number\treverse_repeat_number\ttelomeric_repeat"
+            "{}",
+            options.tsv_header(compression.is_compressed())
         )?;
     }
 
-    // iterate over the fasta records
-    for result in reader.records() {
-        let record = result?;
-        let id = record.id().to_owned();
+    // iterate over the fasta records, a window at a time
+    let context = options.context(&telomeric_repeats);
+    while let Some(id) = fasta_stream.next_record()? {
+        let windows = WindowStream::new(&mut fasta_stream, options, context);
 
         // fn window counter
-        write_window_counts(
-            record,
+        let totals = write_window_counts(
+            windows,
             &mut search_file,
-            telomeric_repeat,
-            window_size,
-            id.clone(),
+            &telomeric_repeats,
+            &id,
             extension,
+            options,
+            compression.is_compressed(),
+            &pool,
         )?;
+        provenance.add_record(totals.to_record(&id, &telomeric_repeats));
 
         eprintln!("[+]\tChromosome {id} processed");
     }
+    // complete the compressed stream
+    search_file
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .finish()?;
     eprintln!("[+]\tFinished searching genome.");
 
     // optional log file
     sc.log(matches)?;
+    provenance.write()?;
 
     Ok(())
 }
 
-/// Iterate over windows, counting occurrences of specified string
-/// and write to file on the fly.
-fn write_window_counts<T: std::io::Write>(
-    sequence: bio::io::fasta::Record,
-    file: &mut LineWriter<T>,
-    telomeric_repeat: &str,
-    window_size: usize,
-    id: String,
-    extension: &str,
-) -> Result<()> {
-    // get forward and reverse sequences, and length
-    // to remove overlapping matches.
-    let forward_telomeric_seq = telomeric_repeat.to_uppercase();
-    let reverse_telomeric_seq = utils::reverse_complement(&forward_telomeric_seq).to_uppercase();
-    let telomeric_length = forward_telomeric_seq.len();
-
-    // create the iterator in each loop iteration isnt costly is it?
-    let windows = sequence.seq().chunks(window_size);
-    // keep track of the window size
-
-    let mut start = 0;
-    let mut end = window_size;
-
-    // iterate over windows
-    for (i, window) in windows.enumerate() {
-        // make window uppercase
-        let windows_upper = str::from_utf8(window)?.to_uppercase();
-        // for each window, find the motifs in this
-        let forward_motif = utils::find_motifs(&forward_telomeric_seq, &windows_upper);
-        let reverse_motif = utils::find_motifs(&reverse_telomeric_seq, &windows_upper);
-
-        // remove overlapping matches
-        // not sure this is necessary, but thought it might be...
-        let forward_motif_noverlap =
-            utils::remove_overlapping_indexes(forward_motif, telomeric_length);
-        let reverse_motif_noverlap =
-            utils::remove_overlapping_indexes(reverse_motif, telomeric_length);
-
-        // the number of matches for forward/reverse
-        let forward_repeat_number = forward_motif_noverlap.len();
-        let reverse_repeat_number = reverse_motif_noverlap.len();
-        // write to file
-        // increment window
-        if i != 0 {
-            start += window_size;
-            end += window_size;
+/// Collect the telomeric repeats from `--string` (which can be given
+/// more than once) and `--motif-file`, in the order they were given
+/// and without duplicates, or from the clade database for the
+/// subcommands which take `--clade` in their place.
+pub fn get_telomeric_repeats(matches: &clap::ArgMatches) -> Result<Vec<String>> {
+    if let Ok(Some(clade)) = matches.try_get_one::<String>("clade") {
+        let clade_info = clades::return_telomere_sequence(clade.clone())?;
+        if clade_info.seq.get_inner().is_empty() {
+            bail!("No telomeric repeats for {} in the database.", clade);
         }
-        if end > sequence.seq().len() {
-            end = sequence.seq().len();
+        return Ok(clade_info.seq.get_inner().clone());
+    }
+
+    let mut telomeric_repeats = Vec::new();
+
+    if let Some(strings) = matches.get_many::<String>("string") {
+        for string in strings {
+            telomeric_repeats.push(utils::parse_telomeric_repeat(string)?);
         }
+    }
+    if let Some(motif_file) = matches.get_one::<PathBuf>("motif_file") {
+        telomeric_repeats.append(&mut utils::read_motif_file(motif_file)?);
+    }
+
+    let mut seen = HashSet::new();
+    telomeric_repeats.retain(|e| seen.insert(e.clone()));
+
+    if telomeric_repeats.is_empty() {
+        bail!("No telomeric repeats were given to search for.");
+    }
+
+    Ok(telomeric_repeats)
+}
+
+/// Iterate over windows, counting occurrences of the specified strings
+/// and write to file on the fly. Windows are read from the sequence a
+/// batch at a time and processed in parallel on `pool`, and each window
+/// is scanned for all the strings at once. The TSV rows are written out
+/// in a block per telomeric repeat, as in `tidk find`, unless `sorted`,
+/// in which case they are written in coordinate order so the output can
+/// be indexed with `tabix`. They also report
+/// the fraction of each window covered by the repeat. The bedgraph has
+/// no room for the telomeric repeat, so its value is summed over all of
+/// them. If a `distance` is given, approximate occurrences are counted
+/// too, and reported in extra columns, or in the bedgraph in place of
+/// the exact counts (which they include). The exact counts are summed over
+/// the windows, and returned.
+#[allow(clippy::too_many_arguments)]
+fn write_window_counts<T: std::io::Write, R: std::io::BufRead>(
+    windows: WindowStream<R>,
+    file: &mut LineWriter<T>,
+    telomeric_repeats: &[String],
+    id: &str,
+    extension: &str,
+    options: utils::WindowOptions,
+    sorted: bool,
+    pool: &rayon::ThreadPool,
+) -> Result<WindowTotals> {
+    // all the telomeric repeats are found in one pass over the sequence
+    let forward_telomeric_seqs: Vec<String> =
+        telomeric_repeats.iter().map(|e| e.to_uppercase()).collect();
+    let matcher = utils::RepeatMatcher::new(&forward_telomeric_seqs, options.distance)?;
+
+    // the rows of the TSV, for each telomeric repeat. With just the
+    // one repeat, or if sorted by coordinate, they can be written
+    // straight away
+    let mut rows = vec![String::new(); telomeric_repeats.len()];
+    let mut totals = WindowTotals::new(telomeric_repeats.len());
+
+    windows.process_in_order(
+        pool,
+        pool.current_num_threads() * 16,
+        |window: &Window| -> Result<(Vec<String>, String, WindowCounts)> {
+            let Window { start, end, .. } = *window;
+            // make window uppercase, dropping masked (or unmasked) bases if asked
+            let windows_upper = options.masking.apply(&window.seq);
+            let composition = utils::BaseComposition::new(window.bases());
+            // for each window, find the motifs in this
+            let hits = matcher.find(&windows_upper, window.context_start);
+
+            let mut window_rows = Vec::new();
+            let mut window_counts = Vec::new();
+            // the bedgraph value, summed over telomeric repeats
+            let mut bedgraph_repeat_number = 0.0;
+
+            for (forward_telomeric_seq, hits) in forward_telomeric_seqs.iter().zip(hits.iter()) {
+                let telomeric_length = forward_telomeric_seq.len();
+                let count = |hits: &[usize]| options.count_hits(hits, telomeric_length, start, end);
+
+                // the number of matches for forward/reverse
+                let forward_repeat_number = count(&hits.forward);
+                let reverse_repeat_number = count(&hits.reverse);
+                window_counts.push((forward_repeat_number, reverse_repeat_number));
+                // and the fraction of the window they cover
+                let density = utils::RepeatDensity::new(
+                    &hits.forward,
+                    &hits.reverse,
+                    telomeric_length,
+                    (start, end),
+                );
+
+                // and the approximate matches, if asked for
+                let approx_repeat_numbers = hits
+                    .approx
+                    .as_ref()
+                    .map(|(forward, reverse)| (count(forward), count(reverse)));
+
+                if extension == "tsv" {
+                    let mut row = format!(
+                        "{id}\t{start}\t{end}\t{}\t{}\t{forward_telomeric_seq}\t{density}\t{composition}",
+                        options.format_count(forward_repeat_number),
+                        options.format_count(reverse_repeat_number)
+                    );
+                    if let Some((forward_approx, reverse_approx)) = approx_repeat_numbers {
+                        row.push_str(&format!(
+                            "\t{}\t{}",
+                            options.format_count(forward_approx),
+                            options.format_count(reverse_approx)
+                        ));
+                    }
+                    row.push('\n');
+                    window_rows.push(row);
+                } else {
+                    // approximate counts take precedence, as they were explicitly asked for
+                    bedgraph_repeat_number += match approx_repeat_numbers {
+                        Some((forward_approx, reverse_approx)) => forward_approx + reverse_approx,
+                        None => forward_repeat_number + reverse_repeat_number,
+                    };
+                }
+            }
 
-        if extension == "tsv" {
-            writeln!(
-                file,
-                "{id}\t{end}\t{forward_repeat_number}\t{reverse_repeat_number}\t{forward_telomeric_seq}"
-            )?;
-        } else {
             // for bedgraph only four columns, and sum the forward & reverse for convenience
-            writeln!(
-                file,
-                "{}\t{}\t{}\t{}",
+            let bedgraph_row = format!(
+                "{}\t{}\t{}\t{}\n",
                 id,
                 start,
                 end,
-                forward_repeat_number + reverse_repeat_number,
-            )?;
-        }
+                options.format_count(bedgraph_repeat_number)
+            );
+            Ok((window_rows, bedgraph_row, window_counts))
+        },
+        |(window_rows, bedgraph_row, window_counts)| {
+            totals.add(&window_counts);
+            if extension != "tsv" {
+                file.write_all(bedgraph_row.as_bytes())?;
+            } else if rows.len() == 1 || sorted {
+                for window_row in window_rows {
+                    file.write_all(window_row.as_bytes())?;
+                }
+            } else {
+                for (row, window_row) in rows.iter_mut().zip(window_rows) {
+                    row.push_str(&window_row);
+                }
+            }
+            Ok(())
+        },
+    )?;
+
+    // write to file
+    for row in rows {
+        file.write_all(row.as_bytes())?;
     }
 
-    Ok(())
+    Ok(totals)
 }
 
 #[cfg(test)]
@@ -155,19 +305,69 @@ mod tests {
     use std::io::{LineWriter, Read};
 
     use super::write_window_counts;
+    use crate::stream::{SequenceStream, WindowStream};
+    use crate::utils::{Assignment, Distance, Masking, WindowOptions};
 
     // a wrapper for making a bio::io::fasta record
     fn make_record(id: &str, seq: &[u8]) -> bio::io::fasta::Record {
         bio::io::fasta::Record::with_attrs(id, None, seq)
     }
 
+    // stream a record back out of a fasta, and count its windows
+    fn count_windows<T: std::io::Write>(
+        rec: bio::io::fasta::Record,
+        file: &mut LineWriter<T>,
+        telomeric_repeats: &[String],
+        id: String,
+        extension: &str,
+        options: WindowOptions,
+        sorted: bool,
+    ) -> anyhow::Result<()> {
+        let fasta = [b">", id.as_bytes(), b"\n", rec.seq(), b"\n"].concat();
+        let mut stream = SequenceStream::new(fasta.as_slice());
+        stream.next_record()?;
+        let context = options.context(telomeric_repeats);
+        let windows = WindowStream::new(&mut stream, options, context);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build()?;
+
+        write_window_counts(
+            windows,
+            file,
+            telomeric_repeats,
+            &id,
+            extension,
+            options,
+            sorted,
+            &pool,
+        )?;
+        Ok(())
+    }
+
     // take a record, write to a vector (fake file), then read out of this the output.
-    fn calc_windows(rec: bio::io::fasta::Record, repeat: &str, ws: usize) -> String {
+    fn calc_windows(
+        rec: bio::io::fasta::Record,
+        repeat: &str,
+        ws: usize,
+        distance: Option<Distance>,
+    ) -> String {
         let file = Vec::new();
         let mut lw = LineWriter::new(file);
         let id = rec.id().to_owned();
 
-        write_window_counts(rec, &mut lw, repeat, ws, id, "tsv").unwrap();
+        let options = WindowOptions {
+            distance,
+            ..WindowOptions::new(ws, ws)
+        };
+        count_windows(
+            rec,
+            &mut lw,
+            &[repeat.to_string()],
+            id,
+            "tsv",
+            options,
+            false,
+        )
+        .unwrap();
 
         // read file contents to new vec
         let mut out = Vec::new();
@@ -186,15 +386,317 @@ mod tests {
             b"TTAGGTTAGGTTAGGCAGCATCACACTGATCATCTGATTAGGTTAGGTTAGG",
         );
 
-        let windows_calculation = calc_windows(rec, "TTAGG", 20);
+        let windows_calculation = calc_windows(rec, "TTAGG", 20, None);
 
         let rows: Vec<&str> = windows_calculation.lines().collect();
 
         // three in first window
-        assert_eq!(rows[0], "test1\t20\t3\t0\tTTAGG");
+        assert_eq!(
+            rows[0],
+            "test1\t0\t20\t3\t0\tTTAGG\t20\t0.7500\t0.0000\t0.7500\t0.0000\t0.0000\t0.4500"
+        );
         // none in second
-        assert_eq!(rows[1], "test1\t40\t0\t0\tTTAGG");
+        assert_eq!(
+            rows[1],
+            "test1\t20\t40\t0\t0\tTTAGG\t20\t0.0000\t0.0000\t0.0000\t0.0000\t0.0000\t0.3500"
+        );
         // two in third
-        assert_eq!(rows[2], "test1\t52\t2\t0\tTTAGG");
+        assert_eq!(
+            rows[2],
+            "test1\t40\t52\t2\t0\tTTAGG\t12\t0.8333\t0.0000\t0.8333\t0.0000\t0.0000\t0.5000"
+        );
+    }
+
+    #[test]
+    fn test_search_mismatches() {
+        // second repeat carries a substitution
+        let rec = make_record("test1", b"TTAGGTTCGGTTAGGCAGCA");
+
+        let windows_calculation = calc_windows(rec, "TTAGG", 20, Some(Distance::Hamming(1)));
+
+        let rows: Vec<&str> = windows_calculation.lines().collect();
+
+        // two exact, three approximate
+        assert_eq!(
+            rows[0],
+            "test1\t0\t20\t2\t0\tTTAGG\t20\t0.5000\t0.0000\t0.5000\t0.0000\t0.0000\t0.5000\t3\t0"
+        );
+    }
+
+    #[test]
+    fn test_search_soft_masked() {
+        // the first two repeats are soft-masked
+        let rec = make_record("test1", b"ttaggttaggTTAGGCAGCA");
+
+        let rows: Vec<String> = [Masking::Both, Masking::Unmasked, Masking::Masked]
+            .into_iter()
+            .map(|masking| {
+                let mut lw = LineWriter::new(Vec::new());
+                let options = WindowOptions {
+                    masking,
+                    ..WindowOptions::new(20, 20)
+                };
+                count_windows(
+                    rec.clone(),
+                    &mut lw,
+                    &["TTAGG".to_string()],
+                    "test1".into(),
+                    "tsv",
+                    options,
+                    false,
+                )
+                .unwrap();
+                String::from_utf8(lw.into_inner().unwrap()).unwrap()
+            })
+            .collect();
+
+        assert_eq!(
+            rows[0],
+            "test1\t0\t20\t3\t0\tTTAGG\t20\t0.7500\t0.0000\t0.7500\t0.5000\t0.0000\t0.4500\n"
+        );
+        assert_eq!(
+            rows[1],
+            "test1\t0\t20\t1\t0\tTTAGG\t20\t0.2500\t0.0000\t0.2500\t0.5000\t0.0000\t0.4500\n"
+        );
+        assert_eq!(
+            rows[2],
+            "test1\t0\t20\t2\t0\tTTAGG\t20\t0.5000\t0.0000\t0.5000\t0.5000\t0.0000\t0.4500\n"
+        );
+    }
+
+    #[test]
+    fn test_search_iupac() {
+        let rec = make_record("test1", b"TTAGGGTTGGGGCCCTAACCCCAA");
+
+        let windows_calculation = calc_windows(rec, "TTRGGG", 24, None);
+
+        let rows: Vec<&str> = windows_calculation.lines().collect();
+
+        // two on each strand
+        assert_eq!(
+            rows[0],
+            "test1\t0\t24\t2\t2\tTTRGGG\t24\t0.5000\t0.5000\t1.0000\t0.0000\t0.0000\t0.5833"
+        );
+    }
+
+    #[test]
+    fn test_search_many() {
+        let rec = make_record("test1", b"TTAGGTTAGGTTAGGCAGCATTAGGGTTAGGGTTAGGG");
+
+        let file = Vec::new();
+        let mut lw = LineWriter::new(file);
+        let repeats = ["TTAGG".to_string(), "TTAGGG".to_string()];
+        let options = WindowOptions::new(20, 20);
+        count_windows(
+            rec,
+            &mut lw,
+            &repeats,
+            "test1".into(),
+            "tsv",
+            options,
+            false,
+        )
+        .unwrap();
+        let out = String::from_utf8(lw.into_inner().unwrap()).unwrap();
+
+        let rows: Vec<&str> = out.lines().collect();
+
+        // a block of windows for each repeat
+        assert_eq!(
+            rows,
+            vec![
+                "test1\t0\t20\t3\t0\tTTAGG\t20\t0.7500\t0.0000\t0.7500\t0.0000\t0.0000\t0.4500",
+                "test1\t20\t38\t3\t0\tTTAGG\t18\t0.8333\t0.0000\t0.8333\t0.0000\t0.0000\t0.5000",
+                "test1\t0\t20\t0\t0\tTTAGGG\t20\t0.0000\t0.0000\t0.0000\t0.0000\t0.0000\t0.4500",
+                "test1\t20\t38\t3\t0\tTTAGGG\t18\t1.0000\t0.0000\t1.0000\t0.0000\t0.0000\t0.5000",
+            ]
+        );
+
+        // or sorted by coordinate, for tabix
+        let rec = make_record("test1", b"TTAGGTTAGGTTAGGCAGCATTAGGGTTAGGGTTAGGG");
+        let mut lw = LineWriter::new(Vec::new());
+        count_windows(rec, &mut lw, &repeats, "test1".into(), "tsv", options, true).unwrap();
+        let out = String::from_utf8(lw.into_inner().unwrap()).unwrap();
+        let ends: Vec<&str> = out.lines().map(|e| e.split('\t').nth(2).unwrap()).collect();
+        assert_eq!(ends, vec!["20", "20", "38", "38"]);
+    }
+
+    #[test]
+    fn test_search_step() {
+        // an array straddling the boundary of two tiled windows
+        let rec = make_record("test1", b"CAGCATCACATTAGGTTAGGTTAGGTTAGGCAGCATCACA");
+
+        let file = Vec::new();
+        let mut lw = LineWriter::new(file);
+        let options = WindowOptions::new(20, 10);
+        count_windows(
+            rec,
+            &mut lw,
+            &["TTAGG".into()],
+            "test1".into(),
+            "bedgraph",
+            options,
+            false,
+        )
+        .unwrap();
+        let out = String::from_utf8(lw.into_inner().unwrap()).unwrap();
+
+        let rows: Vec<&str> = out.lines().collect();
+
+        // the middle window holds the whole array
+        assert_eq!(
+            rows,
+            vec!["test1\t0\t20\t2", "test1\t10\t30\t4", "test1\t20\t40\t2"]
+        );
+    }
+
+    #[test]
+    fn test_search_assignment() {
+        // the third repeat straddles the boundary of two tiled windows
+        let rec = make_record("test1", b"CAGCATCTTAGGTTAGGTTAGGTTAGGCAGCATCACA");
+
+        let bedgraph = |assignment| {
+            let file = Vec::new();
+            let mut lw = LineWriter::new(file);
+            let options = WindowOptions {
+                assignment,
+                ..WindowOptions::new(20, 20)
+            };
+            count_windows(
+                rec.clone(),
+                &mut lw,
+                &["TTAGG".into()],
+                "test1".into(),
+                "bedgraph",
+                options,
+                false,
+            )
+            .unwrap();
+            String::from_utf8(lw.into_inner().unwrap()).unwrap()
+        };
+
+        // it is missed when windows are scanned on their own
+        assert_eq!(
+            bedgraph(Assignment::Window),
+            "test1\t0\t20\t2\ntest1\t20\t37\t1\n"
+        );
+        assert_eq!(
+            bedgraph(Assignment::Start),
+            "test1\t0\t20\t3\ntest1\t20\t37\t1\n"
+        );
+        assert_eq!(
+            bedgraph(Assignment::Proportional),
+            "test1\t0\t20\t2.60\ntest1\t20\t37\t1.40\n"
+        );
+    }
+
+    #[test]
+    fn test_sorted_bgzf_indexable() {
+        use crate::output::{Compression, Output};
+        use crate::{open_sequence_stream, utils, InputOptions};
+        use noodles_csi::binning_index::index::{header, reference_sequence::bin::Chunk};
+        use noodles_tabix as tabix;
+        use std::io::{BufRead, Write};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let fasta = dir.join("genome.fa");
+        let (arm, middle) = ("TTAGGG".repeat(20), "ACGT".repeat(100));
+        std::fs::write(
+            &fasta,
+            format!(">chr1\n{arm}{middle}\n>chr2\n{middle}{arm}\n"),
+        )
+        .unwrap();
+
+        // regions out of order, overlapping and repeated
+        let regions = ["chr2:301-520", "chr1:51-300", "chr1:1-100", "chr2:301-520"]
+            .iter()
+            .map(|e| e.parse().unwrap())
+            .collect();
+        let input_options = InputOptions {
+            regions: utils::merge_regions(regions),
+            ..Default::default()
+        };
+        let options = WindowOptions::new(50, 50);
+        let telomeric_repeats = ["TTAGGG".to_string(), "TTGGGG".to_string()];
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+
+        // write the windows as search does
+        let path = dir.join("windows.tsv.gz");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut file = LineWriter::new(Output::new(Box::new(file), Compression::Bgzf));
+        writeln!(file, "{}", options.tsv_header(true)).unwrap();
+        let mut stream = open_sequence_stream(&fasta, &input_options).unwrap();
+        while let Some(id) = stream.next_record().unwrap() {
+            let context = options.context(&telomeric_repeats);
+            let windows = WindowStream::new(&mut stream, options, context);
+            write_window_counts(
+                windows,
+                &mut file,
+                &telomeric_repeats,
+                &id,
+                "tsv",
+                options,
+                true,
+                &pool,
+            )
+            .unwrap();
+        }
+        file.into_inner()
+            .map_err(|e| e.into_error())
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        // index it as `tabix -p bed` would, which fails if a row is out
+        // of order
+        let mut reader = noodles_bgzf::io::Reader::new(std::fs::File::open(&path).unwrap());
+        let mut indexer = tabix::index::Indexer::default();
+        indexer.set_header(header::Builder::bed().build());
+        let mut line = String::new();
+        let mut rows = 0;
+        loop {
+            let start = reader.virtual_position();
+            line.clear();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let window_start: usize = fields[1].parse().unwrap();
+            let window_end: usize = fields[2].parse().unwrap();
+            indexer
+                .add_record(
+                    fields[0],
+                    (window_start + 1).try_into().unwrap(),
+                    window_end.try_into().unwrap(),
+                    Chunk::new(start, reader.virtual_position()),
+                )
+                .unwrap();
+            rows += 1;
+        }
+        // chr1:1-300 in six windows and chr2:301-520 in five, for each repeat
+        assert_eq!(rows, 22);
+        let index_path = dir.join("windows.tsv.gz.tbi");
+        tabix::fs::write(&index_path, &indexer.build()).unwrap();
+
+        // and query it
+        let mut reader = tabix::io::indexed_reader::Builder::default()
+            .build_from_path(&path)
+            .unwrap();
+        let region = "chr2:501-520".parse().unwrap();
+        let rows: Vec<String> = reader
+            .query(&region)
+            .unwrap()
+            .map(|e| e.unwrap().as_ref().to_string())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("chr2\t500\t520\t3\t0\tTTAGGG\t"));
+        assert!(rows[1].starts_with("chr2\t500\t520\t0\t0\tTTGGGG\t"));
     }
 }
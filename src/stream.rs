@@ -0,0 +1,596 @@
+use crate::regions::parse_region_header;
+use crate::utils::{mask_low_quality, WindowOptions};
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use std::io::BufRead;
+
+/// Reads a FASTA or FASTQ file a block of sequence at a time, rather
+/// than a whole record at a time, so that very long sequences never
+/// have to be held in memory. Lines of FASTA sequence are read in
+/// pieces too, so an unwrapped sequence on a single line is no
+/// different. A FASTQ read is held whole, as its qualities come after
+/// its sequence.
+pub struct SequenceStream<R: BufRead> {
+    reader: R,
+    /// The sequence of the current FASTQ read, or a header, without its
+    /// line ending.
+    line: Vec<u8>,
+    /// How much of the current line has been read.
+    line_position: usize,
+    /// Whether the reader is at the start of a line, rather than part
+    /// way along a line of FASTA sequence.
+    at_line_start: bool,
+    /// The ID of the next record, once its header has been read.
+    next_id: Option<String>,
+    /// Whether we are in the sequence of a record.
+    in_record: bool,
+    /// Whether the end of the file has been reached.
+    eof: bool,
+    /// Bases of FASTQ reads below this quality are masked.
+    min_quality: Option<u8>,
+    /// Whether the records are regions of sequences (see
+    /// [`crate::regions::RegionFasta`]).
+    regions: bool,
+    /// Where along the whole sequence the next record starts.
+    next_offset: usize,
+    /// Where along the whole sequence the current record starts.
+    offset: usize,
+}
+
+impl<R: BufRead> SequenceStream<R> {
+    /// Wrap a reader.
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: Vec::new(),
+            line_position: 0,
+            at_line_start: true,
+            next_id: None,
+            in_record: false,
+            eof: false,
+            min_quality: None,
+            regions: false,
+            next_offset: 0,
+            offset: 0,
+        }
+    }
+
+    /// Mask the bases of FASTQ reads with a quality below `min_quality`.
+    pub fn with_min_quality(mut self, min_quality: Option<u8>) -> Self {
+        self.min_quality = min_quality;
+        self
+    }
+
+    /// Read the records as regions of sequences, each starting where its
+    /// header says, rather than at the start of the sequence.
+    pub fn with_regions(mut self, regions: bool) -> Self {
+        self.regions = regions;
+        self
+    }
+
+    /// Where along the whole sequence the current record starts, which
+    /// is zero unless it is a region of the sequence.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Move on to the next record, returning its ID (the header up to
+    /// the first whitespace), or `None` at the end of the file. Any of
+    /// the current record not yet read is skipped.
+    pub fn next_record(&mut self) -> Result<Option<String>> {
+        loop {
+            if let Some(id) = self.next_id.take() {
+                self.in_record = true;
+                self.offset = self.next_offset;
+                return Ok(Some(id));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            self.read_line()?;
+        }
+    }
+
+    /// Append up to `n` bases of the current record to `buffer`,
+    /// returning how many were appended. Fewer than `n` are appended
+    /// only at the end of the record.
+    pub fn read(&mut self, buffer: &mut Vec<u8>, n: usize) -> Result<usize> {
+        let mut read = 0;
+        while read < n && self.in_record {
+            // the rest of a FASTQ read
+            if self.line_position < self.line.len() {
+                let take = (n - read).min(self.line.len() - self.line_position);
+                buffer.extend_from_slice(&self.line[self.line_position..self.line_position + take]);
+                self.line_position += take;
+                read += take;
+                continue;
+            }
+            if self.at_line_start {
+                self.read_line()?;
+                continue;
+            }
+
+            // part of a line of FASTA sequence, straight from the reader
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                self.at_line_start = true;
+                continue;
+            }
+            let line_end = available.iter().position(|e| *e == b'\n');
+            let take = line_end.unwrap_or(available.len()).min(n - read);
+            let before = buffer.len();
+            buffer.extend(available[..take].iter().filter(|e| **e != b'\r'));
+            read += buffer.len() - before;
+
+            if line_end == Some(take) {
+                self.reader.consume(take + 1);
+                self.at_line_start = true;
+            } else {
+                self.reader.consume(take);
+            }
+        }
+        Ok(read)
+    }
+
+    /// Read the next line, if it is a header, or move on to the start
+    /// of the next line. A line of FASTA sequence is left to be read
+    /// (or skipped) a piece at a time. A FASTQ header is read along
+    /// with the rest of its record, leaving the sequence as the
+    /// current line.
+    fn read_line(&mut self) -> Result<()> {
+        self.line.clear();
+        self.line_position = 0;
+
+        if !self.at_line_start {
+            return self.skip_line();
+        }
+
+        let first = match self.reader.fill_buf()?.first() {
+            Some(first) => *first,
+            None => {
+                self.eof = true;
+                self.in_record = false;
+                return Ok(());
+            }
+        };
+
+        match first {
+            b'>' => {
+                self.read_raw_line()?;
+                if self.regions {
+                    let (id, offset) = parse_region_header(&self.line[1..]);
+                    self.next_id = Some(id);
+                    self.next_offset = offset;
+                } else {
+                    self.next_id = Some(parse_id(&self.line[1..]));
+                }
+                self.line.clear();
+                self.in_record = false;
+            }
+            // quality lines are read with their record, so this is
+            // always the header of a FASTQ record
+            b'@' => {
+                self.read_raw_line()?;
+                self.next_id = Some(parse_id(&self.line[1..]));
+                self.in_record = false;
+                self.read_fastq_record()?;
+            }
+            // an empty line
+            b'\n' | b'\r' => self.reader.consume(1),
+            _ if !self.in_record => {
+                bail!("Expected a FASTA or FASTQ header (starting with '>' or '@') before any sequence.")
+            }
+            _ => self.at_line_start = false,
+        }
+        Ok(())
+    }
+
+    /// Skip the rest of a line of FASTA sequence, a piece at a time.
+    fn skip_line(&mut self) -> Result<()> {
+        loop {
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                break;
+            }
+            match available.iter().position(|e| *e == b'\n') {
+                Some(line_end) => {
+                    self.reader.consume(line_end + 1);
+                    break;
+                }
+                None => {
+                    let n = available.len();
+                    self.reader.consume(n);
+                }
+            }
+        }
+        self.at_line_start = true;
+        Ok(())
+    }
+
+    /// Read the sequence, separator and quality lines of a FASTQ
+    /// record, masking low quality bases, and keep the sequence as
+    /// the current line. The sequence and qualities may each be
+    /// wrapped over several lines: the sequence runs up to the '+'
+    /// line, and the qualities for as long as the sequence.
+    fn read_fastq_record(&mut self) -> Result<()> {
+        let id = self.next_id.clone().unwrap_or_default();
+        let mut seq = Vec::new();
+        loop {
+            if !self.read_raw_line()? {
+                bail!("FASTQ record {} is missing the '+' separator line.", id);
+            }
+            if self.line.first() == Some(&b'+') {
+                break;
+            }
+            seq.extend_from_slice(&self.line);
+        }
+        // quality lines can start with '@' or '+', so they are
+        // counted rather than looked for
+        let mut qual = Vec::new();
+        while qual.len() < seq.len() {
+            if !self.read_raw_line()? {
+                break;
+            }
+            qual.extend_from_slice(&self.line);
+        }
+        if qual.len() != seq.len() {
+            bail!(
+                "FASTQ record {} has a quality string of a different length to its sequence.",
+                id
+            );
+        }
+        if let Some(min_quality) = self.min_quality {
+            mask_low_quality(&mut seq, &qual, min_quality);
+        }
+        self.line = seq;
+        Ok(())
+    }
+
+    /// Read a line into `self.line` without its line ending, returning
+    /// `false` at the end of the file.
+    fn read_raw_line(&mut self) -> Result<bool> {
+        self.line.clear();
+        if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+            return Ok(false);
+        }
+        while let Some(b'\n' | b'\r') = self.line.last() {
+            self.line.pop();
+        }
+        Ok(true)
+    }
+}
+
+/// The ID from a header (without its leading '>' or '@'), which is
+/// everything up to the first whitespace.
+fn parse_id(header: &[u8]) -> String {
+    String::from_utf8_lossy(header)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// A window of a sequence, along with some context on either side,
+/// so that occurrences of a motif overlapping the window can be found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    /// Start of the window (0-based).
+    pub start: usize,
+    /// End of the window (exclusive).
+    pub end: usize,
+    /// Where along the sequence `seq` starts.
+    pub context_start: usize,
+    /// The sequence of the window and its context.
+    pub seq: Vec<u8>,
+}
+
+impl Window {
+    /// The sequence of the window itself, without its context.
+    pub fn bases(&self) -> &[u8] {
+        &self.seq[self.start - self.context_start..self.end - self.context_start]
+    }
+}
+
+/// Iterates over the windows of the current record of a [`SequenceStream`],
+/// giving the same windows as [`WindowOptions::windows`]. Only the current
+/// window and its context are held in memory. For a region of a sequence,
+/// the windows start at the start of the region, and their coordinates
+/// are along the whole sequence.
+pub struct WindowStream<'a, R: BufRead> {
+    stream: &'a mut SequenceStream<R>,
+    options: WindowOptions,
+    /// The bases of context either side of each window.
+    context: usize,
+    /// The bases read but not yet dropped.
+    buffer: Vec<u8>,
+    /// Where along the sequence the buffer starts.
+    buffer_start: usize,
+    /// The index of the next window.
+    index: usize,
+    /// Where along the whole sequence the record starts.
+    offset: usize,
+    done: bool,
+}
+
+impl<'a, R: BufRead> WindowStream<'a, R> {
+    /// Window the current record of `stream`, with `context` bases of
+    /// sequence either side of each window.
+    pub fn new(stream: &'a mut SequenceStream<R>, options: WindowOptions, context: usize) -> Self {
+        let offset = stream.offset();
+        Self {
+            stream,
+            options,
+            context,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            index: 0,
+            offset,
+            done: false,
+        }
+    }
+
+    /// The next window, if there is one.
+    fn next_window(&mut self) -> Result<Option<Window>> {
+        let WindowOptions {
+            window_size, step, ..
+        } = self.options;
+        let start = self.index * step;
+        let context_start = start.saturating_sub(self.context);
+
+        // read as far as the end of this window's context
+        let needed = start + window_size + self.context;
+        let read_to = self.buffer_start + self.buffer.len();
+        if needed > read_to {
+            self.stream.read(&mut self.buffer, needed - read_to)?;
+        }
+        // if we have fewer bases than needed, this is the end of the sequence
+        let available = self.buffer_start + self.buffer.len();
+
+        // the window must start in the sequence, and the last window
+        // must not already have reached the end of it
+        if start >= available || (self.index > 0 && start + window_size - step >= available) {
+            return Ok(None);
+        }
+
+        // drop what is no longer needed
+        if context_start > self.buffer_start {
+            self.buffer.drain(..context_start - self.buffer_start);
+            self.buffer_start = context_start;
+        }
+
+        let end = (start + window_size).min(available);
+        let context_end = (end + self.context).min(available);
+        self.index += 1;
+
+        Ok(Some(Window {
+            start: start + self.offset,
+            end: end + self.offset,
+            context_start: context_start + self.offset,
+            seq: self.buffer[..context_end - context_start].to_vec(),
+        }))
+    }
+
+    /// Process the windows in parallel on `pool`, in batches of `batch`
+    /// windows, passing the results to `output` in order.
+    pub fn process_in_order<T, F, O>(
+        mut self,
+        pool: &rayon::ThreadPool,
+        batch: usize,
+        process: F,
+        mut output: O,
+    ) -> Result<()>
+    where
+        T: Send,
+        F: Fn(&Window) -> Result<T> + Sync,
+        O: FnMut(T) -> Result<()>,
+    {
+        loop {
+            let windows = self.by_ref().take(batch).collect::<Result<Vec<Window>>>()?;
+            if windows.is_empty() {
+                return Ok(());
+            }
+            // collecting keeps the order of the batch
+            let results =
+                pool.install(|| windows.par_iter().map(&process).collect::<Result<Vec<T>>>())?;
+            for result in results {
+                output(result)?;
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for WindowStream<'_, R> {
+    type Item = Result<Window>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let window = self.next_window().transpose();
+        if !matches!(window, Some(Ok(_))) {
+            self.done = true;
+        }
+        window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FASTA: &[u8] = b">one description\nACGTA\nCGTAC\nGT\n>two\n\n>three\r\nAAAA\r\n";
+
+    #[test]
+    fn test_sequence_stream() {
+        let mut stream = SequenceStream::new(FASTA);
+
+        assert_eq!(stream.next_record().unwrap(), Some("one".into()));
+        let mut buffer = Vec::new();
+        assert_eq!(stream.read(&mut buffer, 7).unwrap(), 7);
+        assert_eq!(stream.read(&mut buffer, 7).unwrap(), 5);
+        assert_eq!(buffer, b"ACGTACGTACGT");
+
+        assert_eq!(stream.next_record().unwrap(), Some("two".into()));
+        assert_eq!(stream.read(&mut buffer, 7).unwrap(), 0);
+        // skipping the rest of a record
+        assert_eq!(stream.next_record().unwrap(), Some("three".into()));
+        assert_eq!(stream.next_record().unwrap(), None);
+    }
+
+    #[test]
+    fn test_unwrapped_stream() {
+        // a long sequence on one line is read a piece at a time
+        let fasta = format!(
+            ">one\n{}\r\n>two\n{}\n",
+            "ACGT".repeat(2500),
+            "T".repeat(10)
+        );
+        let reader = std::io::BufReader::with_capacity(16, fasta.as_bytes());
+        let mut stream = SequenceStream::new(reader);
+
+        assert_eq!(stream.next_record().unwrap(), Some("one".into()));
+        let mut buffer = Vec::new();
+        assert_eq!(stream.read(&mut buffer, 9999).unwrap(), 9999);
+        assert!(stream.line.capacity() < 100);
+        buffer.clear();
+        assert_eq!(stream.read(&mut buffer, 10).unwrap(), 1);
+        assert_eq!(buffer, b"T");
+
+        // and skipped without reading it all in
+        let reader = std::io::BufReader::with_capacity(16, fasta.as_bytes());
+        let mut stream = SequenceStream::new(reader);
+        assert_eq!(stream.next_record().unwrap(), Some("one".into()));
+        buffer.clear();
+        stream.read(&mut buffer, 5).unwrap();
+        assert_eq!(stream.next_record().unwrap(), Some("two".into()));
+        assert!(stream.line.capacity() < 100);
+        buffer.clear();
+        assert_eq!(stream.read(&mut buffer, 20).unwrap(), 10);
+        assert_eq!(stream.next_record().unwrap(), None);
+    }
+
+    #[test]
+    fn test_fastq_stream() {
+        // the second quality line starts with '@'
+        let fastq = b"@read1 description\nACGTAC\n+\nIIII!I\n@read2\nTTAGGG\n+read2\n@@@III\n";
+
+        let mut stream = SequenceStream::new(&fastq[..]);
+        assert_eq!(stream.next_record().unwrap(), Some("read1".into()));
+        let mut buffer = Vec::new();
+        assert_eq!(stream.read(&mut buffer, 10).unwrap(), 6);
+        assert_eq!(buffer, b"ACGTAC");
+        assert_eq!(stream.next_record().unwrap(), Some("read2".into()));
+        assert_eq!(stream.next_record().unwrap(), None);
+
+        // Phred 0 for '!', 31 for '@' and 40 for 'I'
+        let mut stream = SequenceStream::new(&fastq[..]).with_min_quality(Some(35));
+        let mut reads = Vec::new();
+        while stream.next_record().unwrap().is_some() {
+            let mut buffer = Vec::new();
+            stream.read(&mut buffer, 10).unwrap();
+            reads.push(buffer);
+        }
+        assert_eq!(reads, vec![b"ACGTNC".to_vec(), b"NNNGGG".to_vec()]);
+
+        let truncated = b"@read1\nACGT\n+\nII\n";
+        let mut stream = SequenceStream::new(&truncated[..]);
+        assert!(stream.next_record().is_err());
+
+        // wrapped sequence and qualities, a quality line starting with '@'
+        let wrapped = b"@read1\nACG\nTAC\n+\nIII\n@!I\n@read2\nTT\n+\nII\n";
+        let mut stream = SequenceStream::new(&wrapped[..]).with_min_quality(Some(35));
+        let mut reads = Vec::new();
+        while let Some(id) = stream.next_record().unwrap() {
+            let mut buffer = Vec::new();
+            stream.read(&mut buffer, 10).unwrap();
+            reads.push((id, buffer));
+        }
+        assert_eq!(
+            reads,
+            vec![
+                ("read1".into(), b"ACGNNC".to_vec()),
+                ("read2".into(), b"TT".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_region_stream() {
+        // a region starting 100 bases along chr1
+        let regions = b">chr1\t100\nACGTACGTAC\n>chr2\t0\nACGT\n";
+        let mut stream = SequenceStream::new(&regions[..]).with_regions(true);
+
+        assert_eq!(stream.next_record().unwrap(), Some("chr1".into()));
+        assert_eq!(stream.offset(), 100);
+        let windows: Vec<(usize, usize, usize)> =
+            WindowStream::new(&mut stream, WindowOptions::new(4, 4), 2)
+                .map(|e| e.map(|w| (w.context_start, w.start, w.end)))
+                .collect::<Result<_>>()
+                .unwrap();
+        assert_eq!(
+            windows,
+            vec![(100, 100, 104), (102, 104, 108), (106, 108, 110)]
+        );
+
+        assert_eq!(stream.next_record().unwrap(), Some("chr2".into()));
+        assert_eq!(stream.offset(), 0);
+    }
+
+    #[test]
+    fn test_window_stream() {
+        for (window_size, step) in [(5, 5), (5, 2), (3, 4), (20, 20), (1, 5)] {
+            let mut stream = SequenceStream::new(FASTA);
+            stream.next_record().unwrap();
+
+            let windows: Vec<Window> =
+                WindowStream::new(&mut stream, WindowOptions::new(window_size, step), 2)
+                    .collect::<Result<_>>()
+                    .unwrap();
+            let coordinates: Vec<(usize, usize)> =
+                windows.iter().map(|e| (e.start, e.end)).collect();
+            let expected: Vec<(usize, usize)> =
+                WindowOptions::new(window_size, step).windows(12).collect();
+            assert_eq!(coordinates, expected);
+
+            // each window carries its context
+            for window in windows {
+                let context_end = (window.end + 2).min(12);
+                assert_eq!(window.context_start, window.start.saturating_sub(2));
+                assert_eq!(
+                    window.seq,
+                    &b"ACGTACGTACGT"[window.context_start..context_end]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_windows_in_order() {
+        let fasta = format!(">one\n{}\n", "A".repeat(40));
+        let mut stream = SequenceStream::new(fasta.as_bytes());
+        stream.next_record().unwrap();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+
+        // the windows of each batch finish in reverse order
+        let mut starts = Vec::new();
+        WindowStream::new(&mut stream, WindowOptions::new(5, 5), 0)
+            .process_in_order(
+                &pool,
+                4,
+                |window| {
+                    let delay = 5 * (40 - window.start as u64);
+                    std::thread::sleep(std::time::Duration::from_millis(delay));
+                    Ok(window.start)
+                },
+                |start| {
+                    starts.push(start);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(starts, vec![0, 5, 10, 15, 20, 25, 30, 35]);
+    }
+}
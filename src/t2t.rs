@@ -0,0 +1,519 @@
+use crate::arrays::{call_arrays, ArrayOptions, TelomericArray};
+use crate::provenance::Provenance;
+use crate::{open_sequence_reader, search, utils, InputOptions, SubCommand};
+use anyhow::{bail, Result};
+use serde_json::json;
+use std::fmt::{self, Display};
+use std::fs::{create_dir_all, File};
+use std::io::LineWriter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str;
+
+/// Whether a telomere was found at the end of a sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelomereStatus {
+    /// Enough telomeric repeat, in the expected orientation.
+    Present,
+    /// Some telomeric repeat, but too little, or in the wrong orientation.
+    Uncertain,
+    /// No telomeric arrays at all.
+    Absent,
+}
+
+impl Display for TelomereStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TelomereStatus::Present => write!(f, "present"),
+            TelomereStatus::Uncertain => write!(f, "uncertain"),
+            TelomereStatus::Absent => write!(f, "absent"),
+        }
+    }
+}
+
+/// Which strand the telomere at one end of a sequence is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    /// The expected strand: C-rich at the 5' end, G-rich at the 3' end.
+    Expected,
+    /// The telomere is on the wrong strand, suggesting an inverted
+    /// scaffold or a misassembled end.
+    Inverted,
+    /// Not enough telomeric repeat on either strand to tell.
+    Unknown,
+}
+
+impl Display for Orientation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Orientation::Expected => write!(f, "expected"),
+            Orientation::Inverted => write!(f, "inverted"),
+            Orientation::Unknown => write!(f, "NA"),
+        }
+    }
+}
+
+/// The telomere call at one end of a sequence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EndReport {
+    /// The call itself.
+    pub status: TelomereStatus,
+    /// The proportion of the end region made of telomeric repeat
+    /// in the expected orientation.
+    pub density: f64,
+    /// As above, in the opposite orientation.
+    pub opposite_density: f64,
+    /// Which strand the telomere is on.
+    pub orientation: Orientation,
+    /// The distance from the end of the sequence (its last base which
+    /// is not in a gap) to the nearest array in the expected
+    /// orientation, if there is one.
+    pub distance: Option<usize>,
+    /// The length of the gap (run of `N`s) at the very end of the
+    /// sequence, if any.
+    pub gap: usize,
+}
+
+/// The telomere calls at both ends of a sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct T2TReport {
+    /// The sequence ID.
+    pub id: String,
+    /// The length of the sequence.
+    pub length: usize,
+    /// The start of the sequence, expected to carry the C-rich strand.
+    pub five_prime: EndReport,
+    /// The end of the sequence, expected to carry the G-rich strand.
+    pub three_prime: EndReport,
+}
+
+impl T2TReport {
+    /// Telomere-to-telomere, i.e. a telomere at both ends.
+    pub fn is_t2t(&self) -> bool {
+        self.five_prime.status == TelomereStatus::Present
+            && self.three_prime.status == TelomereStatus::Present
+    }
+
+    /// Flag telomeres on the wrong strand. If both ends are inverted
+    /// the whole sequence is likely reverse complemented, otherwise
+    /// a single inverted end points to a misassembly.
+    pub fn orientation_flag(&self) -> &'static str {
+        match (self.five_prime.orientation, self.three_prime.orientation) {
+            (Orientation::Inverted, Orientation::Inverted) => "reverse_complemented",
+            (Orientation::Inverted, _) | (_, Orientation::Inverted) => "inverted_end",
+            _ => "none",
+        }
+    }
+}
+
+/// Options for calling telomeres at the ends of sequences.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct T2TOptions {
+    /// How far in from each end (bp) to look for a telomere.
+    pub end_length: usize,
+    /// The density of telomeric repeat needed to call a telomere.
+    pub min_density: f64,
+    /// The shortest run of `N`s reported as an assembly gap.
+    pub min_gap_length: usize,
+}
+
+impl T2TOptions {
+    /// Parse the options from the command line.
+    pub fn from_matches(matches: &clap::ArgMatches) -> Result<Self> {
+        let end_length = *matches
+            .get_one::<usize>("end_length")
+            .expect("defaulted by clap");
+        let min_density = *matches
+            .get_one::<f64>("min_density")
+            .expect("defaulted by clap");
+
+        let min_gap_length = *matches
+            .get_one::<usize>("min_gap")
+            .expect("defaulted by clap");
+
+        if end_length == 0 {
+            bail!("The end length must be greater than zero.");
+        }
+        if !(0.0..=1.0).contains(&min_density) || min_density == 0.0 {
+            bail!("The minimum density must be greater than 0, and at most 1.");
+        }
+
+        if min_gap_length == 0 {
+            bail!("The minimum gap length must be greater than zero.");
+        }
+
+        Ok(Self {
+            end_length,
+            min_density,
+            min_gap_length,
+        })
+    }
+}
+
+/// The entry point for `tidk t2t`.
+pub fn t2t(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
+    let mut provenance = Provenance::new("t2t", matches);
+    let input_fasta = matches
+        .get_one::<PathBuf>("fasta")
+        .expect("errored by clap");
+    let reader = open_sequence_reader(input_fasta, &InputOptions::default())?;
+
+    let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+    provenance.set_motifs(&telomeric_repeats);
+    eprintln!(
+        "[+]\tCalling telomeres at sequence ends with: {}",
+        telomeric_repeats.join(", ")
+    );
+
+    let array_options = ArrayOptions::from_matches(matches);
+    if let Some(d) = array_options.distance {
+        d.check(&telomeric_repeats)?;
+    }
+    let options = T2TOptions::from_matches(matches)?;
+
+    let outdir = matches.get_one::<PathBuf>("dir").expect("errored by clap");
+    let output = matches
+        .get_one::<PathBuf>("output")
+        .expect("errored by clap");
+
+    // create directory for output
+    create_dir_all(outdir)?;
+
+    // create files
+    let file_name = format!("{}/{}{}", outdir.display(), output.display(), "_t2t.tsv");
+    let t2t_file = File::create(file_name)?;
+    let mut t2t_file = LineWriter::new(t2t_file);
+    writeln!(
+        t2t_file,
+        "id\tlength\tfive_prime_status\tfive_prime_density\tfive_prime_orientation\tfive_prime_distance\tthree_prime_status\tthree_prime_density\tthree_prime_orientation\tthree_prime_distance\tt2t\torientation_flag\tfive_prime_gap\tthree_prime_gap"
+    )?;
+    let gaps_file_name = format!("{}/{}{}", outdir.display(), output.display(), "_gaps.bed");
+    let gaps_file = File::create(gaps_file_name)?;
+    let mut gaps_file = LineWriter::new(gaps_file);
+
+    let mut reports = Vec::new();
+    // iterate over the fasta records
+    for result in reader.records() {
+        let record = result?;
+        let id = record.id().to_owned();
+        let sequence = str::from_utf8(record.seq())?.to_uppercase();
+
+        let arrays = call_arrays(&id, &sequence, &telomeric_repeats, &array_options);
+        let report = classify_ends(&id, sequence.as_bytes(), &arrays, &options);
+
+        writeln!(
+            t2t_file,
+            "{}\t{}\t{}\t{:.3}\t{}\t{}\t{}\t{:.3}\t{}\t{}\t{}\t{}\t{}\t{}",
+            report.id,
+            report.length,
+            report.five_prime.status,
+            report.five_prime.density,
+            report.five_prime.orientation,
+            format_distance(report.five_prime.distance),
+            report.three_prime.status,
+            report.three_prime.density,
+            report.three_prime.orientation,
+            format_distance(report.three_prime.distance),
+            if report.is_t2t() { "yes" } else { "no" },
+            report.orientation_flag(),
+            report.five_prime.gap,
+            report.three_prime.gap
+        )?;
+        for (start, end) in utils::find_gaps(sequence.as_bytes(), options.min_gap_length) {
+            writeln!(gaps_file, "{id}\t{start}\t{end}")?;
+        }
+
+        eprintln!(
+            "[+]\tChromosome {id} processed: 5' telomere {}, 3' telomere {}",
+            report.five_prime.status, report.three_prime.status
+        );
+        for (end, end_report) in [("5'", report.five_prime), ("3'", report.three_prime)] {
+            if end_report.orientation == Orientation::Inverted {
+                eprintln!("[-]\t\tChromosome {id}: the {end} telomere is on the wrong strand.");
+            }
+        }
+        provenance.add_record(json!({
+            "id": id,
+            "length": report.length,
+            "arrays": arrays.len(),
+            "five_prime": report.five_prime.status.to_string(),
+            "three_prime": report.three_prime.status.to_string(),
+            "t2t": report.is_t2t(),
+        }));
+        reports.push(report);
+    }
+
+    // the genome level summary
+    let summary = summarise(&reports);
+    let summary_file_name = format!(
+        "{}/{}{}",
+        outdir.display(),
+        output.display(),
+        "_t2t_summary.tsv"
+    );
+    let mut summary_file = File::create(&summary_file_name)?;
+    write!(summary_file, "{summary}")?;
+
+    eprintln!("[+]\tFinished calling telomeres.");
+    for line in summary.lines() {
+        eprintln!("[+]\t\t{line}");
+    }
+
+    // optional log file
+    sc.log(matches)?;
+    provenance.write()?;
+
+    Ok(())
+}
+
+/// Classify the telomeres at either end of a sequence, from the
+/// telomeric arrays called on it. The 5' end is expected to carry the
+/// C-rich strand and the 3' end the G-rich strand. Scaffolds often
+/// start or end in a gap, so the ends are measured from the first and
+/// last bases which are not `N`. The end regions are shortened for
+/// sequences too short to fit both.
+pub fn classify_ends(
+    id: &str,
+    sequence: &[u8],
+    arrays: &[TelomericArray],
+    options: &T2TOptions,
+) -> T2TReport {
+    let (first, last) = utils::non_gap_span(sequence);
+    let end_length = options.end_length.min((last - first) / 2).max(1);
+
+    let mut five_prime = classify_end(
+        arrays,
+        (first, first + end_length),
+        false,
+        |a: &TelomericArray| a.start.saturating_sub(first),
+        options,
+    );
+    five_prime.gap = first;
+    let mut three_prime = classify_end(
+        arrays,
+        (last.saturating_sub(end_length), last),
+        true,
+        |a: &TelomericArray| last.saturating_sub(a.end),
+        options,
+    );
+    three_prime.gap = sequence.len() - last;
+
+    T2TReport {
+        id: id.to_string(),
+        length: sequence.len(),
+        five_prime,
+        three_prime,
+    }
+}
+
+/// Classify one end region, given the expected orientation (G-rich
+/// or not) and how to measure an array's distance from the end.
+fn classify_end<F: Fn(&TelomericArray) -> usize>(
+    arrays: &[TelomericArray],
+    (start, end): (usize, usize),
+    expect_g_rich: bool,
+    distance_from_end: F,
+    options: &T2TOptions,
+) -> EndReport {
+    let region_length = (end - start) as f64;
+
+    let mut expected_bases = 0.0;
+    let mut opposite_bases = 0.0;
+    let mut distance: Option<usize> = None;
+
+    for array in arrays {
+        let bases = array.repeat_bases_within(start, end);
+        if bases == 0.0 {
+            continue;
+        }
+        if array.is_g_rich() == expect_g_rich {
+            expected_bases += bases;
+            let d = distance_from_end(array);
+            distance = Some(distance.map_or(d, |e| e.min(d)));
+        } else {
+            opposite_bases += bases;
+        }
+    }
+
+    // the end region of an all gap sequence is empty, with no repeat
+    let density_of = |bases: f64| {
+        if region_length > 0.0 {
+            bases / region_length
+        } else {
+            0.0
+        }
+    };
+    let density = density_of(expected_bases);
+    let opposite_density = density_of(opposite_bases);
+
+    // only judge the orientation if there is a telomere's worth
+    // of repeat on either strand
+    let orientation = if opposite_density >= options.min_density && opposite_density > density {
+        Orientation::Inverted
+    } else if density >= options.min_density {
+        Orientation::Expected
+    } else {
+        Orientation::Unknown
+    };
+
+    let status = if density >= options.min_density {
+        TelomereStatus::Present
+    } else if expected_bases > 0.0 || opposite_bases > 0.0 {
+        TelomereStatus::Uncertain
+    } else {
+        TelomereStatus::Absent
+    };
+
+    EndReport {
+        status,
+        density,
+        opposite_density,
+        orientation,
+        distance,
+        gap: 0,
+    }
+}
+
+/// Summarise the telomere calls across the genome, as a
+/// two column TSV.
+fn summarise(reports: &[T2TReport]) -> String {
+    let ends = || reports.iter().flat_map(|r| [r.five_prime, r.three_prime]);
+    let count_ends = |status| ends().filter(|e| e.status == status).count();
+
+    let telomeres_found = count_ends(TelomereStatus::Present);
+    let ends_inverted = ends()
+        .filter(|e| e.orientation == Orientation::Inverted)
+        .count();
+
+    format!(
+        "sequences\t{}\nt2t_sequences\t{}\ntelomeres_found\t{}\nends_missing_telomere\t{}\nends_uncertain\t{}\nends_inverted\t{}\nsequences_reverse_complemented\t{}\n",
+        reports.len(),
+        reports.iter().filter(|r| r.is_t2t()).count(),
+        telomeres_found,
+        count_ends(TelomereStatus::Absent),
+        count_ends(TelomereStatus::Uncertain),
+        ends_inverted,
+        reports
+            .iter()
+            .filter(|r| r.orientation_flag() == "reverse_complemented")
+            .count(),
+    )
+}
+
+/// Format an optional distance for the TSV.
+fn format_distance(distance: Option<usize>) -> String {
+    match distance {
+        Some(d) => d.to_string(),
+        None => "NA".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(five_prime: &str, three_prime: &str) -> String {
+        format!(
+            "{}{}{}",
+            five_prime.repeat(20),
+            "GCAT".repeat(100),
+            three_prime.repeat(20)
+        )
+    }
+
+    fn report(sequence: &str) -> T2TReport {
+        let array_options = ArrayOptions::new(10, 30);
+        let options = T2TOptions {
+            end_length: 200,
+            min_density: 0.3,
+            min_gap_length: 10,
+        };
+        let arrays = call_arrays("test", sequence, &["TTAGGG".into()], &array_options);
+        classify_ends("test", sequence.as_bytes(), &arrays, &options)
+    }
+
+    #[test]
+    fn test_t2t() {
+        let report = report(&sequence("CCCTAA", "TTAGGG"));
+        assert_eq!(report.five_prime.status, TelomereStatus::Present);
+        assert_eq!(report.three_prime.status, TelomereStatus::Present);
+        assert_eq!(report.three_prime.distance, Some(0));
+        assert!(report.is_t2t());
+    }
+
+    #[test]
+    fn test_wrong_orientation() {
+        // both ends carry the G-rich strand
+        let report = report(&sequence("TTAGGG", "TTAGGG"));
+        assert_eq!(report.five_prime.status, TelomereStatus::Uncertain);
+        assert!(report.five_prime.opposite_density > 0.5);
+        assert_eq!(report.five_prime.orientation, Orientation::Inverted);
+        assert_eq!(report.three_prime.status, TelomereStatus::Present);
+        assert_eq!(report.three_prime.orientation, Orientation::Expected);
+        assert_eq!(report.orientation_flag(), "inverted_end");
+        assert!(!report.is_t2t());
+    }
+
+    #[test]
+    fn test_reverse_complemented() {
+        let report = report(&sequence("TTAGGG", "CCCTAA"));
+        assert_eq!(report.five_prime.orientation, Orientation::Inverted);
+        assert_eq!(report.three_prime.orientation, Orientation::Inverted);
+        assert_eq!(report.orientation_flag(), "reverse_complemented");
+    }
+
+    #[test]
+    fn test_absent() {
+        let report = report(&sequence("GCAT", "TTAGGG"));
+        assert_eq!(report.five_prime.status, TelomereStatus::Absent);
+        assert_eq!(report.five_prime.distance, None);
+        assert_eq!(report.five_prime.orientation, Orientation::Unknown);
+    }
+
+    #[test]
+    fn test_terminal_gaps() {
+        // the 3' telomere sits just before a gap, and would be missed
+        // if measured from the end of the sequence
+        let sequence = format!(
+            "{}{}{}",
+            "N".repeat(50),
+            sequence("CCCTAA", "TTAGGG"),
+            "N".repeat(300)
+        );
+        let report = report(&sequence);
+        assert_eq!(report.five_prime.status, TelomereStatus::Present);
+        assert_eq!(report.five_prime.distance, Some(0));
+        assert_eq!(report.five_prime.gap, 50);
+        assert_eq!(report.three_prime.status, TelomereStatus::Present);
+        assert_eq!(report.three_prime.distance, Some(0));
+        assert_eq!(report.three_prime.gap, 300);
+        assert!(report.is_t2t());
+    }
+
+    #[test]
+    fn test_all_gap() {
+        let report = report(&"N".repeat(500));
+        for end in [report.five_prime, report.three_prime] {
+            assert_eq!(end.status, TelomereStatus::Absent);
+            assert_eq!(end.density, 0.0);
+            assert_eq!(end.opposite_density, 0.0);
+            assert_eq!(end.orientation, Orientation::Unknown);
+        }
+        assert!(!report.is_t2t());
+    }
+
+    #[test]
+    fn test_summary() {
+        let reports = vec![
+            report(&sequence("CCCTAA", "TTAGGG")),
+            report(&sequence("GCAT", "TTAGGG")),
+            // the 5' telomere is on the wrong strand, so uncertain
+            // rather than missing
+            report(&sequence("TTAGGG", "TTAGGG")),
+        ];
+        assert_eq!(
+            summarise(&reports),
+            "sequences\t3\nt2t_sequences\t1\ntelomeres_found\t4\nends_missing_telomere\t1\nends_uncertain\t1\nends_inverted\t1\nsequences_reverse_complemented\t0\n"
+        );
+    }
+}
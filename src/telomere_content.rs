@@ -0,0 +1,496 @@
+use crate::provenance::Provenance;
+use crate::{open_sequence_reader, search, utils, InputOptions, SubCommand};
+use anyhow::{bail, Context, Result};
+use bio::io::fasta;
+use rayon::prelude::*;
+use serde_json::json;
+use std::fs::{create_dir_all, File};
+use std::io::LineWriter;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The number of reads given to each thread at a time.
+const READS_PER_THREAD: usize = 10000;
+
+/// Reads within this much of the GC content of the telomeric repeat
+/// make up the GC-matched background, as in TelSeq (48-52% for TTAGGG).
+const GC_TOLERANCE: f64 = 0.02;
+
+/// The length (bp) of the windows a reference is split into to find its
+/// GC-matched part, about that of a short read.
+const GC_WINDOW: usize = 100;
+
+/// A sample, and its (single or paired) read files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub name: String,
+    pub reads: Vec<PathBuf>,
+}
+
+impl Sample {
+    /// The samples from the command line: either the read files given
+    /// directly, or a sample sheet.
+    pub fn from_matches(matches: &clap::ArgMatches) -> Result<Vec<Self>> {
+        if let Some(sheet) = matches.get_one::<PathBuf>("samples") {
+            return read_sample_sheet(sheet);
+        }
+
+        let reads: Vec<PathBuf> = matches
+            .get_many::<PathBuf>("reads")
+            .expect("required unless samples is present")
+            .cloned()
+            .collect();
+        let name = match matches.get_one::<String>("sample") {
+            Some(name) => name.clone(),
+            None => sample_name(&reads[0]),
+        };
+        Ok(vec![Self { name, reads }])
+    }
+}
+
+/// Read a sample sheet: a TSV of sample name, the first read file and
+/// optionally the second read file, one sample per line.
+fn read_sample_sheet(path: &Path) -> Result<Vec<Sample>> {
+    let sheet = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read the sample sheet {}", path.display()))?;
+
+    let mut samples = Vec::new();
+    for line in sheet.lines() {
+        let fields: Vec<&str> = line.split('\t').filter(|e| !e.is_empty()).collect();
+        match fields.as_slice() {
+            [] => continue,
+            [name, reads @ ..] if (1..=2).contains(&reads.len()) => samples.push(Sample {
+                name: name.to_string(),
+                reads: reads.iter().map(PathBuf::from).collect(),
+            }),
+            _ => bail!(
+                "Each line of the sample sheet should be a sample name and one or two read files, separated by tabs: {}",
+                line
+            ),
+        }
+    }
+    if samples.is_empty() {
+        bail!("No samples in the sample sheet {}.", path.display());
+    }
+    Ok(samples)
+}
+
+/// The name of a sample from its read file, without directories or
+/// (compression, sequence and mate) extensions.
+fn sample_name(path: &Path) -> String {
+    let mut name = path
+        .file_name()
+        .map(|e| e.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    for suffix in [".gz", ".fastq", ".fq", ".bam", ".sam", ".fasta", ".fa"] {
+        name = name.trim_end_matches(suffix).to_string();
+    }
+    for suffix in ["_R1", "_1", ".R1", ".1"] {
+        if let Some(stripped) = name.strip_suffix(suffix) {
+            return stripped.to_string();
+        }
+    }
+    name
+}
+
+/// Options for estimating telomere content from short reads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentOptions {
+    /// A read is telomeric if it has at least this many tandem copies
+    /// of the telomeric repeat.
+    pub copies: usize,
+    /// The reads with GC content in this range make up the background.
+    pub gc_range: (f64, f64),
+    /// The size of the genome (bp) with GC content in the background
+    /// range.
+    pub gc_genome_size: u64,
+    /// The number of chromosome ends (telomeres) in the genome.
+    pub chromosome_ends: u64,
+}
+
+impl ContentOptions {
+    /// Parse the options from the command line, matching the
+    /// background to the GC content of the telomeric repeat. The
+    /// GC-matched genome size is either given, or found by scanning
+    /// the reference given.
+    pub fn from_matches(matches: &clap::ArgMatches, telomeric_repeat: &str) -> Result<Self> {
+        let copies = *matches
+            .get_one::<usize>("copies")
+            .expect("defaulted by clap");
+        let chromosome_ends = *matches
+            .get_one::<u64>("chromosome_ends")
+            .expect("errored by clap");
+        if copies == 0 || chromosome_ends == 0 {
+            bail!("The number of copies and number of chromosome ends must be greater than zero.");
+        }
+
+        let gc_range = gc_range(telomeric_repeat);
+        let gc_genome_size = match matches.get_one::<u64>("gc_genome_size") {
+            Some(size) => *size,
+            None => {
+                let reference = matches
+                    .get_one::<PathBuf>("gc_reference")
+                    .expect("required by clap unless the GC-matched genome size is given");
+                eprintln!(
+                    "[+]\tFinding the GC-matched genome size of {}",
+                    reference.display()
+                );
+                gc_matched_size(reference, gc_range)?
+            }
+        };
+        if gc_genome_size == 0 {
+            bail!("The GC-matched genome size must be greater than zero.");
+        }
+
+        Ok(Self {
+            copies,
+            gc_range,
+            gc_genome_size,
+            chromosome_ends,
+        })
+    }
+}
+
+/// The reads with GC content in this range make up the background for
+/// a telomeric repeat.
+pub fn gc_range(telomeric_repeat: &str) -> (f64, f64) {
+    let gc = gc_content(telomeric_repeat.as_bytes());
+    (gc - GC_TOLERANCE, gc + GC_TOLERANCE)
+}
+
+/// The size of the GC-matched part of a genome: the total length of
+/// the windows of its sequences (without `N`s) whose GC content is in
+/// the range.
+fn gc_matched_size(reference: &Path, gc_range: (f64, f64)) -> Result<u64> {
+    let reader = open_sequence_reader(reference, &InputOptions::default())?;
+    let mut size = 0;
+    for record in reader.records() {
+        let record = record?;
+        for window in record.seq().chunks_exact(GC_WINDOW) {
+            if window.iter().any(|e| matches!(e, b'N' | b'n')) {
+                continue;
+            }
+            let gc = gc_content(window);
+            if gc >= gc_range.0 && gc <= gc_range.1 {
+                size += GC_WINDOW as u64;
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// The read counts of a sample.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ContentCounts {
+    /// All reads.
+    pub reads: usize,
+    /// The reads with at least k tandem copies of the telomeric repeat.
+    pub telomeric_reads: usize,
+    /// The reads with GC content matching the telomeric repeat.
+    pub gc_reads: usize,
+}
+
+impl ContentCounts {
+    /// Count a read.
+    pub fn add(&mut self, seq: &[u8], telomeric_repeats: &[String], options: &ContentOptions) {
+        self.reads += 1;
+        if is_telomeric(seq, telomeric_repeats, options.copies) {
+            self.telomeric_reads += 1;
+        }
+        let gc = gc_content(seq);
+        if gc >= options.gc_range.0 && gc <= options.gc_range.1 {
+            self.gc_reads += 1;
+        }
+    }
+
+    /// Add up the counts of two sets of reads.
+    pub fn merge(mut self, other: Self) -> Self {
+        self.reads += other.reads;
+        self.telomeric_reads += other.telomeric_reads;
+        self.gc_reads += other.gc_reads;
+        self
+    }
+
+    /// Telomeric reads per million reads.
+    pub fn telomeric_per_million(&self) -> f64 {
+        if self.reads == 0 {
+            return 0.0;
+        }
+        self.telomeric_reads as f64 * 1e6 / self.reads as f64
+    }
+
+    /// The average telomere length (kb) per chromosome end, as in
+    /// TelSeq. The GC-matched reads per bp of GC-matched genome give
+    /// the coverage expected of the telomeres, so the telomeric reads
+    /// divided by this is the total length of the telomeres.
+    pub fn length_estimate(&self, options: &ContentOptions) -> Option<f64> {
+        if self.gc_reads == 0 {
+            return None;
+        }
+        let coverage = self.gc_reads as f64 / options.gc_genome_size as f64;
+        Some(self.telomeric_reads as f64 / coverage / options.chromosome_ends as f64 / 1000.0)
+    }
+}
+
+/// The entry point for `tidk telomere-content`.
+pub fn telomere_content(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
+    let mut provenance = Provenance::new("telomere-content", matches);
+    let samples = Sample::from_matches(matches)?;
+    let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+    provenance.set_motifs(&telomeric_repeats);
+    eprintln!(
+        "[+]\tEstimating telomere content of {} sample(s) with: {}",
+        samples.len(),
+        telomeric_repeats.join(", ")
+    );
+
+    let options = ContentOptions::from_matches(matches, &telomeric_repeats[0])?;
+
+    let threads = *matches
+        .get_one::<usize>("threads")
+        .expect("defaulted by clap");
+    if threads == 0 {
+        bail!("The number of threads must be greater than zero.");
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+
+    let outdir = matches.get_one::<PathBuf>("dir").expect("errored by clap");
+    let output = matches
+        .get_one::<PathBuf>("output")
+        .expect("errored by clap");
+
+    // create directory for output
+    create_dir_all(outdir)?;
+
+    // create file
+    let file_name = format!(
+        "{}/{}{}",
+        outdir.display(),
+        output.display(),
+        "_telomere_content.tsv"
+    );
+    let content_file = File::create(file_name)?;
+    let mut content_file = LineWriter::new(content_file);
+    writeln!(
+        content_file,
+        "sample\treads\ttelomeric_reads\tgc_matched_reads\ttelomeric_reads_per_million\tgc_matched_genome_size\tlength_estimate_kb"
+    )?;
+
+    for sample in &samples {
+        let mut counts = Vec::new();
+        for path in &sample.reads {
+            counts.push(count_reads(path, &telomeric_repeats, &options, &pool)?);
+        }
+        if let [first, second] = counts.as_slice() {
+            if first.reads != second.reads {
+                eprintln!(
+                    "[-]\t\tSample {}: the paired read files have different numbers of reads ({} and {}).",
+                    sample.name, first.reads, second.reads
+                );
+            }
+        }
+        let counts = counts
+            .into_iter()
+            .fold(ContentCounts::default(), ContentCounts::merge);
+
+        let length_estimate = counts.length_estimate(&options);
+        writeln!(
+            content_file,
+            "{}\t{}\t{}\t{}\t{:.3}\t{}\t{}",
+            sample.name,
+            counts.reads,
+            counts.telomeric_reads,
+            counts.gc_reads,
+            counts.telomeric_per_million(),
+            options.gc_genome_size,
+            match length_estimate {
+                Some(l) => format!("{l:.3}"),
+                None => "NA".into(),
+            }
+        )?;
+
+        // each sample is a record
+        provenance.add_record(json!({
+            "id": sample.name,
+            "reads": counts.reads,
+            "telomeric_reads": counts.telomeric_reads,
+            "gc_matched_reads": counts.gc_reads,
+        }));
+
+        eprintln!(
+            "[+]\tSample {} processed: {} telomeric read(s) of {}",
+            sample.name, counts.telomeric_reads, counts.reads
+        );
+    }
+    eprintln!("[+]\tFinished estimating telomere content.");
+
+    // optional log file
+    sc.log(matches)?;
+    provenance.write()?;
+
+    Ok(())
+}
+
+/// Count the reads of a file, in parallel on `pool`.
+fn count_reads(
+    path: &Path,
+    telomeric_repeats: &[String],
+    options: &ContentOptions,
+    pool: &rayon::ThreadPool,
+) -> Result<ContentCounts> {
+    let reader = open_sequence_reader(path, &InputOptions::default())?;
+    let mut records = reader.records();
+
+    let mut counts = ContentCounts::default();
+    loop {
+        let batch = records
+            .by_ref()
+            .take(pool.current_num_threads() * READS_PER_THREAD)
+            .collect::<std::io::Result<Vec<fasta::Record>>>()?;
+        if batch.is_empty() {
+            return Ok(counts);
+        }
+        let batch_counts = pool.install(|| {
+            batch
+                .par_iter()
+                .fold(ContentCounts::default, |mut counts, record| {
+                    counts.add(
+                        &record.seq().to_ascii_uppercase(),
+                        telomeric_repeats,
+                        options,
+                    );
+                    counts
+                })
+                .reduce(ContentCounts::default, ContentCounts::merge)
+        });
+        counts = counts.merge(batch_counts);
+    }
+}
+
+/// Whether an (uppercase) read has at least `copies` tandem copies of
+/// any of the telomeric repeats, on either strand.
+pub fn is_telomeric(seq: &[u8], telomeric_repeats: &[String], copies: usize) -> bool {
+    // a quick check before searching
+    let Some(shortest) = telomeric_repeats.iter().map(|e| e.len()).min() else {
+        return false;
+    };
+    if seq.len() < shortest * copies {
+        return false;
+    }
+
+    let seq = String::from_utf8_lossy(seq);
+    telomeric_repeats.iter().any(|telomeric_repeat| {
+        [
+            telomeric_repeat.clone(),
+            utils::reverse_complement(telomeric_repeat),
+        ]
+        .iter()
+        .any(|motif| {
+            let hits = utils::find_motifs(motif, &seq).indexes;
+            tandem_copies(&hits, motif.len()) >= copies
+        })
+    })
+}
+
+/// The largest number of motif hits (sorted start positions) which
+/// follow on directly from one another.
+pub fn tandem_copies(hits: &[usize], motif_length: usize) -> usize {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous: Option<usize> = None;
+    for &hit in hits {
+        run = match previous {
+            Some(p) if hit == p + motif_length => run + 1,
+            // overlapping hits (e.g. of a motif like AAAA) don't add a copy
+            Some(p) if hit < p + motif_length => continue,
+            _ => 1,
+        };
+        previous = Some(hit);
+        longest = longest.max(run);
+    }
+    longest
+}
+
+/// The proportion of G and C bases in a sequence.
+fn gc_content(seq: &[u8]) -> f64 {
+    if seq.is_empty() {
+        return 0.0;
+    }
+    let gc = seq
+        .iter()
+        .filter(|e| matches!(e, b'G' | b'C' | b'g' | b'c'))
+        .count();
+    gc as f64 / seq.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: ContentOptions = ContentOptions {
+        copies: 4,
+        gc_range: (0.48, 0.52),
+        gc_genome_size: 500_000,
+        chromosome_ends: 10,
+    };
+
+    #[test]
+    fn test_tandem_copies() {
+        assert_eq!(tandem_copies(&[0, 6, 12, 30, 36], 6), 3);
+        assert_eq!(tandem_copies(&[0, 1, 2, 3, 4], 1), 5);
+        // overlapping hits
+        assert_eq!(tandem_copies(&[0, 2, 4, 6], 4), 2);
+        assert_eq!(tandem_copies(&[], 6), 0);
+    }
+
+    #[test]
+    fn test_content_counts() {
+        let repeats = vec!["TTAGGG".to_string()];
+        let mut counts = ContentCounts::default();
+        // four tandem copies on the reverse strand, and 50% GC
+        counts.add(b"CCCTAACCCTAACCCTAACCCTAAGT", &repeats, &OPTIONS);
+        // four copies, but not in tandem
+        counts.add(b"TTAGGGTTAGGGAAAATTAGGGTTAGGG", &repeats, &OPTIONS);
+        counts.add(b"ATATATATAT", &repeats, &OPTIONS);
+        counts.add(b"GCGCATAT", &repeats, &OPTIONS);
+
+        assert_eq!(counts.reads, 4);
+        assert_eq!(counts.telomeric_reads, 1);
+        assert_eq!(counts.gc_reads, 2);
+
+        // 1 telomeric read / (2 reads / 500kb) / 10 ends = 25 kb
+        assert!((counts.length_estimate(&OPTIONS).unwrap() - 25.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_gc_matched_size() {
+        let gc_range = gc_range("TTAGGG");
+        assert!((gc_range.0 - 0.48).abs() < 1e-9 && (gc_range.1 - 0.52).abs() < 1e-9);
+
+        // a window of 50% GC, one of 0% GC, one with an N, and a
+        // partial window, which are all but the first left out
+        let matched = "GCAT".repeat(25);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gc_reference.fa");
+        std::fs::write(
+            &path,
+            format!(
+                ">chr1\n{matched}{}\n>chr2\nN{}{}\n",
+                "A".repeat(100),
+                &matched[1..],
+                &matched[..50]
+            ),
+        )
+        .unwrap();
+        assert_eq!(gc_matched_size(&path, gc_range).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_sample_name() {
+        assert_eq!(sample_name(Path::new("data/ind1_R1.fastq.gz")), "ind1");
+        assert_eq!(sample_name(Path::new("ind2.fq")), "ind2");
+        assert_eq!(sample_name(Path::new("ind3.bam")), "ind3");
+    }
+}
@@ -0,0 +1,423 @@
+use crate::arrays::{call_arrays, ArrayOptions, TelomericArray};
+use crate::provenance::Provenance;
+use crate::{open_sequence_reader, search, InputOptions, SubCommand};
+use anyhow::{bail, Result};
+use bio::io::fasta;
+use rayon::prelude::*;
+use serde_json::json;
+use std::fmt::{self, Display};
+use std::fs::{create_dir_all, File};
+use std::io::LineWriter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str;
+
+/// The number of reads given to each thread at a time.
+const READS_PER_THREAD: usize = 1000;
+
+/// Which end of a read a telomeric array is at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadEnd {
+    /// The start of the read.
+    FivePrime,
+    /// The end of the read.
+    ThreePrime,
+}
+
+impl Display for ReadEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadEnd::FivePrime => write!(f, "five_prime"),
+            ReadEnd::ThreePrime => write!(f, "three_prime"),
+        }
+    }
+}
+
+/// A telomeric array at the end of a read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadTelomere {
+    /// The length of the read.
+    pub read_length: usize,
+    /// Which end of the read the array is at.
+    pub end: ReadEnd,
+    /// The array itself.
+    pub array: TelomericArray,
+}
+
+/// Options for estimating telomere length from reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TelomereLengthOptions {
+    /// An array must come within this distance (bp) of a read end
+    /// to be a telomere.
+    pub end_distance: usize,
+    /// The width (bp) of the bins of the length histogram.
+    pub bin_size: usize,
+}
+
+impl TelomereLengthOptions {
+    /// Parse the options from the command line.
+    pub fn from_matches(matches: &clap::ArgMatches) -> Result<Self> {
+        let bin_size = *matches
+            .get_one::<usize>("bin_size")
+            .expect("defaulted by clap");
+        if bin_size == 0 {
+            bail!("The bin size must be greater than zero.");
+        }
+
+        Ok(Self {
+            end_distance: *matches
+                .get_one::<usize>("end_distance")
+                .expect("defaulted by clap"),
+            bin_size,
+        })
+    }
+}
+
+/// The entry point for `tidk telomere-length`.
+pub fn telomere_length(matches: &clap::ArgMatches, sc: SubCommand) -> Result<()> {
+    let mut provenance = Provenance::new("telomere-length", matches);
+    let input_fasta = matches
+        .get_one::<PathBuf>("fasta")
+        .expect("errored by clap");
+    let input_options = InputOptions::from_matches(matches)?;
+    let reader = open_sequence_reader(input_fasta, &input_options)?;
+
+    let telomeric_repeats = search::get_telomeric_repeats(matches)?;
+    provenance.set_motifs(&telomeric_repeats);
+    eprintln!(
+        "[+]\tEstimating telomere length from reads with: {}",
+        telomeric_repeats.join(", ")
+    );
+
+    let array_options = ArrayOptions::from_matches(matches);
+    if let Some(d) = array_options.distance {
+        d.check(&telomeric_repeats)?;
+    }
+    let options = TelomereLengthOptions::from_matches(matches)?;
+
+    let threads = *matches
+        .get_one::<usize>("threads")
+        .expect("defaulted by clap");
+    if threads == 0 {
+        bail!("The number of threads must be greater than zero.");
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+
+    let outdir = matches.get_one::<PathBuf>("dir").expect("errored by clap");
+    let output = matches
+        .get_one::<PathBuf>("output")
+        .expect("errored by clap");
+
+    // create directory for output
+    create_dir_all(outdir)?;
+
+    // create file
+    let file_name = format!(
+        "{}/{}{}",
+        outdir.display(),
+        output.display(),
+        "_telomere_lengths.tsv"
+    );
+    let lengths_file = File::create(file_name)?;
+    let mut lengths_file = LineWriter::new(lengths_file);
+    writeln!(
+        lengths_file,
+        "id\tread_length\tread_end\tarray_start\tarray_end\tstrand\ttelomeric_repeat\tlength\tpurity"
+    )?;
+
+    let mut reads = 0;
+    let mut reads_with_telomere = 0;
+    let mut lengths = Vec::new();
+
+    // iterate over the reads, a batch at a time
+    let mut records = reader.records();
+    loop {
+        let batch = records
+            .by_ref()
+            .take(threads * READS_PER_THREAD)
+            .collect::<std::io::Result<Vec<fasta::Record>>>()?;
+        if batch.is_empty() {
+            break;
+        }
+        reads += batch.len();
+
+        // collecting keeps the order of the batch
+        let telomeres = pool.install(|| {
+            batch
+                .par_iter()
+                .map(|record| {
+                    let sequence = str::from_utf8(record.seq())?.to_uppercase();
+                    let arrays =
+                        call_arrays(record.id(), &sequence, &telomeric_repeats, &array_options);
+                    Ok(read_telomeres(sequence.len(), arrays, &options))
+                })
+                .collect::<Result<Vec<Vec<ReadTelomere>>>>()
+        })?;
+
+        for telomeres in telomeres {
+            if !telomeres.is_empty() {
+                reads_with_telomere += 1;
+            }
+            for telomere in telomeres {
+                let array = &telomere.array;
+                writeln!(
+                    lengths_file,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.3}",
+                    array.id,
+                    telomere.read_length,
+                    telomere.end,
+                    array.start,
+                    array.end,
+                    array.strand,
+                    array.telomeric_repeat,
+                    array.len(),
+                    array.purity
+                )?;
+                lengths.push(array.len());
+            }
+        }
+        eprintln!("[+]\t{reads} reads processed");
+    }
+
+    // a record for each read would be too many
+    provenance.set_summary(json!({
+        "reads": reads,
+        "reads_with_telomere": reads_with_telomere,
+        "telomeres": lengths.len(),
+    }));
+
+    // the read level length distribution
+    let distribution = LengthDistribution::new(lengths, options.bin_size);
+
+    let summary_file_name = format!(
+        "{}/{}{}",
+        outdir.display(),
+        output.display(),
+        "_telomere_length_summary.tsv"
+    );
+    let mut summary_file = File::create(&summary_file_name)?;
+    let summary = format!(
+        "reads\t{reads}\nreads_with_telomere\t{reads_with_telomere}\n{}",
+        distribution.summary()
+    );
+    write!(summary_file, "{summary}")?;
+
+    let histogram_file_name = format!(
+        "{}/{}{}",
+        outdir.display(),
+        output.display(),
+        "_telomere_length_histogram.tsv"
+    );
+    let mut histogram_file = LineWriter::new(File::create(&histogram_file_name)?);
+    writeln!(histogram_file, "bin_start\tbin_end\tcount")?;
+    for (bin_start, count) in &distribution.histogram {
+        writeln!(
+            histogram_file,
+            "{}\t{}\t{}",
+            bin_start,
+            bin_start + options.bin_size,
+            count
+        )?;
+    }
+
+    eprintln!("[+]\tFinished estimating telomere length.");
+    for line in summary.lines() {
+        eprintln!("[+]\t\t{line}");
+    }
+
+    // optional log file
+    sc.log(matches)?;
+    provenance.write()?;
+
+    Ok(())
+}
+
+/// Pick out the telomeres of a read of length `read_length` from the
+/// telomeric arrays called on it: at each end, the array reaching
+/// closest to the end, if it comes within the end distance. An array
+/// covering (nearly) the whole read is reported once, at the end it
+/// comes closest to.
+pub fn read_telomeres(
+    read_length: usize,
+    arrays: Vec<TelomericArray>,
+    options: &TelomereLengthOptions,
+) -> Vec<ReadTelomere> {
+    let distance_to = |array: &TelomericArray, end: ReadEnd| match end {
+        ReadEnd::FivePrime => array.start,
+        ReadEnd::ThreePrime => read_length - array.end,
+    };
+
+    let closest = |end: ReadEnd| {
+        arrays
+            .iter()
+            .filter(|a| distance_to(a, end) <= options.end_distance)
+            // the closest to the end, and then the longest
+            .min_by_key(|a| (distance_to(a, end), std::cmp::Reverse(a.len())))
+    };
+
+    let mut telomeres: Vec<ReadTelomere> = Vec::new();
+    for end in [ReadEnd::FivePrime, ReadEnd::ThreePrime] {
+        let Some(array) = closest(end) else {
+            continue;
+        };
+        if let Some(previous) = telomeres.first() {
+            if previous.array == *array {
+                // keep whichever end the array is closer to
+                if distance_to(array, end) < distance_to(array, previous.end) {
+                    telomeres[0].end = end;
+                }
+                continue;
+            }
+        }
+        telomeres.push(ReadTelomere {
+            read_length,
+            end,
+            array: array.clone(),
+        });
+    }
+    telomeres
+}
+
+/// The distribution of telomere lengths across reads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LengthDistribution {
+    /// The number of telomeres.
+    pub count: usize,
+    pub mean: f64,
+    pub median: f64,
+    /// Half of the telomeric bases are in telomeres at least this long.
+    pub n50: usize,
+    pub min: usize,
+    pub max: usize,
+    /// The start of each bin, and the number of telomeres in it,
+    /// from zero up to the longest telomere.
+    pub histogram: Vec<(usize, usize)>,
+}
+
+impl LengthDistribution {
+    /// Summarise some telomere lengths, with a histogram of bins
+    /// `bin_size` wide.
+    pub fn new(mut lengths: Vec<usize>, bin_size: usize) -> Self {
+        lengths.sort_unstable();
+
+        let count = lengths.len();
+        let total: usize = lengths.iter().sum();
+        let mean = if count > 0 {
+            total as f64 / count as f64
+        } else {
+            0.0
+        };
+        let median = match count {
+            0 => 0.0,
+            c if c % 2 == 0 => (lengths[c / 2 - 1] + lengths[c / 2]) as f64 / 2.0,
+            c => lengths[c / 2] as f64,
+        };
+
+        // add up from the longest until half of the bases are covered
+        let mut n50 = 0;
+        let mut covered = 0;
+        for &length in lengths.iter().rev() {
+            covered += length;
+            if covered * 2 >= total {
+                n50 = length;
+                break;
+            }
+        }
+
+        let max = lengths.last().copied().unwrap_or(0);
+        let mut histogram: Vec<(usize, usize)> = if count == 0 {
+            Vec::new()
+        } else {
+            (0..=max / bin_size).map(|i| (i * bin_size, 0)).collect()
+        };
+        for length in &lengths {
+            histogram[length / bin_size].1 += 1;
+        }
+
+        Self {
+            count,
+            mean,
+            median,
+            n50,
+            min: lengths.first().copied().unwrap_or(0),
+            max,
+            histogram,
+        }
+    }
+
+    /// The summary statistics, as a two column TSV.
+    pub fn summary(&self) -> String {
+        format!(
+            "telomeres\t{}\nmean_length\t{:.1}\nmedian_length\t{:.1}\nn50_length\t{}\nmin_length\t{}\nmax_length\t{}\n",
+            self.count, self.mean, self.median, self.n50, self.min, self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arrays::Strand;
+
+    fn arrays(sequence: &str) -> Vec<TelomericArray> {
+        let array_options = ArrayOptions::new(10, 30);
+        call_arrays("read", sequence, &["TTAGGG".into()], &array_options)
+    }
+
+    const OPTIONS: TelomereLengthOptions = TelomereLengthOptions {
+        end_distance: 20,
+        bin_size: 100,
+    };
+
+    #[test]
+    fn test_read_telomeres() {
+        // a telomere 10 bases in from the end, and an interstitial array
+        let sequence = format!(
+            "{}{}{}{}{}",
+            "GCAT".repeat(50),
+            "TTAGGG".repeat(10),
+            "GCAT".repeat(50),
+            "TTAGGG".repeat(30),
+            "GCATGCATGC"
+        );
+        let telomeres = read_telomeres(sequence.len(), arrays(&sequence), &OPTIONS);
+        assert_eq!(telomeres.len(), 1);
+        assert_eq!(telomeres[0].end, ReadEnd::ThreePrime);
+        assert_eq!(telomeres[0].array.len(), 180);
+        assert_eq!(telomeres[0].array.strand, Strand::Forward);
+
+        // the C-rich strand at the start
+        let sequence = format!("{}{}", "CCCTAA".repeat(20), "GCAT".repeat(50));
+        let telomeres = read_telomeres(sequence.len(), arrays(&sequence), &OPTIONS);
+        assert_eq!(telomeres.len(), 1);
+        assert_eq!(telomeres[0].end, ReadEnd::FivePrime);
+        assert_eq!(telomeres[0].array.strand, Strand::Reverse);
+
+        // a read made only of telomere is reported once
+        let sequence = format!("{}{}", "TTAGGG".repeat(20), "GCA");
+        let telomeres = read_telomeres(sequence.len(), arrays(&sequence), &OPTIONS);
+        assert_eq!(telomeres.len(), 1);
+        assert_eq!(telomeres[0].end, ReadEnd::FivePrime);
+    }
+
+    #[test]
+    fn test_length_distribution() {
+        let distribution = LengthDistribution::new(vec![300, 100, 250, 1000], 200);
+        assert_eq!(distribution.count, 4);
+        assert!((distribution.mean - 412.5).abs() < 1e-9);
+        assert!((distribution.median - 275.0).abs() < 1e-9);
+        // 1000 alone is more than half of 1650
+        assert_eq!(distribution.n50, 1000);
+        assert_eq!((distribution.min, distribution.max), (100, 1000));
+        assert_eq!(
+            distribution.histogram,
+            vec![(0, 1), (200, 2), (400, 0), (600, 0), (800, 0), (1000, 1)]
+        );
+
+        let empty = LengthDistribution::new(Vec::new(), 200);
+        assert_eq!(empty.count, 0);
+        assert!(empty.histogram.is_empty());
+    }
+}
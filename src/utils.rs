@@ -2,9 +2,14 @@ use bio::pattern_matching::bom::BOM;
 // I'd like to use shift_and, but may have to wait until next public release
 // of rust-bio (or hard code it here...)
 // see https://github.com/rust-bio/rust-bio/blob/master/src/pattern_matching/shift_and.rs
+use aho_corasick::AhoCorasick;
+use anyhow::{bail, Context, Result};
 use bio::pattern_matching::kmp::KMP;
+use bio::pattern_matching::myers::{long, Myers, MyersBuilder};
 use lexical_sort::{natural_lexical_cmp, StringSort};
 use std::cmp::min;
+use std::path::Path;
+use std::str::FromStr;
 
 /// This does the hard lifting in `tidk search` and `tidk find`
 /// take input putative telomeric repeat (motif) and search against
@@ -15,11 +20,14 @@ pub struct Motifs {
     pub length: usize,
 }
 
-/// Find all the occurrences of a motif in a DNA string.
+/// Find all the occurrences of a motif in a DNA string. Motifs
+/// containing IUPAC ambiguity codes are matched degenerately.
 pub fn find_motifs(motif: &str, string: &str) -> Motifs {
     let motif_length = motif.len();
 
-    let matches = if motif_length < 65 {
+    let matches = if is_degenerate(motif) {
+        find_degenerate(motif.as_bytes(), string.as_bytes())
+    } else if motif_length < 65 {
         let matcher = KMP::new(motif.as_bytes());
         matcher.find_all(string.as_bytes()).collect::<Vec<usize>>()
     } else {
@@ -33,6 +41,944 @@ pub fn find_motifs(motif: &str, string: &str) -> Motifs {
     }
 }
 
+/// The distance metric, and the maximum distance, allowed
+/// when counting approximate occurrences of a motif.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distance {
+    /// Substitutions only.
+    Hamming(usize),
+    /// Substitutions, insertions and deletions.
+    Levenshtein(usize),
+}
+
+impl Distance {
+    /// Parse the `--mismatches` and `--edits` options. Clap makes
+    /// sure at most one of them is present.
+    pub fn from_matches(matches: &clap::ArgMatches) -> Option<Self> {
+        if let Some(mismatches) = matches.get_one::<usize>("mismatches") {
+            Some(Distance::Hamming(*mismatches))
+        } else {
+            matches
+                .get_one::<usize>("edits")
+                .map(|edits| Distance::Levenshtein(*edits))
+        }
+    }
+
+    /// The maximum distance allowed.
+    pub fn max(&self) -> usize {
+        match self {
+            Distance::Hamming(k) | Distance::Levenshtein(k) => *k,
+        }
+    }
+
+    /// Check the maximum distance is less than the length of the
+    /// shortest telomeric repeat, which would otherwise match anywhere.
+    pub fn check(&self, telomeric_repeats: &[String]) -> Result<()> {
+        let shortest = telomeric_repeats.iter().map(|e| e.len()).min().unwrap_or(0);
+        if self.max() >= shortest {
+            bail!(
+                "The maximum distance ({}) must be less than the length of the shortest telomeric repeat ({}).",
+                self.max(),
+                shortest
+            );
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Distance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Distance::Hamming(k) => write!(f, "up to {k} mismatch(es)"),
+            Distance::Levenshtein(k) => write!(f, "up to {k} edit(s)"),
+        }
+    }
+}
+
+/// How occurrences of a motif are assigned to windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assignment {
+    /// Each window is scanned on its own, so occurrences spanning
+    /// the boundary between two windows are missed.
+    Window,
+    /// The whole sequence is scanned once, and each occurrence is
+    /// assigned to the window(s) containing its start.
+    Start,
+    /// The whole sequence is scanned once, and each occurrence is
+    /// counted in every window it overlaps, in proportion to the
+    /// overlap.
+    Proportional,
+}
+
+impl std::fmt::Display for Assignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Assignment::Window => write!(f, "window"),
+            Assignment::Start => write!(f, "start"),
+            Assignment::Proportional => write!(f, "proportional"),
+        }
+    }
+}
+
+/// Which bases are counted, given soft-masking (lowercase bases,
+/// e.g. from RepeatMasker).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Masking {
+    /// All bases, masked or not.
+    Both,
+    /// Only the bases which are not soft-masked.
+    Unmasked,
+    /// Only the soft-masked bases.
+    Masked,
+}
+
+impl std::fmt::Display for Masking {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Masking::Both => write!(f, "both"),
+            Masking::Unmasked => write!(f, "unmasked"),
+            Masking::Masked => write!(f, "masked"),
+        }
+    }
+}
+
+impl Masking {
+    /// Uppercase a sequence for matching, replacing the bases which
+    /// are not to be counted with `N`, so that no occurrence of a
+    /// motif can include them.
+    pub fn apply(&self, seq: &[u8]) -> String {
+        seq.iter()
+            .map(|base| {
+                let keep = match self {
+                    Masking::Both => base.is_ascii(),
+                    Masking::Unmasked => base.is_ascii() && !base.is_ascii_lowercase(),
+                    Masking::Masked => base.is_ascii_lowercase(),
+                };
+                if keep {
+                    base.to_ascii_uppercase() as char
+                } else {
+                    'N'
+                }
+            })
+            .collect()
+    }
+}
+
+/// The base composition of a window, reported alongside the repeat
+/// counts so that windows without data (gaps) or inside masked repeats
+/// can be told apart from windows without a telomere.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BaseComposition {
+    /// The proportion of the window which is soft-masked (lowercase).
+    pub soft_masked: f64,
+    /// The proportion of the window which is `N`.
+    pub n: f64,
+    /// The proportion of G and C among the bases which are not `N`,
+    /// if there are any.
+    pub gc: Option<f64>,
+}
+
+impl BaseComposition {
+    /// Count up the composition of a sequence.
+    pub fn new(seq: &[u8]) -> Self {
+        let (mut soft_masked, mut n, mut gc) = (0, 0, 0);
+        for base in seq {
+            if base.is_ascii_lowercase() {
+                soft_masked += 1;
+            }
+            match base {
+                b'N' | b'n' => n += 1,
+                b'G' | b'g' | b'C' | b'c' => gc += 1,
+                _ => (),
+            }
+        }
+
+        let fraction = |count: usize| {
+            if seq.is_empty() {
+                0.0
+            } else {
+                count as f64 / seq.len() as f64
+            }
+        };
+        let called = seq.len() - n;
+
+        Self {
+            soft_masked: fraction(soft_masked),
+            n: fraction(n),
+            gc: (called > 0).then(|| gc as f64 / called as f64),
+        }
+    }
+}
+
+impl std::fmt::Display for BaseComposition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.4}\t{:.4}\t", self.soft_masked, self.n)?;
+        match self.gc {
+            Some(gc) => write!(f, "{gc:.4}"),
+            None => write!(f, "NA"),
+        }
+    }
+}
+
+/// The assembly gaps in a sequence: runs of at least `min_length` `N`s,
+/// as 0-based, end exclusive coordinates.
+pub fn find_gaps(seq: &[u8], min_length: usize) -> Vec<(usize, usize)> {
+    let mut gaps = Vec::new();
+    let mut gap_start = None;
+
+    for (i, base) in seq.iter().chain(std::iter::once(&b'-')).enumerate() {
+        match (matches!(base, b'N' | b'n'), gap_start) {
+            (true, None) => gap_start = Some(i),
+            (false, Some(start)) => {
+                if i - start >= min_length.max(1) {
+                    gaps.push((start, i));
+                }
+                gap_start = None;
+            }
+            _ => (),
+        }
+    }
+
+    gaps
+}
+
+/// The span of a sequence between its first and last bases which are
+/// not `N`, i.e. without the gaps at either end. An all `N` sequence
+/// has an empty span at its start.
+pub fn non_gap_span(seq: &[u8]) -> (usize, usize) {
+    let is_base = |e: &u8| !matches!(e, b'N' | b'n');
+    match seq.iter().position(is_base) {
+        Some(start) => (start, seq.iter().rposition(is_base).unwrap() + 1),
+        None => (0, 0),
+    }
+}
+
+/// Options for counting telomeric repeats in windows, shared by
+/// `tidk search` and `tidk find`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowOptions {
+    /// The size of each window.
+    pub window_size: usize,
+    /// The distance between the starts of consecutive windows.
+    /// Equal to the window size for non-overlapping windows.
+    pub step: usize,
+    /// Also count approximate occurrences, up to this distance.
+    pub distance: Option<Distance>,
+    /// How occurrences are assigned to windows.
+    pub assignment: Assignment,
+    /// Which bases are counted, given soft-masking.
+    pub masking: Masking,
+}
+
+impl WindowOptions {
+    /// Windows of `window_size`, `step` apart, counting exact occurrences
+    /// on both soft-masked and unmasked bases, scanning each window on its
+    /// own.
+    pub fn new(window_size: usize, step: usize) -> Self {
+        Self {
+            window_size,
+            step,
+            distance: None,
+            assignment: Assignment::Window,
+            masking: Masking::Both,
+        }
+    }
+
+    /// Parse the window options from the command line. The step
+    /// defaults to the window size.
+    pub fn from_matches(matches: &clap::ArgMatches) -> Result<Self> {
+        let window_size = *matches
+            .get_one::<usize>("window")
+            .expect("defaulted by clap");
+        let step = matches
+            .get_one::<usize>("step")
+            .copied()
+            .unwrap_or(window_size);
+
+        if window_size == 0 || step == 0 {
+            bail!("The window size and step must be greater than zero.");
+        }
+
+        let assignment = match matches.get_one::<String>("assign").map(|e| e.as_str()) {
+            Some("start") => Assignment::Start,
+            Some("proportional") => Assignment::Proportional,
+            _ => Assignment::Window,
+        };
+
+        let masking = match matches.get_one::<String>("mask").map(|e| e.as_str()) {
+            Some("unmasked") => Masking::Unmasked,
+            Some("masked") => Masking::Masked,
+            _ => Masking::Both,
+        };
+
+        Ok(Self {
+            window_size,
+            step,
+            distance: Distance::from_matches(matches),
+            assignment,
+            masking,
+        })
+    }
+
+    /// The header of the TSV output. Compressed output may be indexed
+    /// with tabix, so its header is marked as a comment with `#`.
+    pub fn tsv_header(&self, compressed: bool) -> String {
+        let mut header = if compressed { "#" } else { "" }.to_string();
+        header +=
+            "id\twindow_start\twindow\tforward_repeat_number\treverse_repeat_number\ttelomeric_repeat\twindow_length\tforward_repeat_density\treverse_repeat_density\trepeat_density\tsoft_masked_fraction\tn_fraction\tgc_content";
+        if self.distance.is_some() {
+            header += "\tforward_approx_repeat_number\treverse_approx_repeat_number";
+        }
+        header
+    }
+
+    /// The bases of context needed either side of each window to
+    /// find the occurrences of the telomeric repeats overlapping it.
+    /// None are needed if each window is scanned on its own.
+    pub fn context(&self, telomeric_repeats: &[String]) -> usize {
+        match self.assignment {
+            Assignment::Window => 0,
+            _ => {
+                let longest = telomeric_repeats.iter().map(|e| e.len()).max().unwrap_or(1);
+                longest.saturating_sub(1) + self.distance.map_or(0, |d| d.max())
+            }
+        }
+    }
+
+    /// Count the occurrences of a motif, given by their sorted start
+    /// positions along the sequence, in the window `start..end`.
+    pub fn count_hits(&self, hits: &[usize], motif_length: usize, start: usize, end: usize) -> f64 {
+        let hits = &hits[hits.partition_point(|h| h + motif_length <= start)
+            ..hits.partition_point(|h| *h < end)];
+        match self.assignment {
+            Assignment::Window | Assignment::Start => {
+                hits.iter().filter(|h| **h >= start).count() as f64
+            }
+            // folded from zero rather than summed, as an empty
+            // f64 sum is -0.0
+            Assignment::Proportional => hits.iter().fold(0.0, |acc, h| {
+                let overlap = (h + motif_length).min(end) - (*h).max(start);
+                acc + overlap as f64 / motif_length as f64
+            }),
+        }
+    }
+
+    /// Format a count for the output. Only proportional counts
+    /// can be fractional.
+    pub fn format_count(&self, count: f64) -> String {
+        match self.assignment {
+            Assignment::Proportional => format!("{count:.2}"),
+            _ => format!("{count}"),
+        }
+    }
+
+    /// The (start, end) coordinates of each window along a sequence
+    /// of length `seq_len`. The end is exclusive, and the last window
+    /// is truncated to the end of the sequence.
+    pub fn windows(&self, seq_len: usize) -> impl Iterator<Item = (usize, usize)> {
+        let WindowOptions {
+            window_size, step, ..
+        } = *self;
+        // windows must start within the sequence, and stop once
+        // one reaches the end of it
+        let number = if seq_len == 0 {
+            0
+        } else if seq_len <= window_size {
+            1
+        } else {
+            min(
+                (seq_len - window_size).div_ceil(step) + 1,
+                (seq_len - 1) / step + 1,
+            )
+        };
+
+        (0..number).map(move |i| {
+            let start = i * step;
+            (start, min(start + window_size, seq_len))
+        })
+    }
+}
+
+/// The occurrences of a telomeric repeat on both strands, as start
+/// positions along the whole sequence. Found by a [`RepeatMatcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepeatHits {
+    /// Occurrences of the telomeric repeat.
+    pub forward: Vec<usize>,
+    /// Occurrences of its reverse complement.
+    pub reverse: Vec<usize>,
+    /// The approximate occurrences on each strand, if asked for.
+    pub approx: Option<(Vec<usize>, Vec<usize>)>,
+}
+
+/// Finds the occurrences of several telomeric repeats, on both strands,
+/// in a single pass over a string. Exact occurrences of plain repeats are
+/// found together with Aho-Corasick; degenerate (IUPAC) repeats, and
+/// approximate occurrences, are found one motif at a time.
+pub struct RepeatMatcher {
+    /// The telomeric repeats.
+    forward: Vec<String>,
+    /// Their reverse complements.
+    reverse: Vec<String>,
+    /// The automaton over all the distinct plain motifs.
+    automaton: AhoCorasick,
+    /// For each pattern of the automaton, the (repeat index, is reverse)
+    /// pairs it stands for.
+    pattern_owners: Vec<Vec<(usize, bool)>>,
+    /// Also find approximate occurrences, up to this distance.
+    distance: Option<Distance>,
+}
+
+impl RepeatMatcher {
+    /// Build the matcher for a set of (uppercase) telomeric repeats.
+    pub fn new(telomeric_repeats: &[String], distance: Option<Distance>) -> Result<Self> {
+        let forward = telomeric_repeats.to_vec();
+        let reverse: Vec<String> = forward.iter().map(|e| reverse_complement(e)).collect();
+
+        // the same motif may turn up more than once, e.g. a palindrome
+        let mut patterns: Vec<&str> = Vec::new();
+        let mut pattern_owners: Vec<Vec<(usize, bool)>> = Vec::new();
+        for (i, (f, r)) in forward.iter().zip(reverse.iter()).enumerate() {
+            for (motif, is_reverse) in [(f, false), (r, true)] {
+                if is_degenerate(motif) {
+                    continue;
+                }
+                match patterns.iter().position(|e| e == motif) {
+                    Some(p) => pattern_owners[p].push((i, is_reverse)),
+                    None => {
+                        patterns.push(motif);
+                        pattern_owners.push(vec![(i, is_reverse)]);
+                    }
+                }
+            }
+        }
+        let automaton =
+            AhoCorasick::new(&patterns).context("Could not build the telomeric repeat matcher.")?;
+
+        Ok(Self {
+            forward,
+            reverse,
+            automaton,
+            pattern_owners,
+            distance,
+        })
+    }
+
+    /// Find the occurrences of each telomeric repeat in an (uppercase)
+    /// string, which starts at `offset` along the sequence. The hits are
+    /// in the order of the telomeric repeats.
+    pub fn find(&self, string: &str, offset: usize) -> Vec<RepeatHits> {
+        let mut hits: Vec<RepeatHits> = self
+            .forward
+            .iter()
+            .map(|_| RepeatHits {
+                forward: Vec::new(),
+                reverse: Vec::new(),
+                approx: None,
+            })
+            .collect();
+
+        // all the plain motifs at once. Overlapping matches are reported
+        // in order of their end, which for a motif of fixed length is the
+        // order of their start
+        for m in self.automaton.find_overlapping_iter(string) {
+            for &(i, is_reverse) in &self.pattern_owners[m.pattern().as_usize()] {
+                let strand = if is_reverse {
+                    &mut hits[i].reverse
+                } else {
+                    &mut hits[i].forward
+                };
+                strand.push(m.start() + offset);
+            }
+        }
+
+        let shift = |indexes: Vec<usize>| indexes.into_iter().map(|e| e + offset).collect();
+        for (i, (f, r)) in self.forward.iter().zip(self.reverse.iter()).enumerate() {
+            // the degenerate motifs one by one
+            if is_degenerate(f) {
+                hits[i].forward = shift(find_motifs(f, string).indexes);
+            }
+            if is_degenerate(r) {
+                hits[i].reverse = shift(find_motifs(r, string).indexes);
+            }
+            hits[i].approx = self.distance.map(|d| {
+                (
+                    shift(find_approximate_motifs(f, string, d).indexes),
+                    shift(find_approximate_motifs(r, string, d).indexes),
+                )
+            });
+        }
+
+        hits
+    }
+}
+
+/// The fraction of a window covered by a telomeric repeat, on each
+/// strand and combined. Unlike the raw counts, these can be compared
+/// between repeats of different lengths, and with the last (truncated)
+/// window of a sequence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RepeatDensity {
+    /// The length of the window in bp.
+    pub window_length: usize,
+    /// The fraction of bases covered by the forward repeat.
+    pub forward: f64,
+    /// The fraction of bases covered by the reverse complement.
+    pub reverse: f64,
+    /// The fraction of bases covered by either.
+    pub combined: f64,
+}
+
+impl RepeatDensity {
+    /// Calculate the densities in the window `start..end` from the
+    /// start positions of the forward and reverse hits of a motif
+    /// along the sequence. Hits are clipped to the window.
+    pub fn new(
+        forward: &[usize],
+        reverse: &[usize],
+        motif_length: usize,
+        (start, end): (usize, usize),
+    ) -> Self {
+        let window_length = end - start;
+        let intervals = |hits: &[usize]| -> Vec<(usize, usize)> {
+            hits.iter()
+                .map(|e| (*e.max(&start), (e + motif_length).min(end)))
+                .filter(|(s, e)| s < e)
+                .collect()
+        };
+        let fraction = |bases: usize| {
+            if window_length == 0 {
+                0.0
+            } else {
+                bases as f64 / window_length as f64
+            }
+        };
+
+        let forward_intervals = intervals(forward);
+        let reverse_intervals = intervals(reverse);
+        let combined = [forward_intervals.clone(), reverse_intervals.clone()].concat();
+
+        Self {
+            window_length,
+            forward: fraction(covered_bases(forward_intervals)),
+            reverse: fraction(covered_bases(reverse_intervals)),
+            combined: fraction(covered_bases(combined)),
+        }
+    }
+}
+
+impl std::fmt::Display for RepeatDensity {
+    /// The density columns of the TSV output.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{:.4}\t{:.4}\t{:.4}",
+            self.window_length, self.forward, self.reverse, self.combined
+        )
+    }
+}
+
+/// The number of bases covered by the union of a set of
+/// (start, end) intervals.
+pub fn covered_bases(mut intervals: Vec<(usize, usize)>) -> usize {
+    intervals.sort_unstable();
+
+    let mut covered = 0;
+    // the end of the covered bases so far
+    let mut covered_to = 0;
+    for (start, end) in intervals {
+        let start = start.max(covered_to);
+        if end > start {
+            covered += end - start;
+            covered_to = end;
+        }
+    }
+    covered
+}
+
+/// A region of a reference, as `chr`, `chr:start` or `chr:start-end`
+/// (1-based and inclusive, as in samtools).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    /// The start of the region (0-based).
+    pub start: usize,
+    /// The end of the region (exclusive), or `None` for the
+    /// end of the reference.
+    pub end: Option<usize>,
+}
+
+impl Region {
+    /// Does an alignment from `start` to `end` (0-based, exclusive)
+    /// on `name` overlap this region?
+    pub fn overlaps(&self, name: &str, start: usize, end: usize) -> bool {
+        self.name == name && end > self.start && self.end.is_none_or(|e| start < e)
+    }
+}
+
+impl FromStr for Region {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((name, range)) = s.rsplit_once(':') else {
+            return Ok(Self {
+                name: s.to_string(),
+                start: 0,
+                end: None,
+            });
+        };
+        let parse = |e: &str| {
+            e.replace(',', "")
+                .parse::<usize>()
+                .with_context(|| format!("Could not parse region {}: expected chr:start-end.", s))
+        };
+        // `chr:start` runs to the end of the sequence
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse(start)?, Some(parse(end)?)),
+            None => (parse(range)?, None),
+        };
+        if start == 0 || end.is_some_and(|e| e < start) {
+            bail!(
+                "Could not parse region {}: the start must be at least 1, and no more than the end.",
+                s
+            );
+        }
+        Ok(Self {
+            name: name.to_string(),
+            start: start - 1,
+            end,
+        })
+    }
+}
+
+/// How much of each end of a sequence to scan, for when only the
+/// telomeres are of interest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ends {
+    /// A length in bp.
+    Length(usize),
+    /// A proportion of the length of each sequence.
+    Proportion(f64),
+}
+
+impl Ends {
+    /// Get the ends to scan, if any, from `--ends` or `--distance`.
+    pub fn from_matches(matches: &clap::ArgMatches) -> Result<Option<Self>> {
+        if let Some(ends) = matches.get_one::<usize>("ends") {
+            if *ends == 0 {
+                bail!("The length of the ends must be greater than zero.");
+            }
+            return Ok(Some(Ends::Length(*ends)));
+        }
+        if let Some(distance) = matches.get_one::<f64>("distance") {
+            if *distance <= 0.0 || *distance > 0.5 {
+                bail!("Distance from chromosome end as a proportion must be greater than 0, and at most 0.5.");
+            }
+            return Ok(Some(Ends::Proportion(*distance)));
+        }
+        Ok(None)
+    }
+
+    /// The regions to scan of sequence `name`, of length `length`. Ends
+    /// which would meet or overlap are scanned as the whole sequence.
+    pub fn regions(&self, name: &str, length: usize) -> Vec<Region> {
+        let end = match self {
+            Ends::Length(bp) => *bp,
+            Ends::Proportion(proportion) => (length as f64 * proportion).ceil() as usize,
+        };
+        let region = |start, end| Region {
+            name: name.to_string(),
+            start,
+            end: Some(end),
+        };
+
+        if length == 0 {
+            vec![]
+        } else if end * 2 >= length {
+            vec![region(0, length)]
+        } else {
+            vec![region(0, end), region(length - end, length)]
+        }
+    }
+}
+
+impl std::fmt::Display for Ends {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ends::Length(bp) => write!(f, "{bp} bp"),
+            Ends::Proportion(proportion) => write!(f, "{proportion} of the sequence length"),
+        }
+    }
+}
+
+/// Sort regions by sequence and start, and merge those which overlap,
+/// so that no part of a sequence is scanned twice and the windows of
+/// each sequence come out in order.
+pub fn merge_regions(mut regions: Vec<Region>) -> Vec<Region> {
+    regions.sort_by(|a, b| (&a.name, a.start).cmp(&(&b.name, b.start)));
+
+    let mut merged: Vec<Region> = Vec::with_capacity(regions.len());
+    for region in regions {
+        match merged.last_mut() {
+            Some(last) if last.name == region.name && last.end.is_none_or(|e| region.start < e) => {
+                last.end = last.end.zip(region.end).map(|(a, b)| a.max(b));
+            }
+            _ => merged.push(region),
+        }
+    }
+    merged
+}
+
+/// Read regions from a BED file (0-based, end exclusive). Blank lines,
+/// comments and `track` or `browser` lines are skipped, as are any
+/// columns after the first three.
+pub fn read_bed_regions<P: AsRef<Path>>(path: P) -> Result<Vec<Region>> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read the BED file: {}", path.display()))?;
+
+    contents
+        .lines()
+        .filter(|line| {
+            !(line.trim().is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser"))
+        })
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let parse = |i: usize| fields.get(i).and_then(|e| e.trim().parse::<usize>().ok());
+            match (parse(1), parse(2)) {
+                (Some(start), Some(end)) if start <= end => Ok(Region {
+                    name: fields[0].to_string(),
+                    start,
+                    end: Some(end),
+                }),
+                _ => bail!(
+                    "Could not parse the line of {}: {} (expected chrom, start and end, with start no more than end).",
+                    path.display(),
+                    line
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Mask (set to `N`) the bases of a read whose Phred quality, encoded
+/// as in Sanger FASTQ (offset 33), is below `min_quality`. A masked base
+/// can't be part of a telomeric repeat.
+pub fn mask_low_quality(seq: &mut [u8], qual: &[u8], min_quality: u8) {
+    for (base, q) in seq.iter_mut().zip(qual) {
+        if q.saturating_sub(33) < min_quality {
+            *base = b'N';
+        }
+    }
+}
+
+/// Find all the approximate occurrences of a motif in a DNA string.
+///
+/// Hits within the allowed distance usually pile up around each
+/// occurrence, so only non-overlapping hits are kept, greedily taking
+/// the hit which ends first. Exact occurrences are included.
+pub fn find_approximate_motifs(motif: &str, string: &str, distance: Distance) -> Motifs {
+    let motif = motif.as_bytes();
+    let text = string.as_bytes();
+
+    // (start, end) of every hit, with end exclusive, in order of end
+    let hits: Vec<(usize, usize)> = match distance {
+        Distance::Hamming(k) => {
+            if text.len() < motif.len() {
+                vec![]
+            } else {
+                (0..=text.len() - motif.len())
+                    .filter(|&i| {
+                        text[i..i + motif.len()]
+                            .iter()
+                            .zip(motif)
+                            .filter(|(t, p)| !iupac_matches(**p, **t))
+                            .count()
+                            <= k
+                    })
+                    .map(|i| (i, i + motif.len()))
+                    .collect()
+            }
+        }
+        Distance::Levenshtein(k) => {
+            // teach Myers the ambiguity codes
+            let mut builder = MyersBuilder::new();
+            for (code, equivalents) in IUPAC_EQUIVALENTS {
+                builder.ambig(*code, *equivalents);
+            }
+            if motif.len() < 65 {
+                let mut matcher: Myers<u64> = builder.build_64(motif);
+                // the distance can't exceed the motif length anyway
+                let k = min(k, motif.len()) as u8;
+                matcher
+                    .find_all(text, k)
+                    .map(|(start, end, _)| (start, end))
+                    .collect()
+            } else {
+                let mut matcher: long::Myers<u64> = builder.build_long_64(motif);
+                matcher
+                    .find_all(text, k)
+                    .map(|(start, end, _)| (start, end))
+                    .collect()
+            }
+        }
+    };
+
+    let mut indexes = Vec::new();
+    let mut last_end = 0;
+    for (start, end) in hits {
+        if indexes.is_empty() || start >= last_end {
+            indexes.push(start);
+            last_end = end;
+        }
+    }
+
+    Motifs {
+        length: indexes.len(),
+        indexes,
+    }
+}
+
+/// The IUPAC ambiguity codes, and the bases they stand for.
+const IUPAC_EQUIVALENTS: &[(u8, &[u8])] = &[
+    (b'R', b"AG"),
+    (b'Y', b"CT"),
+    (b'S', b"CG"),
+    (b'W', b"AT"),
+    (b'K', b"GT"),
+    (b'M', b"AC"),
+    (b'B', b"CGT"),
+    (b'D', b"AGT"),
+    (b'H', b"ACT"),
+    (b'V', b"ACG"),
+    (b'N', b"ACGT"),
+];
+
+/// The set of bases a nucleotide code stands for, as a bitmask
+/// (A = 1, C = 2, G = 4, T = 8). Zero if it is not a nucleotide.
+fn iupac_mask(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'A' => 0b0001,
+        b'C' => 0b0010,
+        b'G' => 0b0100,
+        b'T' | b'U' => 0b1000,
+        b'R' => 0b0101,
+        b'Y' => 0b1010,
+        b'S' => 0b0110,
+        b'W' => 0b1001,
+        b'K' => 0b1100,
+        b'M' => 0b0011,
+        b'B' => 0b1110,
+        b'D' => 0b1101,
+        b'H' => 0b1011,
+        b'V' => 0b0111,
+        b'N' => 0b1111,
+        _ => 0,
+    }
+}
+
+/// Does a base in the text match a (possibly degenerate) base
+/// in the pattern? Ambiguous bases in the text only match if
+/// every base they stand for is allowed by the pattern.
+fn iupac_matches(pattern_base: u8, text_base: u8) -> bool {
+    let text_mask = iupac_mask(text_base);
+    text_mask != 0 && text_mask & iupac_mask(pattern_base) == text_mask
+}
+
+/// Whether a motif contains any IUPAC ambiguity codes.
+pub fn is_degenerate(motif: &str) -> bool {
+    motif
+        .bytes()
+        .any(|b| !matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T'))
+}
+
+/// Check a user supplied (or database) telomeric repeat is made of
+/// IUPAC nucleotide codes, and return it in uppercase.
+pub fn parse_telomeric_repeat(repeat: &str) -> Result<String> {
+    if repeat.is_empty() {
+        bail!("The telomeric repeat is empty.");
+    }
+    if let Some((i, c)) = repeat
+        .char_indices()
+        .find(|(_, c)| !c.is_ascii() || iupac_mask(*c as u8) == 0)
+    {
+        bail!(
+            "The telomeric repeat {repeat} contains a non-nucleotide character '{c}' at position {}. Only IUPAC nucleotide codes are allowed.",
+            i + 1
+        );
+    }
+    Ok(repeat.to_uppercase())
+}
+
+/// Whether a telomeric repeat is written as its G-rich strand
+/// (e.g. TTAGGG rather than CCCTAA). Ties count as G-rich.
+pub fn is_g_rich(telomeric_repeat: &str) -> bool {
+    let g = telomeric_repeat.matches(['G', 'g']).count();
+    let c = telomeric_repeat.matches(['C', 'c']).count();
+    g >= c
+}
+
+/// Read telomeric repeats from a file, one per line. Blank lines
+/// and lines starting with `#` are skipped.
+pub fn read_motif_file<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read the motif file: {}", path.display()))?;
+
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            parse_telomeric_repeat(line)
+                .with_context(|| format!("Invalid telomeric repeat in {}", path.display()))
+        })
+        .collect()
+}
+
+/// A shift-and matcher for degenerate motifs, which falls back
+/// to a naive scan for motifs that don't fit into a `u64`.
+fn find_degenerate(motif: &[u8], text: &[u8]) -> Vec<usize> {
+    let m = motif.len();
+    if m == 0 || text.len() < m {
+        return vec![];
+    }
+    if m > 64 {
+        return (0..=text.len() - m)
+            .filter(|&i| {
+                text[i..i + m]
+                    .iter()
+                    .zip(motif)
+                    .all(|(t, p)| iupac_matches(*p, *t))
+            })
+            .collect();
+    }
+
+    // for each possible text byte, the motif positions it matches
+    let mut masks = [0u64; 256];
+    for (byte, mask) in masks.iter_mut().enumerate() {
+        for (i, p) in motif.iter().enumerate() {
+            if iupac_matches(*p, byte as u8) {
+                *mask |= 1 << i;
+            }
+        }
+    }
+
+    let accept = 1u64 << (m - 1);
+    let mut state = 0u64;
+    let mut matches = Vec::new();
+    for (i, t) in text.iter().enumerate() {
+        state = ((state << 1) | 1) & masks[*t as usize];
+        if state & accept != 0 {
+            matches.push(i + 1 - m);
+        }
+    }
+    matches
+}
+
 /// Calculate the reverse complement of a telomeric repeat.
 pub fn reverse_complement(dna: &str) -> String {
     let dna_chars = dna.chars();
@@ -45,13 +991,26 @@ pub fn reverse_complement(dna: &str) -> String {
     revcomp.into_iter().collect()
 }
 
-/// Switch complementary bases in a DNA string.
+/// Switch complementary bases in a DNA string. IUPAC
+/// ambiguity codes are complemented to the code standing
+/// for the complementary set of bases.
 fn switch_base(c: char) -> char {
     match c {
         'A' => 'T',
         'C' => 'G',
         'T' => 'A',
+        'U' => 'A',
         'G' => 'C',
+        'R' => 'Y',
+        'Y' => 'R',
+        'K' => 'M',
+        'M' => 'K',
+        'B' => 'V',
+        'V' => 'B',
+        'D' => 'H',
+        'H' => 'D',
+        'S' => 'S',
+        'W' => 'W',
         'N' => 'N',
         _ => 'N',
     }
@@ -180,6 +1139,191 @@ mod tests {
         assert_eq!(revcomp, REVCOMP_DNA_STRING)
     }
 
+    #[test]
+    fn revcomp_iupac() {
+        assert_eq!(reverse_complement("TTRGGG"), "CCCYAA");
+        assert_eq!(reverse_complement("KMBVDHSWN"), "NWSDHBVKM");
+        // U is read as T
+        assert_eq!(reverse_complement("UUAGGG"), "CCCTAA");
+    }
+
+    #[test]
+    fn parse_repeat() {
+        assert_eq!(parse_telomeric_repeat("ttrggg").unwrap(), "TTRGGG");
+        assert!(parse_telomeric_repeat("TTAGGX").is_err());
+        assert!(parse_telomeric_repeat("TTA GG").is_err());
+    }
+
+    #[test]
+    fn degenerate_motifs() {
+        let motifs = find_motifs("TTRGGG", "TTAGGGTTGGGGTTCGGGTTAGGG");
+        assert_eq!(motifs.indexes, &[0, 6, 18])
+    }
+
+    #[test]
+    fn ends() {
+        let starts = |ends: Ends, length| -> Vec<(usize, Option<usize>)> {
+            ends.regions("chr1", length)
+                .iter()
+                .map(|e| (e.start, e.end))
+                .collect()
+        };
+        assert_eq!(
+            starts(Ends::Length(100), 1000),
+            vec![(0, Some(100)), (900, Some(1000))]
+        );
+        // the ends meet
+        assert_eq!(starts(Ends::Length(500), 1000), vec![(0, Some(1000))]);
+        assert_eq!(
+            starts(Ends::Proportion(0.01), 1050),
+            vec![(0, Some(11)), (1039, Some(1050))]
+        );
+        assert!(starts(Ends::Length(100), 0).is_empty());
+    }
+
+    #[test]
+    fn bed_regions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("regions.bed");
+        std::fs::write(
+            &path,
+            "# curated ends\ntrack name=ends\nchr1\t0\t10000\nchr2\t5000\t15000\tend\n",
+        )
+        .unwrap();
+        let regions = read_bed_regions(&path).unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                Region {
+                    name: "chr1".into(),
+                    start: 0,
+                    end: Some(10000)
+                },
+                Region {
+                    name: "chr2".into(),
+                    start: 5000,
+                    end: Some(15000)
+                }
+            ]
+        );
+
+        std::fs::write(&path, "chr1\t100\n").unwrap();
+        assert!(read_bed_regions(&path).is_err());
+    }
+
+    #[test]
+    fn masking() {
+        let seq = b"TTAGGGttagggTTaggg";
+        assert_eq!(Masking::Both.apply(seq), "TTAGGGTTAGGGTTAGGG");
+        assert_eq!(Masking::Unmasked.apply(seq), "TTAGGGNNNNNNTTNNNN");
+        assert_eq!(Masking::Masked.apply(seq), "NNNNNNTTAGGGNNAGGG");
+    }
+
+    #[test]
+    fn base_composition() {
+        let composition = BaseComposition::new(b"ttagggNNNNGGCCAT");
+        assert!((composition.soft_masked - 6.0 / 16.0).abs() < 1e-9);
+        assert!((composition.n - 0.25).abs() < 1e-9);
+        assert!((composition.gc.unwrap() - 7.0 / 12.0).abs() < 1e-9);
+        assert_eq!(composition.to_string(), "0.3750\t0.2500\t0.5833");
+
+        let gap = BaseComposition::new(b"NNNN");
+        assert_eq!(gap.gc, None);
+        assert_eq!(gap.to_string(), "0.0000\t1.0000\tNA");
+    }
+
+    #[test]
+    fn gaps() {
+        let seq = b"NNACGTNACGTnnnnnACGTNNN";
+        assert_eq!(find_gaps(seq, 1), vec![(0, 2), (6, 7), (11, 16), (20, 23)]);
+        assert_eq!(find_gaps(seq, 3), vec![(11, 16), (20, 23)]);
+        assert_eq!(non_gap_span(seq), (2, 20));
+        assert_eq!(non_gap_span(b"ACGT"), (0, 4));
+        assert_eq!(non_gap_span(b"NNN"), (0, 0));
+    }
+
+    #[test]
+    fn windows_tiled() {
+        let windows: Vec<_> = WindowOptions::new(20, 20).windows(52).collect();
+        assert_eq!(windows, vec![(0, 20), (20, 40), (40, 52)])
+    }
+
+    #[test]
+    fn windows_sliding() {
+        let windows: Vec<_> = WindowOptions::new(20, 10).windows(52).collect();
+        assert_eq!(
+            windows,
+            vec![(0, 20), (10, 30), (20, 40), (30, 50), (40, 52)]
+        )
+    }
+
+    #[test]
+    fn windows_gapped() {
+        // windows never start past the end of the sequence
+        let windows: Vec<_> = WindowOptions::new(20, 30).windows(52).collect();
+        assert_eq!(windows, vec![(0, 20), (30, 50)]);
+        let windows: Vec<_> = WindowOptions::new(1, 20).windows(25).collect();
+        assert_eq!(windows, vec![(0, 1), (20, 21)]);
+        let windows: Vec<_> = WindowOptions::new(10, 20).windows(20).collect();
+        assert_eq!(windows, vec![(0, 10)]);
+    }
+
+    #[test]
+    fn repeat_density() {
+        // overlapping hits, and a hit running off the end of the window
+        let density = RepeatDensity::new(&[0, 3, 18], &[10], 5, (0, 20));
+        assert_eq!(density.window_length, 20);
+        assert_eq!(density.forward, 10.0 / 20.0);
+        assert_eq!(density.reverse, 5.0 / 20.0);
+        assert_eq!(density.combined, 15.0 / 20.0);
+        assert_eq!(density.to_string(), "20\t0.5000\t0.2500\t0.7500");
+
+        // the same hits, clipped to a window further along
+        let density = RepeatDensity::new(&[0, 3, 18], &[10], 5, (5, 15));
+        assert_eq!(density.forward, 3.0 / 10.0);
+        assert_eq!(density.combined, 8.0 / 10.0);
+    }
+
+    #[test]
+    fn repeat_matcher() {
+        // a plain repeat, its reverse complement given as a repeat too,
+        // and a degenerate repeat
+        let repeats = ["TTAGG".to_string(), "CCTAA".into(), "TTRGG".into()];
+        let matcher = RepeatMatcher::new(&repeats, None).unwrap();
+        let hits = matcher.find("TTAGGTTGGGCCTAA", 100);
+
+        assert_eq!(
+            (hits[0].forward.clone(), hits[0].reverse.clone()),
+            (vec![100], vec![110])
+        );
+        assert_eq!(
+            (hits[1].forward.clone(), hits[1].reverse.clone()),
+            (vec![110], vec![100])
+        );
+        assert_eq!(
+            (hits[2].forward.clone(), hits[2].reverse.clone()),
+            (vec![100, 105], vec![110])
+        );
+        assert!(hits[0].approx.is_none());
+    }
+
+    #[test]
+    fn count_hits() {
+        // a hit straddling the boundary at 10
+        let hits = [0, 8, 12];
+        let mut options = WindowOptions::new(10, 10);
+        assert_eq!(options.count_hits(&hits, 5, 0, 10), 2.0);
+        assert_eq!(options.count_hits(&hits, 5, 10, 20), 1.0);
+        options.assignment = Assignment::Proportional;
+        assert_eq!(options.count_hits(&hits, 5, 0, 10), 1.4);
+        assert_eq!(options.count_hits(&hits, 5, 10, 20), 1.6);
+        assert_eq!(options.format_count(1.4), "1.40");
+        assert_eq!(
+            options.format_count(options.count_hits(&hits, 5, 20, 30)),
+            "0.00"
+        );
+    }
+
     // string rotation tests
 
     // define a few strings here,
@@ -236,4 +1380,82 @@ mod tests {
         let motifs = find_motifs(CANONICAL, HAYSTACK);
         assert_eq!(motifs.indexes, EXPECTED)
     }
+
+    // 7*AACCT with one substitution
+    const HAYSTACK_SNP: &str = "AACCTAACCTAACCTAACGTAACCTAACCTAACCT";
+
+    #[test]
+    fn approximate_motifs_hamming() {
+        let exact = find_motifs(CANONICAL, HAYSTACK_SNP);
+        let motifs = find_approximate_motifs(CANONICAL, HAYSTACK_SNP, Distance::Hamming(1));
+        assert_eq!(exact.length, 6);
+        assert_eq!(motifs.indexes, &[0, 5, 10, 15, 20, 25, 30])
+    }
+
+    #[test]
+    fn approximate_motifs_levenshtein() {
+        // the deleted base in HAYSTACK is recovered as a single repeat
+        let motifs = find_approximate_motifs(CANONICAL, HAYSTACK, Distance::Levenshtein(1));
+        assert_eq!(motifs.length, 8)
+    }
+
+    #[test]
+    fn distance_check() {
+        let telomeric_repeats = ["TTAGGG".to_string(), "TTAGG".to_string()];
+        assert!(Distance::Hamming(4).check(&telomeric_repeats).is_ok());
+        assert!(Distance::Levenshtein(5).check(&telomeric_repeats).is_err());
+    }
+
+    #[test]
+    fn test_region() {
+        let region: Region = "chr1:1,001-2000".parse().unwrap();
+        assert_eq!(
+            region,
+            Region {
+                name: "chr1".into(),
+                start: 1000,
+                end: Some(2000)
+            }
+        );
+        assert!(region.overlaps("chr1", 1999, 2005));
+        assert!(!region.overlaps("chr1", 2000, 2005));
+        assert!(!region.overlaps("chr2", 1500, 1600));
+
+        let region: Region = "chrX".parse().unwrap();
+        assert_eq!(region.end, None);
+        let region: Region = "chrX:5".parse().unwrap();
+        assert_eq!((region.start, region.end), (4, None));
+        assert!("chr1:10-5".parse::<Region>().is_err());
+        assert!("chr1:a-5".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn test_merge_regions() {
+        let regions = [
+            "chr2:1-100",
+            "chr1:501-600",
+            "chr1:1-100",
+            "chr1:51-200",
+            "chr1:1-100",
+        ]
+        .iter()
+        .map(|e| e.parse().unwrap())
+        .collect();
+        let merged: Vec<(String, usize, Option<usize>)> = merge_regions(regions)
+            .into_iter()
+            .map(|e| (e.name, e.start, e.end))
+            .collect();
+        assert_eq!(
+            merged,
+            vec![
+                ("chr1".into(), 0, Some(200)),
+                ("chr1".into(), 500, Some(600)),
+                ("chr2".into(), 0, Some(100)),
+            ]
+        );
+
+        // a region to the end of the sequence takes in any after it
+        let regions = vec!["chr1:10".parse().unwrap(), "chr1:51-60".parse().unwrap()];
+        assert_eq!(merge_regions(regions)[0].end, None);
+    }
 }